@@ -1,55 +1,506 @@
+// main() is empty, so outside of tests nothing in the module is reachable
+#[cfg_attr(not(test), allow(dead_code))]
 mod btree {
     use std::{
-        cell::RefCell,
+        cell::{Cell, RefCell},
         cmp::Ordering,
         fmt::Debug,
         hint::unreachable_unchecked,
+        iter::Peekable,
+        ops::{BitAnd, BitOr, BitXor, Bound, ControlFlow, Range, RangeBounds, Sub},
         rc::{Rc, Weak},
+        sync::{Mutex, MutexGuard},
     };
 
+    #[cfg(feature = "rayon")]
+    use rayon::prelude::*;
+
     const MAX_KEYS: usize = 2;
     const MAX_CHILDREN: usize = 3;
 
     #[derive(Debug, Clone)]
-    enum BTreeNode<T: Ord + Eq + Clone> {
-        Leaf { leaf: BTreeLeaf<T> },
-        SubTree { subtree: BTreeSubTree<T> },
+    enum BTreeNode<T, A: BTreeAugment<T> = ()> {
+        Leaf { leaf: BTreeLeaf<T, A> },
+        SubTree { subtree: BTreeSubTree<T, A> },
     }
 
     #[derive(Debug, Default, Clone)]
-    struct BTreeLeaf<T: Ord + Eq + Clone> {
+    struct BTreeLeaf<T, A: BTreeAugment<T> = ()> {
         values: Vec<Rc<T>>,
-        parent: Option<Weak<RefCell<BTreeNode<T>>>>,
-        next_leaf: Option<Rc<RefCell<BTreeNode<T>>>>,
-        previous_leaf: Option<Weak<RefCell<BTreeNode<T>>>>,
+        parent: Option<Weak<RefCell<BTreeNode<T, A>>>>,
+        next_leaf: Option<Rc<RefCell<BTreeNode<T, A>>>>,
+        previous_leaf: Option<Weak<RefCell<BTreeNode<T, A>>>>,
+    }
+
+    #[derive(Debug)]
+    pub struct BTreeIter<T, A: BTreeAugment<T> = ()> {
+        cur_leaf: Option<Rc<RefCell<BTreeNode<T, A>>>>,
+        cur_ind: usize,
+    }
+
+    // Next key to yield and how many equal keys precede it
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+    pub struct BTreePositionToken<T> {
+        key: T,
+        offset: usize,
+    }
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+    pub enum BTreeDirection {
+        Forward,
+        Backward,
+    }
+
+    // Keyset pagination: `position` is the first value not yet seen going forward,
+    // or the last seen value going backward
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+    pub struct BTreeCursorToken<T> {
+        position: BTreePositionToken<T>,
+        direction: BTreeDirection,
+    }
+
+    #[derive(Debug, Clone)]
+    pub struct BTreeCursor<T: Ord + Eq> {
+        iter: BTreeIter<T>,
+        direction: BTreeDirection,
+        last: Option<BTreeIter<T>>,
     }
 
     #[derive(Debug, Clone)]
-    pub struct BTreeIter<T: Ord + Eq + Clone> {
+    pub struct BTreeRange<T: Ord + Eq> {
+        front: Option<(Rc<RefCell<BTreeNode<T>>>, usize)>,
+        back: Option<(Rc<RefCell<BTreeNode<T>>>, usize)>,
+    }
+
+    #[derive(Debug)]
+    pub struct BTreeDrain<T: Ord + Eq> {
         cur_leaf: Option<Rc<RefCell<BTreeNode<T>>>>,
-        cur_ind: usize,
+        values: std::vec::IntoIter<Rc<T>>,
     }
 
-    #[derive(Debug, Default, Clone)]
-    struct BTreeSubTree<T: Ord + Eq + Clone> {
-        children: Vec<Rc<RefCell<BTreeNode<T>>>>,
-        parent: Option<Weak<RefCell<BTreeNode<T>>>>,
+    #[derive(Debug)]
+    pub struct BTreeExtractIf<'a, T: Ord + Eq, F: FnMut(&T) -> bool> {
+        tree: &'a mut BTree<T>,
+        pred: F,
+        rank: usize,
+    }
+
+    #[derive(Debug, Clone)]
+    pub struct BTreeEnumerated<I> {
+        iter: I,
+        rank: usize,
+    }
+
+    #[derive(Debug, Clone)]
+    pub struct BTreeCounts<T: Ord + Eq> {
+        iter: Peekable<BTreeIter<T>>,
+    }
+
+    #[derive(Debug, Clone)]
+    pub struct BTreeMidKeys<T: Ord + Eq> {
+        stack: Vec<(Rc<RefCell<BTreeNode<T>>>, usize)>,
+    }
+
+    #[derive(Default, Clone)]
+    struct BTreeSubTree<T, A: BTreeAugment<T> = ()> {
+        children: Vec<Rc<RefCell<BTreeNode<T, A>>>>,
+        parent: Option<Weak<RefCell<BTreeNode<T, A>>>>,
         mid_keys: Vec<Rc<T>>,
         values_number: usize,
+        summary: A::Summary,
+    }
+
+    // A monoid kept for every subtree of a `BTree<T, A>` and refreshed on each update
+    pub trait BTreeAugment<T> {
+        type Summary: Clone;
+
+        fn identity() -> Self::Summary;
+
+        fn summarize(value: &T) -> Self::Summary;
+
+        fn combine(left: &Self::Summary, right: &Self::Summary) -> Self::Summary;
+    }
+
+    pub trait BTreeComparator<T: ?Sized> {
+        fn compare(&self, left: &T, right: &T) -> Ordering;
+    }
+
+    #[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+    pub enum BTreeMode {
+        #[default]
+        Multiset,
+        Set,
+    }
+
+    #[derive(Debug, Default)]
+    pub struct BTree<T, A: BTreeAugment<T> = ()> {
+        root: Option<Rc<RefCell<BTreeNode<T, A>>>>,
+        mode: BTreeMode,
+        policy: BTreeRebalancePolicy,
+    }
+
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub struct OccupiedError<T> {
+        pub existing: Rc<T>,
+        pub value: T,
+    }
+
+    #[derive(Debug, Default, Clone, PartialEq, Eq)]
+    pub struct FrozenBTree<T: Ord + Eq> {
+        values: Vec<T>,
+    }
+
+    #[cfg(feature = "mmap")]
+    pub trait FixedSizeKey: Ord + Eq {
+        const SIZE: usize;
+
+        fn write_bytes(&self, bytes: &mut [u8]);
+
+        fn read_bytes(bytes: &[u8]) -> Self;
+    }
+
+    #[cfg(feature = "mmap")]
+    #[derive(Debug)]
+    pub struct MappedFrozenBTree<T: FixedSizeKey> {
+        map: memmap2::Mmap,
+        len: usize,
+        marker: std::marker::PhantomData<T>,
+    }
+
+    #[cfg(feature = "icu")]
+    #[derive(Clone)]
+    pub struct Collation {
+        collator: Rc<icu_collator::Collator>,
+    }
+
+    #[cfg(feature = "icu")]
+    #[derive(Clone)]
+    pub struct CollatedString {
+        value: String,
+        collation: Collation,
     }
 
+    #[derive(Debug, Default, Clone, Copy)]
+    pub struct TotalF64(pub f64);
+
+    #[derive(Debug, Default, Clone, Copy)]
+    pub struct TotalF32(pub f32);
+
     #[derive(Debug, Default, Clone)]
-    pub struct BTree<T: Ord + Eq + Clone> {
-        root: Option<Rc<RefCell<BTreeNode<T>>>>,
+    pub struct DeltaBTree<T: Ord + Eq> {
+        base: FrozenBTree<T>,
+        inserted: BTree<T>,
+        removed: BTree<T>,
+    }
+
+    // With `Merge`, a leaf left with a single value is folded into a sibling with room,
+    // which keeps leaves full for mostly-static trees at the cost of extra restructuring.
+    // `Borrow` only touches siblings once a leaf is empty.
+    // A leaf holds at most `MAX_KEYS` = 2 values, so the only minimum fills are one value
+    // (`Borrow`) and two values (`Merge`); the variants double as the fill threshold
+    #[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+    pub enum BTreeRebalancePolicy {
+        #[default]
+        Borrow,
+        Merge,
+    }
+
+    #[derive(Debug)]
+    pub struct ShardedBTree<T: Ord + Eq> {
+        boundaries: Vec<T>,
+        shards: Vec<Mutex<ShardTree<T>>>,
+    }
+
+    // Rc handles of a shard never leave its lock: guards only hand out owned values
+    #[derive(Debug)]
+    struct ShardTree<T: Ord + Eq>(BTree<T>);
+
+    unsafe impl<T: Ord + Eq + Send> Send for ShardTree<T> {}
+
+    #[derive(Debug)]
+    pub struct ShardGuard<'a, T: Ord + Eq> {
+        shard: MutexGuard<'a, ShardTree<T>>,
+    }
+
+    #[derive(Debug, Clone)]
+    pub struct ShardedBTreeIter<'a, T: Ord + Eq> {
+        sharded: &'a ShardedBTree<T>,
+        next_shard: usize,
+        cur_shard: std::vec::IntoIter<T>,
+    }
+
+    #[derive(Debug, Clone)]
+    struct SeqItem<T>(T);
+
+    // Only built under `&BTree`, so nodes are read without touching reference counts
+    // or borrow flags while nothing can mutate them
+    #[cfg(feature = "rayon")]
+    struct SharedNode<'a, T: Ord + Eq>(&'a BTreeNode<T>);
+
+    #[cfg(feature = "rayon")]
+    unsafe impl<T: Ord + Eq + Sync> Send for SharedNode<'_, T> {}
+
+    #[cfg(feature = "rayon")]
+    unsafe impl<T: Ord + Eq + Sync> Sync for SharedNode<'_, T> {}
+
+    #[derive(Debug)]
+    pub struct BTreeSeq<T: Clone> {
+        tree: BTree<SeqItem<T>>,
+    }
+
+    #[derive(Debug, Clone)]
+    pub struct BTreeSeqIter<'a, T: Clone> {
+        seq: &'a BTreeSeq<T>,
+        iter: BTreeIter<SeqItem<T>>,
+    }
+
+    #[derive(Debug, Clone)]
+    struct MultiSetRun<T> {
+        value: T,
+        count: Cell<usize>,
+    }
+
+    #[derive(Debug)]
+    struct RunTotal;
+
+    #[derive(Debug)]
+    pub struct BTreeMultiSet<T: Ord + Eq> {
+        runs: BTree<MultiSetRun<T>, RunTotal>,
+        len: usize,
+    }
+
+    #[derive(Debug, Clone)]
+    pub struct BTreeMultiSetIter<'a, T: Ord + Eq> {
+        set: &'a BTreeMultiSet<T>,
+        runs: BTreeIter<MultiSetRun<T>, RunTotal>,
+        cur_run: Option<(&'a T, usize)>,
+    }
+
+    #[derive(Debug, Clone)]
+    struct MapEntry<K, V> {
+        key: K,
+        value: RefCell<V>,
+    }
+
+    #[derive(Debug)]
+    pub struct BTreeMap23<K: Ord, V> {
+        entries: BTree<MapEntry<K, V>>,
+    }
+
+    #[derive(Debug, Clone)]
+    pub struct BTreeMap23Iter<'a, K: Ord, V> {
+        map: &'a BTreeMap23<K, V>,
+        entries: BTreeIter<MapEntry<K, V>>,
+    }
+
+    #[derive(Debug)]
+    pub struct BTreeMap23OccupiedEntry<'a, K: Ord, V> {
+        map: &'a mut BTreeMap23<K, V>,
+        leaf: Rc<RefCell<BTreeNode<MapEntry<K, V>>>>,
+        ind: usize,
+    }
+
+    #[derive(Debug)]
+    pub struct BTreeMap23VacantEntryRef<'a, 'b, K: Ord, V, Q: ?Sized> {
+        map: &'a mut BTreeMap23<K, V>,
+        key: &'b Q,
+    }
+
+    #[derive(Debug)]
+    pub enum BTreeMap23EntryRef<'a, 'b, K: Ord, V, Q: ?Sized> {
+        Occupied(BTreeMap23OccupiedEntry<'a, K, V>),
+        Vacant(BTreeMap23VacantEntryRef<'a, 'b, K, V, Q>),
+    }
+
+    #[derive(Debug, Clone)]
+    pub struct BTreeMap23Keys<'a, K: Ord, V> {
+        iter: BTreeMap23Iter<'a, K, V>,
+    }
+
+    #[derive(Debug, Clone)]
+    pub struct BTreeMap23Values<'a, K: Ord, V> {
+        iter: BTreeMap23Iter<'a, K, V>,
+    }
+
+    #[derive(Debug)]
+    pub struct BTreeMap23ValuesMut<'a, K: Ord, V> {
+        entries: BTreeIter<MapEntry<K, V>>,
+        map: std::marker::PhantomData<&'a mut BTreeMap23<K, V>>,
+    }
+
+    #[derive(Debug)]
+    pub struct BTreeMultiMap<K: Ord, V> {
+        groups: BTreeMap23<K, Vec<V>>,
+        len: usize,
+    }
+
+    #[derive(Debug)]
+    pub struct BiBTreeMap<L: Ord + Clone, R: Ord + Clone> {
+        left: BTreeMap23<L, R>,
+        right: BTreeMap23<R, L>,
+    }
+
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    struct IntervalEntry<T> {
+        range: Range<T>,
+    }
+
+    #[derive(Debug)]
+    struct MaxEnd;
+
+    #[derive(Debug)]
+    pub struct BTreeIntervalSet<T: Ord + Clone> {
+        intervals: BTree<IntervalEntry<T>, MaxEnd>,
+    }
+
+    pub struct BTreeBy<T, C: BTreeComparator<T>> {
+        values: BTree<T>,
+        comparator: C,
+    }
+
+    #[derive(Debug, Default, Clone, Copy)]
+    pub struct Descending;
+
+    pub type BTreeDesc<T> = BTreeBy<T, Descending>;
+
+    struct ByKey<K, F> {
+        key: F,
+        marker: std::marker::PhantomData<fn() -> K>,
+    }
+
+    pub struct BTreeByKey<T, K: Ord, F: Fn(&T) -> K> {
+        records: BTreeBy<T, ByKey<K, F>>,
+    }
+
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+    pub enum BTreeOp<T> {
+        Insert(T),
+        Remove(T),
+        PushMax(T),
+        TakeFirstN(usize),
+        TakeLastN(usize),
+        PopFirst,
+        PopLast,
+        RemoveAt(usize),
+        // Ascending ranks removed by a predicate, so replay does not need the closure
+        RemoveRanks(Vec<usize>),
+        RemoveRange(Bound<T>, Bound<T>),
+        SplitOff(T),
+        Append(Vec<T>),
+        Truncate(usize),
+        Dedup,
+        Clear,
+    }
+
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+    pub struct BTreeTrace<T> {
+        ops: Vec<BTreeOp<T>>,
     }
 
-    impl<T: Ord + Eq + Clone> BTreeLeaf<T> {
+    // Exposes every mutator of the wrapped tree, which itself is only lent out immutably
+    #[derive(Debug)]
+    pub struct RecordingBTree<T: Ord + Eq> {
+        tree: BTree<T>,
+        trace: BTreeTrace<T>,
+    }
+
+    #[derive(Debug, Clone)]
+    pub struct BTreeUnion<
+        T,
+        A: Iterator<Item = Rc<T>> = BTreeIter<T>,
+        B: Iterator<Item = Rc<T>> = BTreeIter<T>,
+    > {
+        a: Peekable<A>,
+        b: Peekable<B>,
+    }
+
+    #[derive(Debug, Clone)]
+    pub struct BTreeIntersection<
+        T,
+        A: Iterator<Item = Rc<T>> = BTreeIter<T>,
+        B: Iterator<Item = Rc<T>> = BTreeIter<T>,
+    > {
+        a: Peekable<A>,
+        b: Peekable<B>,
+    }
+
+    #[derive(Debug, Clone)]
+    pub struct BTreeDifference<
+        T,
+        A: Iterator<Item = Rc<T>> = BTreeIter<T>,
+        B: Iterator<Item = Rc<T>> = BTreeIter<T>,
+    > {
+        a: Peekable<A>,
+        b: Peekable<B>,
+    }
+
+    #[derive(Debug, Clone)]
+    pub struct BTreeSymmetricDifference<
+        T,
+        A: Iterator<Item = Rc<T>> = BTreeIter<T>,
+        B: Iterator<Item = Rc<T>> = BTreeIter<T>,
+    > {
+        a: Peekable<A>,
+        b: Peekable<B>,
+    }
+
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub enum BTreeChange<T> {
+        Added(Rc<T>),
+        Removed(Rc<T>),
+    }
+
+    #[derive(Debug, Clone)]
+    pub struct BTreeDiff<T: Ord + Eq> {
+        old: Peekable<BTreeIter<T>>,
+        new: Peekable<BTreeIter<T>>,
+    }
+
+    pub type SortedRcs<I, T> = std::iter::Map<I, fn(T) -> Rc<T>>;
+
+    type BTreeNodeRef<T, A = ()> = Rc<RefCell<BTreeNode<T, A>>>;
+
+    // A leaf together with an index into its values
+    type BTreePosition<T, A = ()> = (BTreeNodeRef<T, A>, usize);
+
+    #[derive(Debug, Clone, Copy)]
+    pub struct UnionView<'a, T: Ord + Eq> {
+        a: &'a BTree<T>,
+        b: &'a BTree<T>,
+    }
+
+    #[derive(Debug, Clone, Copy)]
+    pub struct IntersectionView<'a, T: Ord + Eq> {
+        a: &'a BTree<T>,
+        b: &'a BTree<T>,
+    }
+
+    #[derive(Debug, Clone, Copy)]
+    pub struct DifferenceView<'a, T: Ord + Eq> {
+        a: &'a BTree<T>,
+        b: &'a BTree<T>,
+    }
+
+    #[derive(Debug, Clone)]
+    pub struct DeltaBTreeRange<'a, T: Ord + Eq> {
+        base: Peekable<std::slice::Iter<'a, T>>,
+        inserted: Peekable<BTreeRange<T>>,
+        removed: Peekable<BTreeRange<T>>,
+        overlay: &'a BTree<T>,
+    }
+
+    impl<T, A: BTreeAugment<T>> BTreeLeaf<T, A> {
         #[inline]
         pub fn new(
             values: Vec<Rc<T>>,
-            parent: Option<Weak<RefCell<BTreeNode<T>>>>,
-            next_leaf: Option<Rc<RefCell<BTreeNode<T>>>>,
-            previous_leaf: Option<Weak<RefCell<BTreeNode<T>>>>,
+            parent: Option<Weak<RefCell<BTreeNode<T, A>>>>,
+            next_leaf: Option<Rc<RefCell<BTreeNode<T, A>>>>,
+            previous_leaf: Option<Weak<RefCell<BTreeNode<T, A>>>>,
         ) -> Self {
             Self {
                 values,
@@ -60,14 +511,146 @@ mod btree {
         }
     }
 
-    impl<T: Ord + Eq + Clone> BTreeIter<T> {
+    impl<T, A: BTreeAugment<T>> BTreeIter<T, A> {
         #[inline]
-        fn new(cur_leaf: Option<Rc<RefCell<BTreeNode<T>>>>, cur_ind: usize) -> Self {
+        fn new(cur_leaf: Option<Rc<RefCell<BTreeNode<T, A>>>>, cur_ind: usize) -> Self {
             Self { cur_leaf, cur_ind }
         }
     }
 
-    impl<T: Ord + Eq + Clone> Default for BTreeIter<T> {
+    impl<T, A: BTreeAugment<T>> Clone for BTreeIter<T, A> {
+        #[inline]
+        fn clone(&self) -> Self {
+            Self::new(self.cur_leaf.clone(), self.cur_ind)
+        }
+    }
+
+    impl<T: Ord + Eq + Clone> BTreeIter<T> {
+        pub fn position_token(&self) -> Option<BTreePositionToken<T>> {
+            let leaf = self.cur_leaf.as_ref()?;
+            let key = BTreeNode::value_at(leaf, self.cur_ind);
+
+            // Detached leaves (e.g. after `into_iter`) have no tree to resume in
+            let (rank, root) = BTreeNode::rank_and_root(leaf.clone(), self.cur_ind)?;
+
+            let first_equal = BTreeNode::partition_point(&root, &|v| *v < *key)
+                .map(|(first_leaf, ind)| BTreeNode::position_rank(first_leaf, ind))
+                .unwrap();
+
+            Some(BTreePositionToken {
+                key: (*key).clone(),
+                offset: rank - first_equal,
+            })
+        }
+    }
+
+    impl<T, A: BTreeAugment<T>> BTreeIter<T, A> {
+        pub fn advance_by(&mut self, n: usize) -> Result<(), std::num::NonZeroUsize> {
+            let leaf = match &self.cur_leaf {
+                Some(leaf) => leaf.clone(),
+                None => return std::num::NonZeroUsize::new(n).map_or(Ok(()), Err),
+            };
+
+            let len = unsafe { leaf.borrow().unwrap_as_leaf_unchecked().values.len() };
+
+            if n < len - self.cur_ind {
+                self.cur_ind += n;
+                return Ok(());
+            }
+
+            // Detached leaves (e.g. after `into_iter`) can only be walked
+            let (rank, root) = match BTreeNode::rank_and_root(leaf, self.cur_ind) {
+                Some(rank_and_root) => rank_and_root,
+
+                None => {
+                    return match (0..n).find(|_| self.next().is_none()) {
+                        Some(advanced) => Err(std::num::NonZeroUsize::new(n - advanced).unwrap()),
+                        None => Ok(()),
+                    }
+                }
+            };
+
+            let remaining = BTreeNode::values_number(&root) - rank;
+
+            match n < remaining {
+                true => {
+                    let (leaf, ind) = BTreeNode::position_at(&root, rank + n);
+                    self.cur_leaf = Some(leaf);
+                    self.cur_ind = ind;
+                    Ok(())
+                }
+
+                false => {
+                    self.cur_leaf = None;
+                    self.cur_ind = 0;
+                    std::num::NonZeroUsize::new(n - remaining).map_or(Ok(()), Err)
+                }
+            }
+        }
+
+        #[inline]
+        pub fn enumerated(self) -> BTreeEnumerated<Self> {
+            let rank = self
+                .cur_leaf
+                .as_ref()
+                .and_then(|leaf| BTreeNode::rank_and_root(leaf.clone(), self.cur_ind))
+                .map(|(rank, _)| rank)
+                .unwrap_or_default();
+
+            BTreeEnumerated::new(self, rank)
+        }
+    }
+
+    impl<T: Ord + Eq> BTreeCursor<T> {
+        #[inline]
+        fn new(iter: BTreeIter<T>, direction: BTreeDirection) -> Self {
+            Self {
+                iter,
+                direction,
+                last: None,
+            }
+        }
+
+        #[inline]
+        pub fn direction(&self) -> BTreeDirection {
+            self.direction
+        }
+
+        pub fn token(&self) -> Option<BTreeCursorToken<T>>
+        where
+            T: Clone,
+        {
+            let mut position = self.last.as_ref()?.position_token()?;
+
+            if self.direction == BTreeDirection::Forward {
+                position.offset += 1;
+            }
+
+            Some(BTreeCursorToken {
+                position,
+                direction: self.direction,
+            })
+        }
+    }
+
+    impl<T: Ord + Eq> Iterator for BTreeCursor<T> {
+        type Item = Rc<T>;
+
+        #[inline]
+        fn next(&mut self) -> Option<Self::Item> {
+            let position = self.iter.clone();
+
+            let value = match self.direction {
+                BTreeDirection::Forward => self.iter.next(),
+                BTreeDirection::Backward => self.iter.next_back(),
+            }?;
+
+            self.last = Some(position);
+            Some(value)
+        }
+    }
+
+    impl<T, A: BTreeAugment<T>> Default for BTreeIter<T, A> {
         #[inline]
         fn default() -> Self {
             Self {
@@ -77,7 +660,7 @@ mod btree {
         }
     }
 
-    impl<T: Ord + Eq + Clone> Iterator for BTreeIter<T> {
+    impl<T, A: BTreeAugment<T>> Iterator for BTreeIter<T, A> {
         type Item = Rc<T>;
 
         #[inline]
@@ -112,9 +695,15 @@ mod btree {
                     cur_val
                 })
         }
+
+        #[inline]
+        fn nth(&mut self, n: usize) -> Option<Self::Item> {
+            self.advance_by(n).ok()?;
+            self.next()
+        }
     }
 
-    impl<T: Ord + Eq + Clone> DoubleEndedIterator for BTreeIter<T> {
+    impl<T, A: BTreeAugment<T>> DoubleEndedIterator for BTreeIter<T, A> {
         #[inline]
         fn next_back(&mut self) -> Option<Self::Item> {
             self.cur_leaf
@@ -129,8 +718,7 @@ mod btree {
                         Ok(leaf
                             .previous_leaf
                             .as_ref()
-                            .map(|prev_leaf| prev_leaf.upgrade().map(|prev_leaf| prev_leaf.clone()))
-                            .flatten())
+                            .and_then(|prev_leaf| prev_leaf.upgrade()))
                     }
                 })
                 .map(|prev| {
@@ -145,7 +733,7 @@ mod btree {
                             self.cur_ind = prev_leaf
                                 .as_ref()
                                 .map(|leaf| unsafe {
-                                    leaf.borrow().unwrap_as_leaf_unchecked().values.len()
+                                    leaf.borrow().unwrap_as_leaf_unchecked().values.len() - 1
                                 })
                                 .unwrap_or_default();
 
@@ -158,55 +746,362 @@ mod btree {
         }
     }
 
-    impl<T: Ord + Eq + Clone> BTreeSubTree<T> {
+    impl<T: Ord + Eq> BTreeRange<T> {
         #[inline]
-        pub fn new(
-            children: Vec<Rc<RefCell<BTreeNode<T>>>>,
-            parent: Option<Weak<RefCell<BTreeNode<T>>>>,
-            mid_keys: Vec<Rc<T>>,
+        fn new(
+            front: Option<(Rc<RefCell<BTreeNode<T>>>, usize)>,
+            back: Option<(Rc<RefCell<BTreeNode<T>>>, usize)>,
         ) -> Self {
-            let values_number = children
-                .iter()
-                .map(|node| BTreeNode::values_number(node.clone()))
-                .sum();
+            match (front, back) {
+                (Some(front), Some(back))
+                    if BTreeNode::value_at(&front.0, front.1)
+                        <= BTreeNode::value_at(&back.0, back.1) =>
+                {
+                    Self {
+                        front: Some(front),
+                        back: Some(back),
+                    }
+                }
 
-            Self {
-                children,
-                parent,
-                mid_keys,
-                values_number,
+                _ => Self::default(),
             }
         }
 
         #[inline]
-        pub fn get_children_index_by_value(&self, value: &T) -> usize {
-            match self.mid_keys.len() {
-                1 => match value.cmp(&*self.mid_keys[0]) {
-                    Ordering::Less => 0,
-                    _ => 1,
-                },
+        fn is_last(&self, leaf: &Rc<RefCell<BTreeNode<T>>>, ind: usize) -> bool {
+            self.front
+                .iter()
+                .chain(self.back.iter())
+                .any(|(other_leaf, other_ind)| Rc::ptr_eq(leaf, other_leaf) && ind == *other_ind)
+        }
+    }
 
-                2 => {
-                    if *value < *self.mid_keys[0] {
-                        0
-                    } else if *value > *self.mid_keys[0] && *value < *self.mid_keys[1] {
-                        1
-                    } else {
-                        2
-                    }
-                }
+    impl<T: Ord + Eq> BTreeRange<T> {
+        #[inline]
+        pub fn enumerated(self) -> BTreeEnumerated<Self> {
+            let rank = self
+                .front
+                .as_ref()
+                .and_then(|(leaf, ind)| BTreeNode::rank_and_root(leaf.clone(), *ind))
+                .map(|(rank, _)| rank)
+                .unwrap_or_default();
 
-                _ => unreachable!(),
-            }
+            BTreeEnumerated::new(self, rank)
         }
     }
 
-    impl<T: Ord + Eq + Clone> BTreeNode<T> {
+    impl<T: Ord + Eq> Default for BTreeRange<T> {
         #[inline]
-        pub fn is_leaf(&self) -> bool {
-            match self {
-                BTreeNode::Leaf { .. } => true,
-                BTreeNode::SubTree { .. } => false,
+        fn default() -> Self {
+            Self {
+                front: None,
+                back: None,
+            }
+        }
+    }
+
+    impl<T: Ord + Eq> Iterator for BTreeRange<T> {
+        type Item = Rc<T>;
+
+        #[inline]
+        fn next(&mut self) -> Option<Self::Item> {
+            let (leaf, ind) = self.front.take()?;
+            let value = BTreeNode::value_at(&leaf, ind);
+
+            match self.is_last(&leaf, ind) {
+                true => self.back = None,
+                false => self.front = BTreeNode::next_position(leaf, ind),
+            }
+
+            Some(value)
+        }
+    }
+
+    impl<T: Ord + Eq> DoubleEndedIterator for BTreeRange<T> {
+        #[inline]
+        fn next_back(&mut self) -> Option<Self::Item> {
+            let (leaf, ind) = self.back.take()?;
+            let value = BTreeNode::value_at(&leaf, ind);
+
+            match self.is_last(&leaf, ind) {
+                true => self.front = None,
+                false => self.back = BTreeNode::previous_position(leaf, ind),
+            }
+
+            Some(value)
+        }
+    }
+
+    impl<T: Ord + Eq> BTreeDrain<T> {
+        #[inline]
+        fn take_leaf(leaf: &BTreeNodeRef<T>) -> (Vec<Rc<T>>, Option<BTreeNodeRef<T>>) {
+            let mut leaf_ref = leaf.borrow_mut();
+            let leaf_ref = unsafe { leaf_ref.unwrap_as_leaf_mut_unchecked() };
+            (
+                std::mem::take(&mut leaf_ref.values),
+                leaf_ref.next_leaf.take(),
+            )
+        }
+    }
+
+    impl<T: Ord + Eq> Iterator for BTreeDrain<T> {
+        type Item = Rc<T>;
+
+        fn next(&mut self) -> Option<Self::Item> {
+            loop {
+                if let Some(value) = self.values.next() {
+                    return Some(value);
+                }
+
+                let (values, next_leaf) = Self::take_leaf(self.cur_leaf.as_ref()?);
+                self.values = values.into_iter();
+                self.cur_leaf = next_leaf;
+            }
+        }
+    }
+
+    impl<T: Ord + Eq> Drop for BTreeDrain<T> {
+        fn drop(&mut self) {
+            while let Some(leaf) = self.cur_leaf.take() {
+                self.cur_leaf = Self::take_leaf(&leaf).1;
+            }
+        }
+    }
+
+    impl<T: Ord + Eq, F: FnMut(&T) -> bool> Iterator for BTreeExtractIf<'_, T, F> {
+        type Item = Rc<T>;
+
+        fn next(&mut self) -> Option<Self::Item> {
+            while self.rank < self.tree.len() {
+                let (leaf, ind) = BTreeNode::position_at(self.tree.root.as_ref()?, self.rank);
+
+                match (self.pred)(&BTreeNode::value_at(&leaf, ind)) {
+                    true => return Some(self.tree.remove_at_position(leaf, ind)),
+                    false => self.rank += 1,
+                }
+            }
+
+            None
+        }
+    }
+
+    impl<I> BTreeEnumerated<I> {
+        #[inline]
+        fn new(iter: I, rank: usize) -> Self {
+            Self { iter, rank }
+        }
+    }
+
+    impl<T: Ord + Eq> BTreeCounts<T> {
+        #[inline]
+        fn new(iter: BTreeIter<T>) -> Self {
+            Self {
+                iter: iter.peekable(),
+            }
+        }
+    }
+
+    impl<T: Ord + Eq> Iterator for BTreeCounts<T> {
+        type Item = (Rc<T>, usize);
+
+        fn next(&mut self) -> Option<Self::Item> {
+            let value = self.iter.next()?;
+            let mut count = 1;
+
+            while self.iter.next_if(|next| **next == *value).is_some() {
+                count += 1;
+            }
+
+            Some((value, count))
+        }
+    }
+
+    impl<T, I: Iterator<Item = Rc<T>>> Iterator for BTreeEnumerated<I> {
+        type Item = (usize, Rc<T>);
+
+        #[inline]
+        fn next(&mut self) -> Option<Self::Item> {
+            let value = self.iter.next()?;
+            let rank = self.rank;
+            self.rank += 1;
+            Some((rank, value))
+        }
+    }
+
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub enum BTreeTraverseEvent<T: Ord + Eq> {
+        Enter(usize, Vec<Rc<T>>),
+        Leaf(usize, Vec<Rc<T>>),
+        Exit(usize),
+    }
+
+    #[derive(Debug, Clone)]
+    enum BTreeTraverseStep<T: Ord + Eq> {
+        Visit(Rc<RefCell<BTreeNode<T>>>, usize),
+        Exit(usize),
+    }
+
+    #[derive(Debug, Clone)]
+    pub struct BTreeTraverseEvents<T: Ord + Eq> {
+        stack: Vec<BTreeTraverseStep<T>>,
+    }
+
+    impl<T: Ord + Eq> BTreeTraverseEvents<T> {
+        #[inline]
+        fn new(root: Option<Rc<RefCell<BTreeNode<T>>>>) -> Self {
+            Self {
+                stack: root
+                    .map(|root| vec![BTreeTraverseStep::Visit(root, 0)])
+                    .unwrap_or_default(),
+            }
+        }
+    }
+
+    impl<T: Ord + Eq> Iterator for BTreeTraverseEvents<T> {
+        type Item = BTreeTraverseEvent<T>;
+
+        fn next(&mut self) -> Option<Self::Item> {
+            match self.stack.pop()? {
+                BTreeTraverseStep::Exit(depth) => Some(BTreeTraverseEvent::Exit(depth)),
+
+                BTreeTraverseStep::Visit(node, depth) => {
+                    match &*node.borrow() {
+                        BTreeNode::Leaf { leaf } => {
+                            Some(BTreeTraverseEvent::Leaf(depth, leaf.values.clone()))
+                        }
+
+                        BTreeNode::SubTree { subtree } => {
+                            self.stack.push(BTreeTraverseStep::Exit(depth));
+
+                            self.stack.extend(
+                                subtree.children.iter().rev().map(|child| {
+                                    BTreeTraverseStep::Visit(child.clone(), depth + 1)
+                                }),
+                            );
+
+                            Some(BTreeTraverseEvent::Enter(depth, subtree.mid_keys.clone()))
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    impl<T: Ord + Eq> BTreeMidKeys<T> {
+        #[inline]
+        fn new(root: Option<Rc<RefCell<BTreeNode<T>>>>) -> Self {
+            Self {
+                stack: root.map(|root| vec![(root, 0)]).unwrap_or_default(),
+            }
+        }
+    }
+
+    impl<T: Ord + Eq> Iterator for BTreeMidKeys<T> {
+        type Item = (usize, Vec<Rc<T>>);
+
+        fn next(&mut self) -> Option<Self::Item> {
+            while let Some((node, depth)) = self.stack.pop() {
+                let node = node.borrow();
+
+                if let BTreeNode::SubTree { subtree } = &*node {
+                    self.stack.extend(
+                        subtree
+                            .children
+                            .iter()
+                            .rev()
+                            .map(|child| (child.clone(), depth + 1)),
+                    );
+
+                    return Some((depth, subtree.mid_keys.clone()));
+                }
+            }
+
+            None
+        }
+    }
+
+    // Plain trees fold nothing, so their summaries take no space
+    impl<T> BTreeAugment<T> for () {
+        type Summary = ();
+
+        #[inline]
+        fn identity() {}
+
+        #[inline]
+        fn summarize(_: &T) {}
+
+        #[inline]
+        fn combine(_: &(), _: &()) {}
+    }
+
+    impl<T: Debug, A: BTreeAugment<T> + Debug> Debug for BTreeSubTree<T, A> {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            f.debug_struct("BTreeSubTree")
+                .field("children", &self.children)
+                .field("parent", &self.parent)
+                .field("mid_keys", &self.mid_keys)
+                .field("values_number", &self.values_number)
+                .finish_non_exhaustive()
+        }
+    }
+
+    impl<T, A: BTreeAugment<T>> BTreeSubTree<T, A> {
+        #[inline]
+        pub fn new(
+            children: Vec<Rc<RefCell<BTreeNode<T, A>>>>,
+            parent: Option<Weak<RefCell<BTreeNode<T, A>>>>,
+            mid_keys: Vec<Rc<T>>,
+        ) -> Self {
+            let values_number = children
+                .iter()
+                .map(|node| BTreeNode::values_number(node))
+                .sum();
+
+            let mut subtree = Self {
+                children,
+                parent,
+                mid_keys,
+                values_number,
+                summary: A::identity(),
+            };
+
+            subtree.resummarize();
+            subtree
+        }
+
+        // Zero-sized summaries carry nothing, so plain trees never fold their children
+        #[inline]
+        fn resummarize(&mut self) {
+            if std::mem::size_of::<A::Summary>() != 0 {
+                self.summary = self.children.iter().fold(A::identity(), |summary, child| {
+                    A::combine(&summary, &BTreeNode::summary(child))
+                });
+            }
+        }
+
+        #[inline]
+        pub fn get_children_index_by_value(&self, value: &T) -> usize
+        where
+            T: Ord,
+        {
+            match self.mid_keys.len() {
+                1..=MAX_KEYS => self.mid_keys.partition_point(|key| **key <= *value),
+                _ => unreachable!(),
+            }
+        }
+
+        #[inline]
+        fn get_children_index_by(&self, is_before: &impl Fn(&T) -> bool) -> usize {
+            self.mid_keys.partition_point(|key| is_before(key))
+        }
+    }
+
+    impl<T, A: BTreeAugment<T>> BTreeNode<T, A> {
+        #[inline]
+        pub fn is_leaf(&self) -> bool {
+            match self {
+                BTreeNode::Leaf { .. } => true,
+                BTreeNode::SubTree { .. } => false,
             }
         }
 
@@ -216,7 +1111,7 @@ mod btree {
         }
 
         #[inline]
-        pub fn unwrap_as_leaf(&self) -> &BTreeLeaf<T> {
+        pub fn unwrap_as_leaf(&self) -> &BTreeLeaf<T, A> {
             match self {
                 BTreeNode::Leaf { leaf } => leaf,
                 BTreeNode::SubTree { .. } => unreachable!(),
@@ -224,7 +1119,7 @@ mod btree {
         }
 
         #[inline]
-        pub fn unwrap_as_leaf_mut(&mut self) -> &mut BTreeLeaf<T> {
+        pub fn unwrap_as_leaf_mut(&mut self) -> &mut BTreeLeaf<T, A> {
             match self {
                 BTreeNode::Leaf { leaf } => leaf,
                 BTreeNode::SubTree { .. } => unreachable!(),
@@ -232,7 +1127,7 @@ mod btree {
         }
 
         #[inline]
-        pub unsafe fn unwrap_as_leaf_unchecked(&self) -> &BTreeLeaf<T> {
+        pub unsafe fn unwrap_as_leaf_unchecked(&self) -> &BTreeLeaf<T, A> {
             match self {
                 BTreeNode::Leaf { leaf } => leaf,
                 BTreeNode::SubTree { .. } => unreachable_unchecked(),
@@ -240,7 +1135,7 @@ mod btree {
         }
 
         #[inline]
-        pub unsafe fn unwrap_as_leaf_mut_unchecked(&mut self) -> &mut BTreeLeaf<T> {
+        pub unsafe fn unwrap_as_leaf_mut_unchecked(&mut self) -> &mut BTreeLeaf<T, A> {
             match self {
                 BTreeNode::Leaf { leaf } => leaf,
                 BTreeNode::SubTree { .. } => unreachable_unchecked(),
@@ -248,7 +1143,7 @@ mod btree {
         }
 
         #[inline]
-        pub fn unwrap_as_subtree(&self) -> &BTreeSubTree<T> {
+        pub fn unwrap_as_subtree(&self) -> &BTreeSubTree<T, A> {
             match self {
                 BTreeNode::SubTree { subtree } => subtree,
                 BTreeNode::Leaf { .. } => unreachable!(),
@@ -256,7 +1151,7 @@ mod btree {
         }
 
         #[inline]
-        pub unsafe fn unwrap_as_subtree_unchecked(&self) -> &BTreeSubTree<T> {
+        pub unsafe fn unwrap_as_subtree_unchecked(&self) -> &BTreeSubTree<T, A> {
             match self {
                 BTreeNode::SubTree { subtree } => subtree,
                 BTreeNode::Leaf { .. } => unreachable_unchecked(),
@@ -264,7 +1159,7 @@ mod btree {
         }
 
         #[inline]
-        pub fn unwrap_as_subtree_mut(&mut self) -> &mut BTreeSubTree<T> {
+        pub fn unwrap_as_subtree_mut(&mut self) -> &mut BTreeSubTree<T, A> {
             match self {
                 BTreeNode::SubTree { subtree } => subtree,
                 BTreeNode::Leaf { .. } => unreachable!(),
@@ -272,7 +1167,7 @@ mod btree {
         }
 
         #[inline]
-        pub unsafe fn unwrap_as_subtree_mut_unchecked(&mut self) -> &mut BTreeSubTree<T> {
+        pub unsafe fn unwrap_as_subtree_mut_unchecked(&mut self) -> &mut BTreeSubTree<T, A> {
             match self {
                 BTreeNode::SubTree { subtree } => subtree,
                 BTreeNode::Leaf { .. } => unreachable_unchecked(),
@@ -280,7 +1175,7 @@ mod btree {
         }
 
         #[inline]
-        pub fn get_parent(&self) -> Option<&Weak<RefCell<BTreeNode<T>>>> {
+        pub fn get_parent(&self) -> Option<&Weak<RefCell<BTreeNode<T, A>>>> {
             match self {
                 BTreeNode::Leaf { leaf } => leaf.parent.as_ref(),
                 BTreeNode::SubTree { subtree } => subtree.parent.as_ref(),
@@ -288,7 +1183,7 @@ mod btree {
         }
 
         #[inline]
-        pub fn get_parent_mut(&mut self) -> Option<&mut Weak<RefCell<BTreeNode<T>>>> {
+        pub fn get_parent_mut(&mut self) -> Option<&mut Weak<RefCell<BTreeNode<T, A>>>> {
             match self {
                 BTreeNode::Leaf { leaf } => leaf.parent.as_mut(),
                 BTreeNode::SubTree { subtree } => subtree.parent.as_mut(),
@@ -296,7 +1191,7 @@ mod btree {
         }
 
         #[inline]
-        pub fn set_parent(&mut self, new_parent: Option<Weak<RefCell<BTreeNode<T>>>>) {
+        pub fn set_parent(&mut self, new_parent: Option<Weak<RefCell<BTreeNode<T, A>>>>) {
             match self {
                 BTreeNode::Leaf { leaf } => leaf.parent = new_parent,
                 BTreeNode::SubTree { subtree } => subtree.parent = new_parent,
@@ -319,68 +1214,38 @@ mod btree {
             }
         }
 
-        pub fn first_leaf(this: Rc<RefCell<Self>>) -> Rc<RefCell<Self>> {
-            match {
-                let is_leaf = this.borrow().is_leaf();
-                is_leaf
-            } {
-                true => this,
-
-                false => BTreeNode::first_leaf(unsafe {
-                    this.borrow()
-                        .unwrap_as_subtree_unchecked()
-                        .children
-                        .first()
-                        .unwrap()
-                        .clone()
-                }),
+        pub fn first_leaf(this: &Rc<RefCell<Self>>) -> Rc<RefCell<Self>> {
+            match &*this.borrow() {
+                BTreeNode::Leaf { .. } => this.clone(),
+                BTreeNode::SubTree { subtree } => {
+                    Self::first_leaf(subtree.children.first().unwrap())
+                }
             }
         }
 
-        #[inline]
-        pub fn first(this: Rc<RefCell<Self>>) -> Option<Rc<T>> {
-            unsafe {
-                Self::first_leaf(this)
-                    .borrow()
-                    .unwrap_as_leaf_unchecked()
-                    .values
-                    .first()
-                    .map(|v| v.clone())
+        pub fn first(this: &RefCell<Self>) -> Option<Rc<T>> {
+            match &*this.borrow() {
+                BTreeNode::Leaf { leaf } => leaf.values.first().cloned(),
+                BTreeNode::SubTree { subtree } => Self::first(subtree.children.first().unwrap()),
             }
         }
 
-        pub fn last_leaf(this: Rc<RefCell<Self>>) -> Rc<RefCell<Self>> {
-            match {
-                let is_leaf = this.borrow().is_leaf();
-                is_leaf
-            } {
-                true => this,
-
-                false => BTreeNode::last_leaf(unsafe {
-                    this.borrow()
-                        .unwrap_as_subtree_unchecked()
-                        .children
-                        .last()
-                        .unwrap()
-                        .clone()
-                }),
+        pub fn last_leaf(this: &Rc<RefCell<Self>>) -> Rc<RefCell<Self>> {
+            match &*this.borrow() {
+                BTreeNode::Leaf { .. } => this.clone(),
+                BTreeNode::SubTree { subtree } => Self::last_leaf(subtree.children.last().unwrap()),
             }
         }
 
-        #[inline]
-        pub fn last(this: Rc<RefCell<Self>>) -> Option<Rc<T>> {
-            unsafe {
-                Self::last_leaf(this)
-                    .borrow()
-                    .unwrap_as_leaf_unchecked()
-                    .values
-                    .last()
-                    .map(|v| v.clone())
+        pub fn last(this: &RefCell<Self>) -> Option<Rc<T>> {
+            match &*this.borrow() {
+                BTreeNode::Leaf { leaf } => leaf.values.last().cloned(),
+                BTreeNode::SubTree { subtree } => Self::last(subtree.children.last().unwrap()),
             }
         }
 
         #[inline]
-        pub fn values_number(this: Rc<RefCell<Self>>) -> usize {
+        pub fn values_number(this: &RefCell<Self>) -> usize {
             match &*this.borrow() {
                 BTreeNode::Leaf { leaf } => leaf.values.len(),
                 BTreeNode::SubTree { subtree } => subtree.values_number,
@@ -389,10 +1254,10 @@ mod btree {
 
         pub fn update_parent_value_number(parent: Rc<RefCell<Self>>) {
             unsafe {
-                parent
-                    .borrow_mut()
-                    .unwrap_as_subtree_mut_unchecked()
-                    .values_number += 1;
+                let mut parent_ref = parent.borrow_mut();
+                let parent_subtree = parent_ref.unwrap_as_subtree_mut_unchecked();
+                parent_subtree.values_number += 1;
+                parent_subtree.resummarize();
             }
 
             unsafe {
@@ -402,141 +1267,565 @@ mod btree {
             }
         }
 
-        pub fn get(this: Rc<RefCell<Self>>, index: usize) -> Rc<T> {
-            match {
-                let is_leaf = this.borrow().is_leaf();
-                is_leaf
-            } {
-                true => unsafe { this.borrow().unwrap_as_leaf_unchecked().values[index].clone() },
+        pub fn get(this: &RefCell<Self>, index: usize) -> Rc<T> {
+            match &*this.borrow() {
+                BTreeNode::Leaf { leaf } => leaf.values[index].clone(),
 
-                false => {
+                BTreeNode::SubTree { subtree } => {
                     let mut reduced_index = index;
 
-                    let child = unsafe {
-                        let this_ref = this.borrow();
-
-                        this_ref
-                            .unwrap_as_subtree_unchecked()
-                            .children
-                            .iter()
-                            .skip_while(|&node| {
-                                let values_number = Self::values_number(node.clone());
-
-                                if reduced_index < values_number {
-                                    false
-                                } else {
-                                    reduced_index -= values_number;
-                                    true
-                                }
-                            })
-                            .next()
-                            .unwrap()
-                            .clone()
-                    };
+                    let child = subtree
+                        .children
+                        .iter()
+                        .find(|&node| {
+                            let values_number = Self::values_number(node);
+
+                            if reduced_index < values_number {
+                                true
+                            } else {
+                                reduced_index -= values_number;
+                                false
+                            }
+                        })
+                        .unwrap();
 
                     Self::get(child, reduced_index)
                 }
             }
         }
 
-        pub fn find(this: Rc<RefCell<Self>>, value: &T) -> Rc<RefCell<Self>> {
-            match {
-                let is_leaf = this.borrow().is_leaf();
-                is_leaf
-            } {
-                true => this,
-
-                false => unsafe {
-                    let this_ref = this.borrow();
-                    let this_ref = this_ref.unwrap_as_subtree_unchecked();
-                    let child_index = this_ref.get_children_index_by_value(value);
-                    let child = this_ref.children[child_index].clone();
-                    Self::find(child, value)
-                },
+        #[inline]
+        fn position_rank(leaf: Rc<RefCell<Self>>, ind: usize) -> usize {
+            Self::rank_and_root(leaf, ind).unwrap().0
+        }
+
+        fn rank_and_root(
+            leaf: Rc<RefCell<Self>>,
+            ind: usize,
+        ) -> Option<(usize, Rc<RefCell<Self>>)> {
+            let mut rank = ind;
+            let mut node = leaf;
+
+            loop {
+                let parent = match node.borrow().get_parent() {
+                    Some(parent) => parent.upgrade()?,
+                    None => return Some((rank, node.clone())),
+                };
+
+                rank += unsafe {
+                    parent
+                        .borrow()
+                        .unwrap_as_subtree_unchecked()
+                        .children
+                        .iter()
+                        .take_while(|child| !Rc::ptr_eq(child, &node))
+                        .map(|child| Self::values_number(child))
+                        .sum::<usize>()
+                };
+
+                node = parent;
             }
         }
-    }
 
-    impl<T: Ord + Eq + Clone> BTree<T> {
-        #[inline]
-        pub const fn new() -> Self {
-            Self { root: None }
+        fn position_at(this: &Rc<RefCell<Self>>, index: usize) -> (Rc<RefCell<Self>>, usize) {
+            match &*this.borrow() {
+                BTreeNode::Leaf { .. } => (this.clone(), index),
+
+                BTreeNode::SubTree { subtree } => {
+                    let mut reduced_index = index;
+
+                    let child = subtree
+                        .children
+                        .iter()
+                        .find(|&node| {
+                            let values_number = Self::values_number(node);
+
+                            if reduced_index < values_number {
+                                true
+                            } else {
+                                reduced_index -= values_number;
+                                false
+                            }
+                        })
+                        .unwrap();
+
+                    Self::position_at(child, reduced_index)
+                }
+            }
         }
 
         #[inline]
-        pub fn len(&self) -> usize {
-            self.root
-                .as_ref()
-                .map(|node| BTreeNode::values_number(node.clone()))
-                .unwrap_or_default()
+        fn value_at(leaf: &Rc<RefCell<Self>>, ind: usize) -> Rc<T> {
+            unsafe { leaf.borrow().unwrap_as_leaf_unchecked().values[ind].clone() }
         }
 
         #[inline]
-        pub fn is_empty(&self) -> bool {
-            self.len() == 0
+        fn next_position(
+            leaf: Rc<RefCell<Self>>,
+            ind: usize,
+        ) -> Option<(Rc<RefCell<Self>>, usize)> {
+            let leaf_ref = leaf.borrow();
+            let leaf_ref = unsafe { leaf_ref.unwrap_as_leaf_unchecked() };
+
+            match ind + 1 < leaf_ref.values.len() {
+                true => Some((leaf.clone(), ind + 1)),
+                false => leaf_ref.next_leaf.clone().map(|next_leaf| (next_leaf, 0)),
+            }
         }
 
         #[inline]
-        pub fn is_not_empty(&self) -> bool {
-            !self.is_empty()
+        fn previous_position(
+            leaf: Rc<RefCell<Self>>,
+            ind: usize,
+        ) -> Option<(Rc<RefCell<Self>>, usize)> {
+            match ind {
+                0 => unsafe {
+                    leaf.borrow()
+                        .unwrap_as_leaf_unchecked()
+                        .previous_leaf
+                        .as_ref()
+                        .and_then(|prev_leaf| prev_leaf.upgrade())
+                        .map(|prev_leaf| {
+                            let len = prev_leaf.borrow().unwrap_as_leaf_unchecked().values.len();
+                            (prev_leaf, len - 1)
+                        })
+                },
+
+                _ => Some((leaf, ind - 1)),
+            }
         }
 
         #[inline]
-        fn new_root_after_division(
-            first_node: Rc<RefCell<BTreeNode<T>>>,
-            second_node: Rc<RefCell<BTreeNode<T>>>,
-            mid_key: Rc<T>,
-        ) -> Rc<RefCell<BTreeNode<T>>> {
-            let new_root = Rc::new(RefCell::new(BTreeNode::SubTree {
-                subtree: BTreeSubTree::new(
-                    vec![first_node.clone(), second_node.clone()],
-                    None,
-                    vec![mid_key],
+        fn last_position(this: &Rc<RefCell<Self>>) -> (Rc<RefCell<Self>>, usize) {
+            let leaf = Self::last_leaf(this);
+            let len = unsafe { leaf.borrow().unwrap_as_leaf_unchecked().values.len() };
+            (leaf, len - 1)
+        }
+
+        fn partition_point(
+            this: &Rc<RefCell<Self>>,
+            is_before: &impl Fn(&T) -> bool,
+        ) -> Option<(Rc<RefCell<Self>>, usize)> {
+            match &*this.borrow() {
+                BTreeNode::SubTree { subtree } => Self::partition_point(
+                    &subtree.children[subtree.get_children_index_by(is_before)],
+                    is_before,
                 ),
-            }));
 
-            first_node
-                .borrow_mut()
-                .set_parent(Some(Rc::downgrade(&new_root)));
+                BTreeNode::Leaf { leaf } => {
+                    let ind = leaf.values.partition_point(|v| is_before(v));
 
-            second_node
-                .borrow_mut()
-                .set_parent(Some(Rc::downgrade(&new_root)));
+                    match ind < leaf.values.len() {
+                        true => Some((this.clone(), ind)),
+                        false => leaf.next_leaf.clone().map(|next_leaf| (next_leaf, 0)),
+                    }
+                }
+            }
+        }
 
-            new_root
+        fn insertion_point(
+            this: &Rc<RefCell<Self>>,
+            is_before: &impl Fn(&T) -> bool,
+        ) -> (Rc<RefCell<Self>>, usize, usize) {
+            let mut node = this.clone();
+            let mut node_ind = 0;
+
+            loop {
+                let child = match &*node.borrow() {
+                    BTreeNode::SubTree { subtree } => {
+                        node_ind = subtree.get_children_index_by(is_before);
+                        subtree.children[node_ind].clone()
+                    }
+
+                    BTreeNode::Leaf { leaf } => {
+                        let pos = leaf.values.partition_point(|v| is_before(v));
+                        return (node.clone(), node_ind, pos);
+                    }
+                };
+
+                node = child;
+            }
+        }
+
+        fn value_before(leaf: &Rc<RefCell<Self>>, pos: usize) -> Option<Rc<T>> {
+            let leaf_ref = leaf.borrow();
+            let leaf_ref = unsafe { leaf_ref.unwrap_as_leaf_unchecked() };
+
+            match pos {
+                0 => leaf_ref.previous_leaf.as_ref().and_then(Weak::upgrade).map(
+                    |previous_leaf| unsafe {
+                        previous_leaf
+                            .borrow()
+                            .unwrap_as_leaf_unchecked()
+                            .values
+                            .last()
+                            .unwrap()
+                            .clone()
+                    },
+                ),
+
+                _ => Some(leaf_ref.values[pos - 1].clone()),
+            }
+        }
+
+        pub fn find(this: &Rc<RefCell<Self>>, value: &T) -> Rc<RefCell<Self>>
+        where
+            T: Ord,
+        {
+            match &*this.borrow() {
+                BTreeNode::Leaf { .. } => this.clone(),
+
+                BTreeNode::SubTree { subtree } => Self::find(
+                    &subtree.children[subtree.get_children_index_by_value(value)],
+                    value,
+                ),
+            }
+        }
+
+        // Points the separator right before `leaf` at its first value
+        // if the separator is stale
+        fn refresh_separator(leaf: &Rc<RefCell<Self>>, is_stale: impl Fn(&Rc<T>) -> bool) {
+            let mut node = leaf.clone();
+
+            loop {
+                let parent = match node.borrow().get_parent().and_then(Weak::upgrade) {
+                    Some(parent) => parent,
+                    None => return,
+                };
+
+                let mut parent_ref = parent.borrow_mut();
+                let subtree = unsafe { parent_ref.unwrap_as_subtree_mut_unchecked() };
+
+                let node_ind = subtree
+                    .children
+                    .iter()
+                    .position(|child| Rc::ptr_eq(child, &node))
+                    .unwrap();
+
+                if node_ind > 0 {
+                    if is_stale(&subtree.mid_keys[node_ind - 1]) {
+                        subtree.mid_keys[node_ind - 1] = Self::value_at(leaf, 0);
+                    }
+
+                    return;
+                }
+
+                drop(parent_ref);
+                node = parent;
+            }
+        }
+
+        fn decrease_parent_value_number(parent: Rc<RefCell<Self>>) {
+            let mut cur_node = Some(parent);
+
+            while let Some(node) = cur_node {
+                let mut node_ref = node.borrow_mut();
+                let subtree = unsafe { node_ref.unwrap_as_subtree_mut_unchecked() };
+                subtree.values_number -= 1;
+                subtree.resummarize();
+                cur_node = subtree.parent.as_ref().and_then(Weak::upgrade);
+            }
+        }
+    }
+
+    impl<T: Clone, A: BTreeAugment<T>> BTreeNode<T, A> {
+        fn clone_values_into(source: &[Rc<T>], target: &mut Vec<Rc<T>>) {
+            target.truncate(source.len());
+
+            target
+                .iter_mut()
+                .zip(source)
+                .for_each(|(target, source)| match Rc::get_mut(target) {
+                    Some(target) => target.clone_from(source),
+                    None => *target = Rc::new((**source).clone()),
+                });
+
+            let reused = target.len();
+            target.extend(source[reused..].iter().map(|v| Rc::new((**v).clone())));
+        }
+
+        fn clone_into(
+            source: &Rc<RefCell<Self>>,
+            target: Option<Rc<RefCell<Self>>>,
+            parent: Option<Weak<RefCell<Self>>>,
+            prev_leaf: &mut Option<Rc<RefCell<Self>>>,
+        ) -> Rc<RefCell<Self>> {
+            match &*source.borrow() {
+                BTreeNode::Leaf { leaf } => {
+                    let target = target
+                        .filter(|node| node.borrow().is_leaf())
+                        .unwrap_or_else(|| {
+                            Rc::new(RefCell::new(BTreeNode::Leaf {
+                                leaf: BTreeLeaf::new(vec![], None, None, None),
+                            }))
+                        });
+
+                    unsafe {
+                        let mut target_ref = target.borrow_mut();
+                        let target_leaf = target_ref.unwrap_as_leaf_mut_unchecked();
+
+                        Self::clone_values_into(&leaf.values, &mut target_leaf.values);
+                        target_leaf.parent = parent;
+                        target_leaf.next_leaf = None;
+                        target_leaf.previous_leaf = prev_leaf.as_ref().map(Rc::downgrade);
+                    }
+
+                    if let Some(prev_leaf) = prev_leaf.replace(target.clone()) {
+                        unsafe {
+                            prev_leaf
+                                .borrow_mut()
+                                .unwrap_as_leaf_mut_unchecked()
+                                .next_leaf = Some(target.clone());
+                        }
+                    }
+
+                    target
+                }
+
+                BTreeNode::SubTree { subtree } => {
+                    let target = target
+                        .filter(|node| node.borrow().is_node())
+                        .unwrap_or_else(|| {
+                            Rc::new(RefCell::new(BTreeNode::SubTree {
+                                subtree: BTreeSubTree::new(vec![], None, vec![]),
+                            }))
+                        });
+
+                    let mut children = unsafe {
+                        let mut target_ref = target.borrow_mut();
+                        let target_subtree = target_ref.unwrap_as_subtree_mut_unchecked();
+
+                        // Separators are rebuilt from the cloned children, so they share
+                        // the cloned values instead of keeping copies of their own
+                        target_subtree.mid_keys.clear();
+                        target_subtree.parent = parent;
+                        target_subtree.values_number = subtree.values_number;
+                        target_subtree.summary = subtree.summary.clone();
+                        std::mem::take(&mut target_subtree.children)
+                    };
+
+                    children.truncate(subtree.children.len());
+
+                    subtree.children.iter().enumerate().for_each(|(i, child)| {
+                        let cloned = Self::clone_into(
+                            child,
+                            children.get(i).cloned(),
+                            Some(Rc::downgrade(&target)),
+                            prev_leaf,
+                        );
+
+                        match i < children.len() {
+                            true => children[i] = cloned,
+                            false => children.push(cloned),
+                        }
+                    });
+
+                    unsafe {
+                        let mut target_ref = target.borrow_mut();
+                        let target_subtree = target_ref.unwrap_as_subtree_mut_unchecked();
+
+                        target_subtree.mid_keys.extend(
+                            children[1..]
+                                .iter()
+                                .map(|child| BTreeNode::value_at(&Self::first_leaf(child), 0)),
+                        );
+
+                        target_subtree.children = children;
+                    }
+
+                    target
+                }
+            }
+        }
+    }
+
+    impl<T, A: BTreeAugment<T>> BTreeNode<T, A> {
+        fn height(this: &Rc<RefCell<Self>>) -> usize {
+            match &*this.borrow() {
+                BTreeNode::Leaf { .. } => 0,
+                BTreeNode::SubTree { subtree } => Self::height(&subtree.children[0]) + 1,
+            }
+        }
+
+        fn resummarize_ancestors(this: &Rc<RefCell<Self>>) {
+            let mut cur_node = this.borrow().get_parent().and_then(Weak::upgrade);
+
+            while let Some(node) = cur_node {
+                let mut node_ref = node.borrow_mut();
+                let subtree = unsafe { node_ref.unwrap_as_subtree_mut_unchecked() };
+                subtree.resummarize();
+                cur_node = subtree.parent.as_ref().and_then(Weak::upgrade);
+            }
+        }
+
+        // Leaves hold at most two values, so only subtrees keep their summary
+        fn summary(this: &RefCell<Self>) -> A::Summary {
+            match &*this.borrow() {
+                BTreeNode::Leaf { leaf } => {
+                    leaf.values.iter().fold(A::identity(), |summary, value| {
+                        A::combine(&summary, &A::summarize(value))
+                    })
+                }
+
+                BTreeNode::SubTree { subtree } => subtree.summary.clone(),
+            }
+        }
+
+        fn fold_ranks(this: &Rc<RefCell<Self>>, start: usize, end: usize) -> Option<A::Summary> {
+            if start >= end {
+                return None;
+            }
+
+            if start == 0 && end == Self::values_number(this) {
+                return Some(Self::summary(this));
+            }
+
+            match &*this.borrow() {
+                BTreeNode::Leaf { leaf } => leaf.values[start..end]
+                    .iter()
+                    .map(|value| A::summarize(value))
+                    .reduce(|left, right| A::combine(&left, &right)),
+
+                BTreeNode::SubTree { subtree } => {
+                    let mut offset = 0;
+
+                    subtree
+                        .children
+                        .iter()
+                        .filter_map(|child| {
+                            let len = Self::values_number(child);
+
+                            let folded = Self::fold_ranks(
+                                child,
+                                start.saturating_sub(offset),
+                                end.saturating_sub(offset).min(len),
+                            );
+
+                            offset += len;
+                            folded
+                        })
+                        .reduce(|left, right| A::combine(&left, &right))
+                }
+            }
+        }
+
+        fn recount_values_number(this: &Rc<RefCell<Self>>) {
+            let mut cur_node = Some(this.clone());
+
+            while let Some(node) = cur_node {
+                let mut node_ref = node.borrow_mut();
+                let subtree = unsafe { node_ref.unwrap_as_subtree_mut_unchecked() };
+
+                subtree.values_number = subtree
+                    .children
+                    .iter()
+                    .map(|child| Self::values_number(child))
+                    .sum();
+
+                subtree.resummarize();
+                cur_node = subtree.parent.as_ref().and_then(Weak::upgrade);
+            }
         }
+    }
 
+    impl<T> BTree<T> {
         #[inline]
-        pub fn insert(&mut self, value: T) {
-            match self.root.is_none() {
-                true => {
+        pub const fn new() -> Self {
+            Self::with_mode(BTreeMode::Multiset)
+        }
+
+        #[inline]
+        pub const fn with_mode(mode: BTreeMode) -> Self {
+            Self {
+                root: None,
+                mode,
+                policy: BTreeRebalancePolicy::Borrow,
+            }
+        }
+    }
+
+    impl<T, A: BTreeAugment<T>> BTree<T, A> {
+        // Summaries are picked by type, as in `BTree::<T, A>::augmented()`
+        #[inline]
+        pub const fn augmented() -> Self {
+            Self {
+                root: None,
+                mode: BTreeMode::Multiset,
+                policy: BTreeRebalancePolicy::Borrow,
+            }
+        }
+
+        #[inline]
+        pub fn with_rebalance_policy(mut self, policy: BTreeRebalancePolicy) -> Self {
+            self.policy = policy;
+            self
+        }
+
+        #[inline]
+        pub fn mode(&self) -> BTreeMode {
+            self.mode
+        }
+
+        #[inline]
+        pub fn rebalance_policy(&self) -> BTreeRebalancePolicy {
+            self.policy
+        }
+
+        #[inline]
+        fn empty_like<U: Ord + Eq>(&self) -> BTree<U> {
+            BTree {
+                root: None,
+                mode: self.mode,
+                policy: self.policy,
+            }
+        }
+
+        #[inline]
+        pub fn len(&self) -> usize {
+            self.root
+                .as_ref()
+                .map(|node| BTreeNode::values_number(node))
+                .unwrap_or_default()
+        }
+
+        #[inline]
+        pub fn is_empty(&self) -> bool {
+            self.len() == 0
+        }
+
+        #[inline]
+        pub fn is_not_empty(&self) -> bool {
+            !self.is_empty()
+        }
+
+        // `cmp` orders a stored value against the inserted one, which goes after its equals
+        fn insert_rc_by(&mut self, value: Rc<T>, cmp: impl Fn(&T, &T) -> Ordering) {
+            let root = match &self.root {
+                Some(root) => root.clone(),
+
+                None => {
                     self.root = Some(Rc::new(RefCell::new(BTreeNode::Leaf {
-                        leaf: BTreeLeaf::new(vec![Rc::new(value)], None, None, None),
+                        leaf: BTreeLeaf::new(vec![value], None, None, None),
                     })));
+                    return;
                 }
+            };
 
-                false => match {
-                    let is_leaf = self.root.as_ref().unwrap().borrow().is_leaf();
-                    is_leaf
-                } {
-                    true => self.insert_to_root_leaf(value),
+            let (leaf, leaf_ind, pos) =
+                BTreeNode::insertion_point(&root, &|v| cmp(v, &value) != Ordering::Greater);
 
-                    false => {
-                        let subtree = self.root.as_ref().unwrap().clone();
-                        self.insert_to_subtree(subtree, value);
-                    }
-                },
+            match Rc::ptr_eq(&root, &leaf) {
+                true => self.insert_to_root_leaf_at(pos, value),
+                false => self.insert_to_leaf_at(leaf, leaf_ind, pos, value),
             }
         }
 
-        #[inline]
-        fn insert_to_root_leaf(&mut self, value: T) {
+        fn insert_to_root_leaf_at(&mut self, pos: usize, value: Rc<T>) {
             let (first_leaf, second_leaf, mid_key) = unsafe {
                 let mut leaf = self.root.as_ref().unwrap().borrow_mut();
                 let leaf = leaf.unwrap_as_leaf_mut_unchecked();
 
-                leaf.values.push(Rc::new(value));
-                leaf.values.sort_by(|a, b| a.cmp(&*b));
+                leaf.values.insert(pos, value);
 
                 if leaf.values.len() <= MAX_KEYS {
                     return;
@@ -548,7 +1837,7 @@ mod btree {
 
                 let second_leaf = Rc::new(RefCell::new(BTreeNode::Leaf {
                     leaf: BTreeLeaf::new(
-                        leaf.values[1..].iter().map(|x| x.clone()).collect(),
+                        leaf.values[1..].to_vec(),
                         None,
                         None,
                         Some(Rc::downgrade(&first_leaf)),
@@ -576,55 +1865,32 @@ mod btree {
             ));
         }
 
-        #[inline]
-        fn insert_to_subtree(&mut self, subtree: Rc<RefCell<BTreeNode<T>>>, value: T) {
-            let child_subtree_index = unsafe {
-                subtree
-                    .borrow()
-                    .unwrap_as_subtree_unchecked()
-                    .get_children_index_by_value(&value)
-            };
-
-            self.insert_to_children_subtree(subtree, child_subtree_index, value)
-        }
-
-        #[inline]
-        fn insert_to_children_subtree(
+        fn insert_to_leaf_at(
             &mut self,
-            subtree: Rc<RefCell<BTreeNode<T>>>,
-            child_subtree_index: usize,
-            value: T,
+            leaf: Rc<RefCell<BTreeNode<T, A>>>,
+            leaf_ind: usize,
+            pos: usize,
+            value: Rc<T>,
         ) {
-            let node = unsafe {
-                let subtree_ref = subtree.borrow();
-                let subtree_ref = subtree_ref.unwrap_as_subtree_unchecked();
-                subtree_ref.children[child_subtree_index].clone()
-            };
+            let is_overflow = unsafe {
+                let mut leaf_ref = leaf.borrow_mut();
+                let leaf_ref = leaf_ref.unwrap_as_leaf_mut_unchecked();
 
-            match {
-                let is_leaf = node.borrow().is_leaf();
-                is_leaf
-            } {
-                true => self.insert_to_leaf(node, child_subtree_index, value),
-                false => self.insert_to_subtree(node, value),
+                leaf_ref.values.insert(pos, value);
+                leaf_ref.values.len() > MAX_KEYS
             };
-        }
 
-        #[inline]
-        fn insert_to_leaf(&mut self, leaf: Rc<RefCell<BTreeNode<T>>>, leaf_ind: usize, value: T) {
+            // Ancestors refold their children, so the leaf must not be borrowed anymore
+            if !is_overflow {
+                let parent_tree = leaf.borrow().get_parent().and_then(Weak::upgrade).unwrap();
+                BTreeNode::update_parent_value_number(parent_tree);
+                return;
+            }
+
             let (parent_tree, first_leaf, second_leaf, mid_key) = unsafe {
                 let mut leaf_ref = leaf.borrow_mut();
                 let leaf_ref = leaf_ref.unwrap_as_leaf_mut_unchecked();
 
-                leaf_ref.values.push(Rc::new(value));
-                leaf_ref.values.sort();
-
-                if leaf_ref.values.len() <= MAX_KEYS {
-                    let parent_tree = leaf_ref.parent.as_ref().unwrap().upgrade().unwrap().clone();
-                    BTreeNode::update_parent_value_number(parent_tree);
-                    return;
-                }
-
                 let first_leaf = Rc::new(RefCell::new(BTreeNode::Leaf {
                     leaf: BTreeLeaf::new(
                         vec![leaf_ref.values[0].clone()],
@@ -645,7 +1911,7 @@ mod btree {
 
                 let second_leaf = Rc::new(RefCell::new(BTreeNode::Leaf {
                     leaf: BTreeLeaf::new(
-                        leaf_ref.values[1..].iter().map(|x| x.clone()).collect(),
+                        leaf_ref.values[1..].to_vec(),
                         leaf_ref.parent.clone(),
                         leaf_ref.next_leaf.clone(),
                         Some(Rc::downgrade(&first_leaf)),
@@ -679,59 +1945,170 @@ mod btree {
                 parent_subtree.children.insert(leaf_ind + 1, second_leaf);
             }
 
-            self.insert_mid_key_to_parent_subtree(parent_tree, mid_key)
+            self.insert_mid_key_to_parent_subtree(parent_tree, leaf_ind, mid_key)
         }
 
-        fn insert_mid_key_to_parent_subtree(
-            &mut self,
-            subtree: Rc<RefCell<BTreeNode<T>>>,
-            mid_key: Rc<T>,
-        ) {
-            unsafe {
-                let mut tree = subtree.borrow_mut();
-                let tree = tree.unwrap_as_subtree_mut_unchecked();
+        fn remove_at_position(&mut self, leaf: Rc<RefCell<BTreeNode<T, A>>>, ind: usize) -> Rc<T> {
+            // A value shared with a separator is the first one of a right subtree,
+            // so its successor takes over the separator once it is removed
+            let is_shared = Rc::strong_count(&leaf.borrow().get_values()[ind]) > 1;
+            let rank = is_shared.then(|| BTreeNode::position_rank(leaf.clone(), ind));
 
-                tree.mid_keys.push(mid_key);
-                tree.mid_keys.sort_by(|a, b| a.cmp(&*b));
+            let (removed, parent, is_empty) = unsafe {
+                let mut leaf_ref = leaf.borrow_mut();
+                let leaf_ref = leaf_ref.unwrap_as_leaf_mut_unchecked();
+                let removed = leaf_ref.values.remove(ind);
 
-                if tree.mid_keys.len() <= MAX_KEYS {
-                    return;
+                (
+                    removed,
+                    leaf_ref.parent.as_ref().and_then(Weak::upgrade),
+                    leaf_ref.values.is_empty(),
+                )
+            };
+
+            match parent {
+                None => {
+                    if is_empty {
+                        self.root = None;
+                    }
                 }
-            }
 
-            match unsafe {
-                let is_parent_none = subtree
-                    .as_ref()
-                    .borrow()
-                    .unwrap_as_subtree_unchecked()
-                    .parent
-                    .is_none();
+                Some(parent) => {
+                    BTreeNode::decrease_parent_value_number(parent.clone());
 
-                is_parent_none
-            } {
-                true => self.rebalance_root_after_mid_key_insertion(),
+                    if is_empty {
+                        self.rebalance_empty_leaf(parent, leaf);
+                    } else if self.policy == BTreeRebalancePolicy::Merge {
+                        self.merge_single_value_leaf(parent, leaf);
+                    }
+                }
+            }
 
-                false => {
-                    let (first_subtree, second_subtree, mid_key) = unsafe {
-                        let mut tree = subtree.borrow_mut();
-                        let tree = tree.unwrap_as_subtree_mut_unchecked();
+            if let Some(rank) = rank.filter(|&rank| rank < self.len()) {
+                let (successor_leaf, successor_ind) =
+                    BTreeNode::position_at(self.root.as_ref().unwrap(), rank);
 
-                        let first_subtree = Rc::new(RefCell::new(BTreeNode::SubTree {
-                            subtree: BTreeSubTree::new(
-                                tree.children[..2].iter().map(|node| node.clone()).collect(),
-                                Some(tree.parent.as_ref().unwrap().clone()),
-                                vec![tree.mid_keys[0].clone()],
-                            ),
-                        }));
+                if successor_ind == 0 && Rc::strong_count(&removed) > 1 {
+                    BTreeNode::refresh_separator(&successor_leaf, |key| Rc::ptr_eq(key, &removed));
+                }
+            }
 
-                        tree.children[..2].iter_mut().for_each(|node| {
+            removed
+        }
+
+        #[inline]
+        pub fn first(&self) -> Option<Rc<T>> {
+            self.root
+                .as_ref()
+                .and_then(|root_node| BTreeNode::first(root_node))
+        }
+
+        #[inline]
+        pub fn last(&self) -> Option<Rc<T>> {
+            self.root
+                .as_ref()
+                .and_then(|root_node| BTreeNode::last(root_node))
+        }
+
+        #[inline]
+        pub fn iter(&self) -> BTreeIter<T, A> {
+            self.root
+                .as_ref()
+                .map(BTreeNode::first_leaf)
+                .map(|first_leaf| BTreeIter::new(Some(first_leaf), 0))
+                .unwrap_or_default()
+        }
+
+        #[inline]
+        unsafe fn stored_ref(&self, value: &Rc<T>) -> &T {
+            // Stored values are only ever dropped through `&mut self`,
+            // so they outlive the shared borrow of the tree
+            &*Rc::as_ptr(value)
+        }
+
+        fn last_position(&self) -> Option<BTreePosition<T, A>> {
+            let last_leaf = BTreeNode::last_leaf(self.root.as_ref()?);
+            let len = unsafe { last_leaf.borrow().unwrap_as_leaf_unchecked().values.len() };
+            Some((last_leaf, len - 1))
+        }
+
+        #[inline]
+        fn new_root_after_division(
+            first_node: Rc<RefCell<BTreeNode<T, A>>>,
+            second_node: Rc<RefCell<BTreeNode<T, A>>>,
+            mid_key: Rc<T>,
+        ) -> Rc<RefCell<BTreeNode<T, A>>> {
+            let new_root = Rc::new(RefCell::new(BTreeNode::SubTree {
+                subtree: BTreeSubTree::new(
+                    vec![first_node.clone(), second_node.clone()],
+                    None,
+                    vec![mid_key],
+                ),
+            }));
+
+            first_node
+                .borrow_mut()
+                .set_parent(Some(Rc::downgrade(&new_root)));
+
+            second_node
+                .borrow_mut()
+                .set_parent(Some(Rc::downgrade(&new_root)));
+
+            new_root
+        }
+
+        // Separators are placed by the position of the split child rather than sorted,
+        // so among equal keys each one stays in front of the child it was taken from
+        fn insert_mid_key_to_parent_subtree(
+            &mut self,
+            subtree: Rc<RefCell<BTreeNode<T, A>>>,
+            key_ind: usize,
+            mid_key: Rc<T>,
+        ) {
+            unsafe {
+                let mut tree = subtree.borrow_mut();
+                let tree = tree.unwrap_as_subtree_mut_unchecked();
+
+                tree.mid_keys.insert(key_ind, mid_key);
+
+                if tree.mid_keys.len() <= MAX_KEYS {
+                    return;
+                }
+            }
+
+            match unsafe {
+                let is_parent_none = subtree
+                    .as_ref()
+                    .borrow()
+                    .unwrap_as_subtree_unchecked()
+                    .parent
+                    .is_none();
+
+                is_parent_none
+            } {
+                true => self.rebalance_root_after_mid_key_insertion(),
+
+                false => {
+                    let (first_subtree, second_subtree, mid_key) = unsafe {
+                        let mut tree = subtree.borrow_mut();
+                        let tree = tree.unwrap_as_subtree_mut_unchecked();
+
+                        let first_subtree = Rc::new(RefCell::new(BTreeNode::SubTree {
+                            subtree: BTreeSubTree::new(
+                                tree.children[..2].to_vec(),
+                                Some(tree.parent.as_ref().unwrap().clone()),
+                                vec![tree.mid_keys[0].clone()],
+                            ),
+                        }));
+
+                        tree.children[..2].iter_mut().for_each(|node| {
                             node.borrow_mut()
                                 .set_parent(Some(Rc::downgrade(&first_subtree)))
                         });
 
                         let second_subtree = Rc::new(RefCell::new(BTreeNode::SubTree {
                             subtree: BTreeSubTree::new(
-                                tree.children[2..].iter().map(|x| x.clone()).collect(),
+                                tree.children[2..].to_vec(),
                                 Some(tree.parent.as_ref().unwrap().clone()),
                                 vec![tree.mid_keys[2].clone()],
                             ),
@@ -755,7 +2132,7 @@ mod btree {
                             .map(|node| node.upgrade().unwrap())
                             .unwrap();
 
-                        {
+                        let subtree_index = {
                             let mut parent_tree_ref = parent_tree.borrow_mut();
                             let parent_tree_ref = parent_tree_ref.unwrap_as_subtree_mut_unchecked();
 
@@ -774,11 +2151,122 @@ mod btree {
                             parent_tree_ref
                                 .children
                                 .insert(subtree_index + 1, second_subtree);
-                        }
 
-                        self.insert_mid_key_to_parent_subtree(parent_tree.clone(), mid_key);
+                            subtree_index
+                        };
+
+                        self.insert_mid_key_to_parent_subtree(
+                            parent_tree.clone(),
+                            subtree_index,
+                            mid_key,
+                        );
+                    }
+                }
+            }
+        }
+
+        fn rebalance_empty_leaf(
+            &mut self,
+            parent: Rc<RefCell<BTreeNode<T, A>>>,
+            leaf: Rc<RefCell<BTreeNode<T, A>>>,
+        ) {
+            let is_parent_underflow = unsafe {
+                let mut parent_ref = parent.borrow_mut();
+                let parent_tree = parent_ref.unwrap_as_subtree_mut_unchecked();
+
+                let leaf_ind = parent_tree
+                    .children
+                    .iter()
+                    .position(|node| Rc::ptr_eq(node, &leaf))
+                    .unwrap();
+
+                let left_sibling = leaf_ind
+                    .checked_sub(1)
+                    .map(|ind| parent_tree.children[ind].clone())
+                    .filter(|sibling| BTreeNode::values_number(sibling) == MAX_KEYS);
+
+                if let Some(left_sibling) = left_sibling {
+                    let moved = left_sibling
+                        .borrow_mut()
+                        .unwrap_as_leaf_mut_unchecked()
+                        .values
+                        .pop()
+                        .unwrap();
+
+                    parent_tree.mid_keys[leaf_ind - 1] = moved.clone();
+                    leaf.borrow_mut().get_values_mut().push(moved);
+                    return;
+                }
+
+                let right_sibling = parent_tree
+                    .children
+                    .get(leaf_ind + 1)
+                    .cloned()
+                    .filter(|sibling| BTreeNode::values_number(sibling) == MAX_KEYS);
+
+                if let Some(right_sibling) = right_sibling {
+                    let mut right_ref = right_sibling.borrow_mut();
+                    let right_leaf = right_ref.unwrap_as_leaf_mut_unchecked();
+                    let moved = right_leaf.values.remove(0);
+
+                    parent_tree.mid_keys[leaf_ind] = right_leaf.values[0].clone();
+                    leaf.borrow_mut().get_values_mut().push(moved);
+                    return;
+                }
+
+                Self::unlink_empty_leaf(parent_tree, &leaf, leaf_ind)
+            };
+
+            if is_parent_underflow {
+                self.rebalance_underflow_subtree(parent)
+            }
+        }
+
+        fn merge_single_value_leaf(
+            &mut self,
+            parent: Rc<RefCell<BTreeNode<T, A>>>,
+            leaf: Rc<RefCell<BTreeNode<T, A>>>,
+        ) {
+            let is_parent_underflow = unsafe {
+                let mut parent_ref = parent.borrow_mut();
+                let parent_tree = parent_ref.unwrap_as_subtree_mut_unchecked();
+
+                let leaf_ind = parent_tree
+                    .children
+                    .iter()
+                    .position(|node| Rc::ptr_eq(node, &leaf))
+                    .unwrap();
+
+                let sibling = [leaf_ind.checked_sub(1), Some(leaf_ind + 1)]
+                    .into_iter()
+                    .flatten()
+                    .filter_map(|ind| Some((ind, parent_tree.children.get(ind)?.clone())))
+                    .find(|(_, sibling)| BTreeNode::values_number(sibling) == 1);
+
+                let (sibling_ind, sibling) = match sibling {
+                    Some(sibling) => sibling,
+                    None => return,
+                };
+
+                let moved = leaf.borrow_mut().get_values_mut().pop().unwrap();
+                let mut sibling_ref = sibling.borrow_mut();
+                let sibling_values = sibling_ref.get_values_mut();
+
+                match sibling_ind < leaf_ind {
+                    true => sibling_values.push(moved),
+
+                    false => {
+                        parent_tree.mid_keys[leaf_ind] = moved.clone();
+                        sibling_values.insert(0, moved);
                     }
                 }
+
+                drop(sibling_ref);
+                Self::unlink_empty_leaf(parent_tree, &leaf, leaf_ind)
+            };
+
+            if is_parent_underflow {
+                self.rebalance_underflow_subtree(parent)
             }
         }
 
@@ -790,10 +2278,7 @@ mod btree {
 
                 let first_subtree = Rc::new(RefCell::new(BTreeNode::SubTree {
                     subtree: BTreeSubTree::new(
-                        root_tree.children[..2]
-                            .iter()
-                            .map(|node| node.clone())
-                            .collect(),
+                        root_tree.children[..2].to_vec(),
                         None,
                         vec![root_tree.mid_keys[0].clone()],
                     ),
@@ -806,7 +2291,7 @@ mod btree {
 
                 let second_subtree = Rc::new(RefCell::new(BTreeNode::SubTree {
                     subtree: BTreeSubTree::new(
-                        root_tree.children[2..].iter().map(|x| x.clone()).collect(),
+                        root_tree.children[2..].to_vec(),
                         None,
                         vec![root_tree.mid_keys[2].clone()],
                     ),
@@ -831,124 +2316,8248 @@ mod btree {
             ))
         }
 
-        #[inline]
-        pub fn first(&self) -> Option<Rc<T>> {
-            self.root
-                .as_ref()
-                .map(|root_node| BTreeNode::first(root_node.clone()))
-                .flatten()
-        }
+        // Returns whether the parent is left with a single child
+        fn unlink_empty_leaf(
+            parent_tree: &mut BTreeSubTree<T, A>,
+            leaf: &Rc<RefCell<BTreeNode<T, A>>>,
+            leaf_ind: usize,
+        ) -> bool {
+            unsafe {
+                let mut leaf_ref = leaf.borrow_mut();
+                let leaf_ref = leaf_ref.unwrap_as_leaf_mut_unchecked();
+                let prev_leaf = leaf_ref
+                    .previous_leaf
+                    .take()
+                    .and_then(|leaf| leaf.upgrade());
+                let next_leaf = leaf_ref.next_leaf.take();
 
-        #[inline]
-        pub fn last(&self) -> Option<Rc<T>> {
-            self.root
-                .as_ref()
-                .map(|root_node| BTreeNode::last(root_node.clone()))
-                .flatten()
-        }
+                if let Some(prev_leaf) = &prev_leaf {
+                    prev_leaf
+                        .borrow_mut()
+                        .unwrap_as_leaf_mut_unchecked()
+                        .next_leaf = next_leaf.clone();
+                }
 
-        #[inline]
-        pub fn iter(&self) -> BTreeIter<T> {
-            self.root
-                .as_ref()
-                .map(|root_node| BTreeNode::first_leaf(root_node.clone()))
-                .map(|first_leaf| BTreeIter::new(Some(first_leaf), 0))
-                .unwrap_or_default()
+                if let Some(next_leaf) = &next_leaf {
+                    next_leaf
+                        .borrow_mut()
+                        .unwrap_as_leaf_mut_unchecked()
+                        .previous_leaf = prev_leaf.as_ref().map(Rc::downgrade);
+                }
+            }
+
+            parent_tree.children.remove(leaf_ind);
+            parent_tree.mid_keys.remove(leaf_ind.saturating_sub(1));
+            parent_tree.children.len() < 2
         }
 
-        #[inline]
-        pub unsafe fn get_unchecked(&self, index: usize) -> Rc<T> {
-            BTreeNode::get(self.root.as_ref().unwrap().clone(), index)
+        fn rebalance_underflow_subtree(&mut self, subtree: Rc<RefCell<BTreeNode<T, A>>>) {
+            let parent = unsafe {
+                subtree
+                    .borrow()
+                    .unwrap_as_subtree_unchecked()
+                    .parent
+                    .as_ref()
+                    .and_then(Weak::upgrade)
+            };
+
+            let parent = match parent {
+                Some(parent) => parent,
+
+                None => {
+                    let child = unsafe {
+                        subtree
+                            .borrow_mut()
+                            .unwrap_as_subtree_mut_unchecked()
+                            .children
+                            .pop()
+                            .unwrap()
+                    };
+
+                    child.borrow_mut().set_parent(None);
+                    self.root = Some(child);
+                    return;
+                }
+            };
+
+            let is_parent_underflow = unsafe {
+                let mut parent_ref = parent.borrow_mut();
+                let parent_tree = parent_ref.unwrap_as_subtree_mut_unchecked();
+
+                let subtree_ind = parent_tree
+                    .children
+                    .iter()
+                    .position(|node| Rc::ptr_eq(node, &subtree))
+                    .unwrap();
+
+                let sibling_ind = match subtree_ind {
+                    0 => 1,
+                    _ => subtree_ind - 1,
+                };
+
+                let sibling = parent_tree.children[sibling_ind].clone();
+                let mut sibling_ref = sibling.borrow_mut();
+                let sibling_tree = sibling_ref.unwrap_as_subtree_mut_unchecked();
+                let mut subtree_ref = subtree.borrow_mut();
+                let subtree_tree = subtree_ref.unwrap_as_subtree_mut_unchecked();
+
+                let is_parent_underflow = match (
+                    sibling_tree.children.len() == MAX_CHILDREN,
+                    sibling_ind < subtree_ind,
+                ) {
+                    (true, true) => {
+                        let moved = sibling_tree.children.pop().unwrap();
+                        let moved_number = BTreeNode::values_number(&moved);
+
+                        subtree_tree.mid_keys.insert(
+                            0,
+                            std::mem::replace(
+                                &mut parent_tree.mid_keys[sibling_ind],
+                                sibling_tree.mid_keys.pop().unwrap(),
+                            ),
+                        );
+
+                        moved.borrow_mut().set_parent(Some(Rc::downgrade(&subtree)));
+
+                        subtree_tree.children.insert(0, moved);
+                        sibling_tree.values_number -= moved_number;
+                        subtree_tree.values_number += moved_number;
+                        false
+                    }
+
+                    (true, false) => {
+                        let moved = sibling_tree.children.remove(0);
+                        let moved_number = BTreeNode::values_number(&moved);
+
+                        subtree_tree.mid_keys.push(std::mem::replace(
+                            &mut parent_tree.mid_keys[subtree_ind],
+                            sibling_tree.mid_keys.remove(0),
+                        ));
+
+                        moved.borrow_mut().set_parent(Some(Rc::downgrade(&subtree)));
+
+                        subtree_tree.children.push(moved);
+                        sibling_tree.values_number -= moved_number;
+                        subtree_tree.values_number += moved_number;
+                        false
+                    }
+
+                    (false, is_left_sibling) => {
+                        let child = subtree_tree.children.pop().unwrap();
+                        sibling_tree.values_number += BTreeNode::values_number(&child);
+
+                        child.borrow_mut().set_parent(Some(Rc::downgrade(&sibling)));
+
+                        match is_left_sibling {
+                            true => {
+                                sibling_tree
+                                    .mid_keys
+                                    .push(parent_tree.mid_keys.remove(sibling_ind));
+
+                                sibling_tree.children.push(child);
+                            }
+
+                            false => {
+                                sibling_tree
+                                    .mid_keys
+                                    .insert(0, parent_tree.mid_keys.remove(subtree_ind));
+
+                                sibling_tree.children.insert(0, child);
+                            }
+                        }
+
+                        parent_tree.children.remove(subtree_ind);
+                        parent_tree.children.len() < 2
+                    }
+                };
+
+                sibling_tree.resummarize();
+                subtree_tree.resummarize();
+                is_parent_underflow
+            };
+
+            if is_parent_underflow {
+                self.rebalance_underflow_subtree(parent)
+            }
         }
+    }
 
+    impl<T: Ord + Eq, A: BTreeAugment<T>> BTree<T, A> {
         #[inline]
-        pub fn get(&self, index: usize) -> Option<Rc<T>> {
-            if index >= self.len() {
-                None
-            } else {
-                unsafe { Some(self.get_unchecked(index)) }
+        pub fn insert(&mut self, value: T) -> bool {
+            if self.mode == BTreeMode::Set && self.contains(&value) {
+                return false;
             }
+
+            self.insert_value(value);
+            true
         }
 
-        #[inline]
-        pub fn find(&self, value: &T) -> BTreeIter<T> {
-            self.root
-                .as_ref()
-                .map(|node| BTreeNode::find(node.clone(), value))
-                .map(|leaf| {
-                    let cur_ind = unsafe {
-                        leaf.borrow()
-                            .unwrap_as_leaf_unchecked()
-                            .values
-                            .iter()
-                            .position(|v| **v >= *value)
-                    };
+        #[allow(clippy::blocks_in_conditions)]
+        fn insert_value(&mut self, value: T) {
+            match self.root.is_none() {
+                true => {
+                    self.root = Some(Rc::new(RefCell::new(BTreeNode::Leaf {
+                        leaf: BTreeLeaf::new(vec![Rc::new(value)], None, None, None),
+                    })));
+                }
 
-                    (leaf, cur_ind)
-                })
-                .map(|(leaf, cur_ind)| cur_ind.map(|cur_ind| BTreeIter::new(Some(leaf), cur_ind)))
-                .flatten()
-                .unwrap_or_default()
+                false => match {
+                    let is_leaf = self.root.as_ref().unwrap().borrow().is_leaf();
+                    is_leaf
+                } {
+                    true => self.insert_to_root_leaf(value),
+
+                    false => {
+                        let subtree = self.root.as_ref().unwrap().clone();
+                        self.insert_to_subtree(subtree, value);
+                    }
+                },
+            }
         }
-    }
 
-    impl<T: Ord + Eq + Clone> Extend<T> for BTree<T> {
         #[inline]
-        fn extend<I: IntoIterator<Item = T>>(&mut self, iter: I) {
-            iter.into_iter().for_each(|x| self.insert(x));
+        fn insert_rc(&mut self, value: Rc<T>) {
+            self.insert_rc_by(value, T::cmp)
         }
-    }
 
-    impl<T: Ord + Eq + Clone> FromIterator<T> for BTree<T> {
         #[inline]
-        fn from_iter<I: IntoIterator<Item = T>>(iter: I) -> Self {
-            let mut tree = BTree::new();
-            tree.extend(iter.into_iter());
-            tree
-        }
-    }
+        fn insert_to_root_leaf(&mut self, value: T) {
+            let pos = unsafe {
+                self.root
+                    .as_ref()
+                    .unwrap()
+                    .borrow()
+                    .unwrap_as_leaf_unchecked()
+                    .values
+                    .partition_point(|v| **v <= value)
+            };
 
-    impl<T: Ord + Eq + Clone> IntoIterator for BTree<T> {
-        type Item = Rc<T>;
-        type IntoIter = BTreeIter<T>;
+            self.insert_to_root_leaf_at(pos, Rc::new(value))
+        }
 
         #[inline]
-        fn into_iter(self) -> Self::IntoIter {
-            self.root
-                .map(|root_node| BTreeNode::first_leaf(root_node))
-                .map(|first_leaf| BTreeIter::new(Some(first_leaf), 0))
-                .unwrap_or_default()
+        fn insert_to_subtree(&mut self, subtree: Rc<RefCell<BTreeNode<T, A>>>, value: T) {
+            let child_subtree_index = unsafe {
+                subtree
+                    .borrow()
+                    .unwrap_as_subtree_unchecked()
+                    .get_children_index_by_value(&value)
+            };
+
+            self.insert_to_children_subtree(subtree, child_subtree_index, value)
         }
-    }
 
-    #[test]
+        pub fn remove<Q: Ord + ?Sized>(&mut self, value: &Q) -> Option<Rc<T>>
+        where
+            T: std::borrow::Borrow<Q>,
+        {
+            let (leaf, ind) = self.root.as_ref().and_then(|root| {
+                BTreeNode::partition_point(root, &|v| Self::borrow_value(v) < value)
+            })?;
+
+            match Self::borrow_value(&BTreeNode::value_at(&leaf, ind)) == value {
+                true => Some(self.remove_at_position(leaf, ind)),
+                false => None,
+            }
+        }
+
+        fn from_sorted_rcs<I: IntoIterator<Item = Rc<T>>>(values: I) -> Self {
+            let mut values = values.into_iter();
+            let mut level = vec![];
+            let mut prev_leaf: Option<Rc<RefCell<BTreeNode<T, A>>>> = None;
+
+            loop {
+                let leaf_values = values.by_ref().take(MAX_KEYS).collect::<Vec<_>>();
+
+                if leaf_values.is_empty() {
+                    break;
+                }
+
+                let first_value = leaf_values[0].clone();
+
+                let leaf = Rc::new(RefCell::new(BTreeNode::Leaf {
+                    leaf: BTreeLeaf::new(
+                        leaf_values,
+                        None,
+                        None,
+                        prev_leaf.as_ref().map(Rc::downgrade),
+                    ),
+                }));
+
+                if let Some(prev_leaf) = &prev_leaf {
+                    unsafe {
+                        prev_leaf
+                            .borrow_mut()
+                            .unwrap_as_leaf_mut_unchecked()
+                            .next_leaf = Some(leaf.clone());
+                    }
+                }
+
+                prev_leaf = Some(leaf.clone());
+                level.push((leaf, first_value));
+            }
+
+            while level.len() > 1 {
+                level = Self::build_level(level);
+            }
+
+            Self {
+                root: level.pop().map(|(node, _)| node),
+                ..Self::augmented()
+            }
+        }
+
+        pub fn contains<Q: Ord + ?Sized>(&self, value: &Q) -> bool
+        where
+            T: std::borrow::Borrow<Q>,
+        {
+            self.root
+                .as_ref()
+                .and_then(|root| {
+                    BTreeNode::partition_point(root, &|v| Self::borrow_value(v) < value)
+                })
+                .is_some_and(|(leaf, ind)| unsafe {
+                    Self::borrow_value(&leaf.borrow().unwrap_as_leaf_unchecked().values[ind])
+                        == value
+                })
+        }
+
+        fn range_ranks<R: RangeBounds<T>>(&self, range: &R) -> (usize, usize) {
+            let root = match self.root.as_ref() {
+                Some(root) => root,
+                None => return (0, 0),
+            };
+
+            let start = match range.start_bound() {
+                Bound::Unbounded => 0,
+                start => self.position_rank_or_len(Self::range_front(root, start)),
+            };
+
+            let end = match range.end_bound() {
+                Bound::Included(end) => {
+                    self.position_rank_or_len(BTreeNode::partition_point(root, &|v| v <= end))
+                }
+
+                Bound::Excluded(end) => {
+                    self.position_rank_or_len(BTreeNode::partition_point(root, &|v| v < end))
+                }
+
+                Bound::Unbounded => self.len(),
+            };
+
+            (start, end.max(start))
+        }
+
+        #[inline]
+        pub fn fold_range<R: RangeBounds<T>>(&self, range: R) -> Option<A::Summary> {
+            let (start, end) = self.range_ranks(&range);
+            BTreeNode::fold_ranks(self.root.as_ref()?, start, end)
+        }
+
+        #[inline]
+        #[allow(clippy::blocks_in_conditions)]
+        fn insert_to_children_subtree(
+            &mut self,
+            subtree: Rc<RefCell<BTreeNode<T, A>>>,
+            child_subtree_index: usize,
+            value: T,
+        ) {
+            let node = unsafe {
+                let subtree_ref = subtree.borrow();
+                let subtree_ref = subtree_ref.unwrap_as_subtree_unchecked();
+                subtree_ref.children[child_subtree_index].clone()
+            };
+
+            match {
+                let is_leaf = node.borrow().is_leaf();
+                is_leaf
+            } {
+                true => self.insert_to_leaf(node, child_subtree_index, value),
+                false => self.insert_to_subtree(node, value),
+            };
+        }
+
+        #[inline]
+        fn range_front(root: &BTreeNodeRef<T, A>, start: Bound<&T>) -> Option<BTreePosition<T, A>> {
+            match start {
+                Bound::Included(start) => BTreeNode::partition_point(root, &|v| v < start),
+                Bound::Excluded(start) => BTreeNode::partition_point(root, &|v| v <= start),
+                Bound::Unbounded => BTreeNode::partition_point(root, &|_| false),
+            }
+        }
+
+        fn build_level(
+            nodes: Vec<(BTreeNodeRef<T, A>, Rc<T>)>,
+        ) -> Vec<(BTreeNodeRef<T, A>, Rc<T>)> {
+            let mut remaining = nodes.len();
+            let mut nodes = nodes.into_iter();
+            let mut parents = Vec::with_capacity(remaining / 2);
+
+            while remaining > 0 {
+                let group_len = match remaining {
+                    2 | 4 => 2,
+                    _ => MAX_CHILDREN,
+                };
+
+                let group = nodes.by_ref().take(group_len).collect::<Vec<_>>();
+                remaining -= group_len;
+
+                let first_value = group[0].1.clone();
+                let mid_keys = group[1..].iter().map(|(_, first)| first.clone()).collect();
+                let children = group.into_iter().map(|(node, _)| node).collect::<Vec<_>>();
+
+                let parent = Rc::new(RefCell::new(BTreeNode::SubTree {
+                    subtree: BTreeSubTree::new(children.clone(), None, mid_keys),
+                }));
+
+                children
+                    .iter()
+                    .for_each(|child| child.borrow_mut().set_parent(Some(Rc::downgrade(&parent))));
+
+                parents.push((parent, first_value));
+            }
+
+            parents
+        }
+
+        #[inline]
+        fn borrow_value<Q: ?Sized>(value: &T) -> &Q
+        where
+            T: std::borrow::Borrow<Q>,
+        {
+            value.borrow()
+        }
+
+        fn position_rank_or_len(&self, position: Option<BTreePosition<T, A>>) -> usize {
+            position
+                .map(|(leaf, ind)| BTreeNode::position_rank(leaf, ind))
+                .unwrap_or_else(|| self.len())
+        }
+
+        #[inline]
+        fn insert_to_leaf(
+            &mut self,
+            leaf: Rc<RefCell<BTreeNode<T, A>>>,
+            leaf_ind: usize,
+            value: T,
+        ) {
+            // Upper bound: equal values keep their insertion order
+            let pos = unsafe {
+                leaf.borrow()
+                    .unwrap_as_leaf_unchecked()
+                    .values
+                    .partition_point(|v| **v <= value)
+            };
+
+            self.insert_to_leaf_at(leaf, leaf_ind, pos, Rc::new(value))
+        }
+    }
+
+    impl<T: Ord + Eq> BTree<T> {
+        pub fn try_insert(&mut self, value: T) -> Result<(), OccupiedError<T>> {
+            match self.get_stored(&value) {
+                Some(existing) => Err(OccupiedError { existing, value }),
+                None => {
+                    self.insert_value(value);
+                    Ok(())
+                }
+            }
+        }
+
+        #[inline]
+        pub fn get_or_insert_with<Q: Ord + ?Sized>(
+            &mut self,
+            value: &Q,
+            f: impl FnOnce(&Q) -> T,
+        ) -> Rc<T>
+        where
+            T: std::borrow::Borrow<Q>,
+        {
+            self.get_or_insert_by(|v| Self::borrow_value(v).cmp(value), || f(value))
+        }
+
+        // `cmp` orders a stored value against the query
+        fn get_or_insert_by(
+            &mut self,
+            cmp: impl Fn(&T) -> Ordering,
+            make: impl FnOnce() -> T,
+        ) -> Rc<T> {
+            let root = match &self.root {
+                Some(root) => root.clone(),
+
+                None => {
+                    self.insert_value(make());
+                    return self.first().unwrap();
+                }
+            };
+
+            let (leaf, leaf_ind, pos) =
+                BTreeNode::insertion_point(&root, &|v| cmp(v) != Ordering::Greater);
+
+            if let Some(stored) =
+                BTreeNode::value_before(&leaf, pos).filter(|stored| cmp(stored) == Ordering::Equal)
+            {
+                return stored;
+            }
+
+            let inserted = Rc::new(make());
+
+            assert!(
+                cmp(&inserted) == Ordering::Equal,
+                "new value is not equal to the queried one"
+            );
+
+            match Rc::ptr_eq(&root, &leaf) {
+                true => self.insert_to_root_leaf_at(pos, inserted.clone()),
+                false => self.insert_to_leaf_at(leaf, leaf_ind, pos, inserted.clone()),
+            }
+
+            inserted
+        }
+
+        pub fn replace(&mut self, value: T) -> Option<Rc<T>> {
+            let position = self
+                .root
+                .as_ref()
+                .and_then(|root| BTreeNode::partition_point(root, &|v| *v < value));
+
+            if let Some((leaf, ind)) = position {
+                let mut leaf_ref = leaf.borrow_mut();
+                let stored = unsafe { &mut leaf_ref.unwrap_as_leaf_mut_unchecked().values[ind] };
+
+                if **stored == value {
+                    let replaced = std::mem::replace(stored, Rc::new(value));
+                    drop(leaf_ref);
+                    BTreeNode::resummarize_ancestors(&leaf);
+
+                    if ind == 0 && Rc::strong_count(&replaced) > 1 {
+                        BTreeNode::refresh_separator(&leaf, |key| Rc::ptr_eq(key, &replaced));
+                    }
+
+                    return Some(replaced);
+                }
+            }
+
+            self.insert_value(value);
+            None
+        }
+
+        pub fn remove_at(&mut self, index: usize) -> Option<Rc<T>> {
+            if index >= self.len() {
+                return None;
+            }
+
+            let (leaf, ind) = BTreeNode::position_at(self.root.as_ref().unwrap(), index);
+            Some(self.remove_at_position(leaf, ind))
+        }
+
+        #[inline]
+        pub fn pop_first(&mut self) -> Option<Rc<T>> {
+            let first_leaf = self.root.as_ref().map(BTreeNode::first_leaf)?;
+            Some(self.remove_at_position(first_leaf, 0))
+        }
+
+        #[inline]
+        pub fn pop_last(&mut self) -> Option<Rc<T>> {
+            let (last_leaf, ind) = self.root.as_ref().map(BTreeNode::last_position)?;
+            Some(self.remove_at_position(last_leaf, ind))
+        }
+
+        // Comparison fast path for append-ordered input: the rightmost spine is still
+        // walked, but only the current maximum is compared against the value.
+        // A cached last leaf would not help, since the insertion itself updates
+        // the value counters of every ancestor anyway
+        pub fn push_max(&mut self, value: T) -> bool {
+            let root = match &self.root {
+                Some(root) => root.clone(),
+                None => return self.insert(value),
+            };
+
+            let last_leaf = BTreeNode::last_leaf(&root);
+
+            let (len, last_order) = unsafe {
+                let leaf_ref = last_leaf.borrow();
+                let values = &leaf_ref.unwrap_as_leaf_unchecked().values;
+                (values.len(), (**values.last().unwrap()).cmp(&value))
+            };
+
+            match last_order {
+                Ordering::Greater => return self.insert(value),
+                Ordering::Equal if self.mode == BTreeMode::Set => return false,
+                _ => {}
+            }
+
+            match Rc::ptr_eq(&root, &last_leaf) {
+                true => self.insert_to_root_leaf_at(len, Rc::new(value)),
+
+                false => {
+                    let leaf_ind = unsafe {
+                        last_leaf
+                            .borrow()
+                            .get_parent()
+                            .and_then(Weak::upgrade)
+                            .unwrap()
+                            .borrow()
+                            .unwrap_as_subtree_unchecked()
+                            .children
+                            .len()
+                            - 1
+                    };
+
+                    self.insert_to_leaf_at(last_leaf, leaf_ind, len, Rc::new(value))
+                }
+            }
+
+            true
+        }
+
+        #[inline]
+        fn sorted_rcs<I: IntoIterator<Item = T>>(iter: I) -> SortedRcs<I::IntoIter, T> {
+            iter.into_iter().map(Rc::new as fn(T) -> Rc<T>)
+        }
+
+        #[inline]
+        pub fn union(&self, other: &Self) -> BTreeUnion<T> {
+            BTreeUnion::new(self.iter(), other.iter())
+        }
+
+        #[inline]
+        pub fn difference(&self, other: &Self) -> BTreeDifference<T> {
+            BTreeDifference::new(self.iter(), other.iter())
+        }
+
+        #[inline]
+        pub fn symmetric_difference(&self, other: &Self) -> BTreeSymmetricDifference<T> {
+            BTreeSymmetricDifference::new(self.iter(), other.iter())
+        }
+
+        pub fn is_subset(&self, other: &Self) -> bool {
+            let mut other = other.iter().peekable();
+
+            self.iter().all(|value| {
+                skip_less(&mut other, &value);
+                other.peek().is_some_and(|next| **next == *value)
+            })
+        }
+
+        #[inline]
+        pub fn is_superset(&self, other: &Self) -> bool {
+            other.is_subset(self)
+        }
+
+        #[inline]
+        pub fn is_disjoint(&self, other: &Self) -> bool {
+            BTreeIntersection::new(self.iter(), other.iter())
+                .next()
+                .is_none()
+        }
+
+        #[inline]
+        pub fn diff(&self, other: &Self) -> BTreeDiff<T> {
+            BTreeDiff::new(self.iter(), other.iter())
+        }
+
+        #[inline]
+        pub fn union_sorted<I: IntoIterator<Item = T>>(
+            &self,
+            iter: I,
+        ) -> BTreeUnion<T, BTreeIter<T>, SortedRcs<I::IntoIter, T>> {
+            BTreeUnion::new(self.iter(), Self::sorted_rcs(iter))
+        }
+
+        #[inline]
+        pub fn intersect_sorted<I: IntoIterator<Item = T>>(
+            &self,
+            iter: I,
+        ) -> BTreeIntersection<T, BTreeIter<T>, SortedRcs<I::IntoIter, T>> {
+            BTreeIntersection::new(self.iter(), Self::sorted_rcs(iter))
+        }
+
+        #[inline]
+        pub fn difference_sorted<I: IntoIterator<Item = T>>(
+            &self,
+            iter: I,
+        ) -> BTreeDifference<T, BTreeIter<T>, SortedRcs<I::IntoIter, T>> {
+            BTreeDifference::new(self.iter(), Self::sorted_rcs(iter))
+        }
+
+        pub fn apply_op(&mut self, op: &BTreeOp<T>)
+        where
+            T: Clone,
+        {
+            match op {
+                BTreeOp::Insert(value) => {
+                    self.insert(value.clone());
+                }
+
+                BTreeOp::Remove(value) => drop(self.remove(value)),
+
+                BTreeOp::PushMax(value) => {
+                    self.push_max(value.clone());
+                }
+
+                BTreeOp::TakeFirstN(n) => drop(self.take_first_n(*n)),
+                BTreeOp::TakeLastN(n) => drop(self.take_last_n(*n)),
+                BTreeOp::PopFirst => drop(self.pop_first()),
+                BTreeOp::PopLast => drop(self.pop_last()),
+                BTreeOp::RemoveAt(index) => drop(self.remove_at(*index)),
+
+                BTreeOp::RemoveRanks(ranks) => {
+                    let mut ranks = ranks.iter().peekable();
+                    let mut rank = 0;
+
+                    self.retain(|_| {
+                        let is_removed = ranks.next_if(|&&removed| removed == rank).is_some();
+                        rank += 1;
+                        !is_removed
+                    });
+                }
+
+                BTreeOp::RemoveRange(start, end) => {
+                    self.remove_range((start.clone(), end.clone()));
+                }
+
+                BTreeOp::SplitOff(key) => drop(self.split_off(key)),
+                BTreeOp::Append(values) => self.append(&mut values.iter().cloned().collect()),
+                BTreeOp::Truncate(len) => self.truncate(*len),
+                BTreeOp::Dedup => self.dedup(),
+                BTreeOp::Clear => self.clear(),
+            }
+        }
+
+        #[inline]
+        pub fn replay(trace: &BTreeTrace<T>) -> Self
+        where
+            T: Clone,
+        {
+            Self::replay_with(trace, |_, _, _| {})
+        }
+
+        pub fn replay_with(
+            trace: &BTreeTrace<T>,
+            mut on_step: impl FnMut(usize, &BTreeOp<T>, &Self),
+        ) -> Self
+        where
+            T: Clone,
+        {
+            let mut tree = Self::new();
+
+            trace.ops.iter().enumerate().for_each(|(step, op)| {
+                tree.apply_op(op);
+                on_step(step, op, &tree);
+            });
+
+            tree
+        }
+
+        // A value put in front of a leaf takes over the separator before it
+        fn insert_at_rank(&mut self, rank: usize, value: T) {
+            self.place_at_rank(rank, value);
+
+            let (leaf, ind) = BTreeNode::position_at(self.root.as_ref().unwrap(), rank);
+
+            if ind == 0 {
+                BTreeNode::refresh_separator(&leaf, |_| true);
+            }
+        }
+
+        fn place_at_rank(&mut self, rank: usize, value: T) {
+            let mut node = match &self.root {
+                Some(root) => root.clone(),
+
+                None => {
+                    self.insert(value);
+                    return;
+                }
+            };
+
+            if node.borrow().is_leaf() {
+                return self.insert_to_root_leaf_at(rank, Rc::new(value));
+            }
+
+            let mut rank = rank;
+
+            loop {
+                let (child, child_ind) = unsafe {
+                    let node_ref = node.borrow();
+                    let subtree = node_ref.unwrap_as_subtree_unchecked();
+                    let mut ind = 0;
+
+                    while ind + 1 < subtree.children.len()
+                        && rank >= BTreeNode::values_number(&subtree.children[ind])
+                    {
+                        rank -= BTreeNode::values_number(&subtree.children[ind]);
+                        ind += 1;
+                    }
+
+                    (subtree.children[ind].clone(), ind)
+                };
+
+                if child.borrow().is_leaf() {
+                    return self.insert_to_leaf_at(child, child_ind, rank, Rc::new(value));
+                }
+
+                node = child;
+            }
+        }
+
+        pub fn range<R: RangeBounds<T>>(&self, range: R) -> BTreeRange<T> {
+            let root = match self.root.as_ref() {
+                Some(root) => root,
+                None => return BTreeRange::default(),
+            };
+
+            let front = Self::range_front(root, range.start_bound());
+
+            let back_end = match range.end_bound() {
+                Bound::Included(end) => BTreeNode::partition_point(root, &|v| v <= end),
+                Bound::Excluded(end) => BTreeNode::partition_point(root, &|v| v < end),
+                Bound::Unbounded => None,
+            };
+
+            let back = match back_end {
+                Some((leaf, ind)) => BTreeNode::previous_position(leaf, ind),
+                None => Some(BTreeNode::last_position(root)),
+            };
+
+            BTreeRange::new(front, back)
+        }
+
+        pub fn range_by_index<R: RangeBounds<usize>>(&self, range: R) -> BTreeRange<T> {
+            let len = self.len();
+
+            let start = match range.start_bound() {
+                Bound::Included(&start) => start,
+                Bound::Excluded(&start) => start + 1,
+                Bound::Unbounded => 0,
+            };
+
+            let end = match range.end_bound() {
+                Bound::Included(&end) => end + 1,
+                Bound::Excluded(&end) => end,
+                Bound::Unbounded => len,
+            };
+
+            assert!(
+                start <= end,
+                "range start (is {start}) should be <= range end (is {end})"
+            );
+            assert!(
+                end <= len,
+                "range end (is {end}) should be <= len (is {len})"
+            );
+
+            match start < end {
+                true => {
+                    let root = self.root.as_ref().unwrap();
+
+                    BTreeRange::new(
+                        Some(BTreeNode::position_at(root, start)),
+                        Some(BTreeNode::position_at(root, end - 1)),
+                    )
+                }
+
+                false => BTreeRange::default(),
+            }
+        }
+
+        #[inline]
+        pub fn page(&self, offset: usize, limit: usize) -> BTreeRange<T> {
+            let start = offset.min(self.len());
+            self.range_by_index(start..start + limit.min(self.len() - start))
+        }
+
+        pub fn for_each_in_range<R: RangeBounds<T>>(
+            &self,
+            range: R,
+            mut f: impl FnMut(&T) -> ControlFlow<()>,
+        ) -> ControlFlow<()> {
+            let mut cur = self
+                .root
+                .as_ref()
+                .and_then(|root| Self::range_front(root, range.start_bound()));
+
+            let is_past_end = |v: &T| match range.end_bound() {
+                Bound::Included(end) => v > end,
+                Bound::Excluded(end) => v >= end,
+                Bound::Unbounded => false,
+            };
+
+            while let Some((leaf, start_ind)) = cur {
+                let leaf_ref = leaf.borrow();
+                let leaf_ref = unsafe { leaf_ref.unwrap_as_leaf_unchecked() };
+
+                for value in &leaf_ref.values[start_ind..] {
+                    if is_past_end(value) {
+                        return ControlFlow::Continue(());
+                    }
+
+                    f(value)?;
+                }
+
+                cur = leaf_ref.next_leaf.clone().map(|next_leaf| (next_leaf, 0));
+            }
+
+            ControlFlow::Continue(())
+        }
+
+        #[cfg(feature = "rayon")]
+        pub fn par_contains_many(&self, values: &[T]) -> Vec<bool>
+        where
+            T: Sync,
+        {
+            let mut order = (0..values.len()).collect::<Vec<_>>();
+            order.par_sort_unstable_by(|&a, &b| values[a].cmp(&values[b]));
+
+            let probes = order.iter().map(|&ind| &values[ind]).collect::<Vec<_>>();
+            let mut found = vec![false; values.len()];
+
+            if let Some(root) = &self.root {
+                SharedNode::of(root).contains_sorted(&probes, &mut found);
+            }
+
+            let mut result = vec![false; values.len()];
+            order
+                .into_iter()
+                .zip(found)
+                .for_each(|(ind, is_found)| result[ind] = is_found);
+            result
+        }
+
+        // Keeps the node layout; separators are taken from the mapped first values,
+        // since stale ones have nothing to be mapped to
+        fn map_shape<U: Ord + Eq>(mut self, mut f: impl FnMut(Rc<T>) -> Rc<U>) -> BTree<U> {
+            let root = match &self.root {
+                Some(root) if self.is_not_empty() => root.clone(),
+                _ => return self.empty_like(),
+            };
+
+            let (root, _) = Self::map_node(&root, &mut None, &mut f);
+            self.clear();
+
+            BTree {
+                root: Some(root),
+                ..self.empty_like()
+            }
+        }
+
+        fn map_node<U: Ord + Eq>(
+            node: &Rc<RefCell<BTreeNode<T>>>,
+            prev_leaf: &mut Option<Rc<RefCell<BTreeNode<U>>>>,
+            f: &mut impl FnMut(Rc<T>) -> Rc<U>,
+        ) -> (Rc<RefCell<BTreeNode<U>>>, Rc<U>) {
+            match &mut *node.borrow_mut() {
+                BTreeNode::Leaf { leaf } => {
+                    let values = std::mem::take(&mut leaf.values)
+                        .into_iter()
+                        .map(&mut *f)
+                        .collect::<Vec<_>>();
+
+                    let first_value = values[0].clone();
+
+                    let mapped = Rc::new(RefCell::new(BTreeNode::Leaf {
+                        leaf: BTreeLeaf::new(
+                            values,
+                            None,
+                            None,
+                            prev_leaf.as_ref().map(Rc::downgrade),
+                        ),
+                    }));
+
+                    if let Some(prev_leaf) = prev_leaf.replace(mapped.clone()) {
+                        unsafe {
+                            prev_leaf
+                                .borrow_mut()
+                                .unwrap_as_leaf_mut_unchecked()
+                                .next_leaf = Some(mapped.clone());
+                        }
+                    }
+
+                    (mapped, first_value)
+                }
+
+                BTreeNode::SubTree { subtree } => {
+                    // Without the separators `f` gets the only reference to each value
+                    subtree.mid_keys.clear();
+
+                    let children = subtree
+                        .children
+                        .iter()
+                        .map(|child| Self::map_node(child, prev_leaf, f))
+                        .collect::<Vec<_>>();
+
+                    let first_value = children[0].1.clone();
+                    let mid_keys = children[1..]
+                        .iter()
+                        .map(|(_, first)| first.clone())
+                        .collect();
+                    let children = children
+                        .into_iter()
+                        .map(|(node, _)| node)
+                        .collect::<Vec<_>>();
+
+                    let mapped = Rc::new(RefCell::new(BTreeNode::SubTree {
+                        subtree: BTreeSubTree::new(children.clone(), None, mid_keys),
+                    }));
+
+                    children.iter().for_each(|child| {
+                        child.borrow_mut().set_parent(Some(Rc::downgrade(&mapped)))
+                    });
+
+                    (mapped, first_value)
+                }
+            }
+        }
+
+        pub fn bulk_load_from_sorted<I: IntoIterator<Item = T>>(values: I) -> Self {
+            let mut prev_value: Option<Rc<T>> = None;
+
+            Self::from_sorted_rcs(values.into_iter().map(|value| {
+                let value = Rc::new(value);
+
+                if let Some(prev_value) = prev_value.replace(value.clone()) {
+                    assert!(*prev_value <= *value, "values must be sorted");
+                }
+
+                value
+            }))
+        }
+
+        fn take_leaf_chain(&mut self) -> Option<Rc<RefCell<BTreeNode<T>>>> {
+            self.root.take().map(|root| BTreeNode::first_leaf(&root))
+        }
+
+        pub fn clear(&mut self) {
+            let mut cur_leaf = self.take_leaf_chain();
+
+            while let Some(leaf) = cur_leaf {
+                cur_leaf = unsafe {
+                    leaf.borrow_mut()
+                        .unwrap_as_leaf_mut_unchecked()
+                        .next_leaf
+                        .take()
+                };
+            }
+        }
+
+        pub fn retain(&mut self, mut f: impl FnMut(&T) -> bool) {
+            let mut survivors = Vec::with_capacity(self.len());
+            let mut cur_leaf = self.take_leaf_chain();
+
+            while let Some(leaf) = cur_leaf {
+                let mut leaf_ref = leaf.borrow_mut();
+                let leaf_ref = unsafe { leaf_ref.unwrap_as_leaf_mut_unchecked() };
+
+                survivors.extend(
+                    std::mem::take(&mut leaf_ref.values)
+                        .into_iter()
+                        .filter(|v| f(v)),
+                );
+
+                cur_leaf = leaf_ref.next_leaf.take();
+            }
+
+            self.root = Self::from_sorted_rcs(survivors).root;
+        }
+
+        #[inline]
+        fn quantile_index(&self, q: f64) -> usize {
+            assert!(
+                (0.0..=1.0).contains(&q),
+                "quantile (is {q}) should be in 0.0..=1.0"
+            );
+
+            ((self.len() - 1) as f64 * q).floor() as usize
+        }
+
+        #[inline]
+        pub fn quantile(&self, q: f64) -> Option<Rc<T>> {
+            match self.is_empty() {
+                true => None,
+                false => self.get(self.quantile_index(q)),
+            }
+        }
+
+        #[inline]
+        pub fn median(&self) -> Option<Rc<T>> {
+            self.quantile(0.5)
+        }
+
+        pub fn quantiles(&self, qs: &[f64]) -> Vec<Rc<T>> {
+            if self.is_empty() {
+                return vec![];
+            }
+
+            let mut order = qs
+                .iter()
+                .enumerate()
+                .map(|(pos, &q)| (self.quantile_index(q), pos))
+                .collect::<Vec<_>>();
+
+            order.sort_unstable();
+
+            let indices = order.iter().map(|&(index, _)| index).collect::<Vec<_>>();
+            let mut values = vec![None; qs.len()];
+
+            for ((_, pos), value) in order.into_iter().zip(self.get_many(&indices)) {
+                values[pos] = value;
+            }
+
+            values.into_iter().map(Option::unwrap).collect()
+        }
+
+        pub fn get_many(&self, indices: &[usize]) -> Vec<Option<Rc<T>>> {
+            assert!(
+                indices.windows(2).all(|w| w[0] <= w[1]),
+                "indices should be sorted"
+            );
+
+            let mut iter = self.iter();
+            let mut rank = 0;
+
+            indices
+                .iter()
+                .map(|&index| {
+                    if index >= self.len() {
+                        return None;
+                    }
+
+                    iter.advance_by(index - rank).unwrap();
+                    rank = index;
+                    iter.clone().next()
+                })
+                .collect()
+        }
+
+        #[inline]
+        pub fn bottom_k(&self, k: usize) -> Vec<Rc<T>> {
+            self.iter().take(k).collect()
+        }
+
+        pub fn top_k(&self, k: usize) -> Vec<Rc<T>> {
+            let mut iter = match self.root.as_ref() {
+                Some(root) => {
+                    let last_leaf = BTreeNode::last_leaf(root);
+                    let len = unsafe { last_leaf.borrow().unwrap_as_leaf_unchecked().values.len() };
+                    BTreeIter::new(Some(last_leaf), len - 1)
+                }
+
+                None => return vec![],
+            };
+
+            (0..k).map_while(|_| iter.next_back()).collect()
+        }
+
+        #[inline]
+        pub fn counts(&self) -> BTreeCounts<T> {
+            BTreeCounts::new(self.iter())
+        }
+
+        pub fn dedup(&mut self) {
+            let mut values = self.take_values();
+            values.dedup_by(|value, prev| **value == **prev);
+            self.root = Self::from_sorted_rcs(values).root;
+        }
+
+        #[inline]
+        pub fn extract_if<F: FnMut(&T) -> bool>(&mut self, pred: F) -> BTreeExtractIf<'_, T, F> {
+            BTreeExtractIf {
+                tree: self,
+                pred,
+                rank: 0,
+            }
+        }
+
+        #[inline]
+        pub fn drain(&mut self) -> BTreeDrain<T> {
+            BTreeDrain {
+                cur_leaf: self.take_leaf_chain(),
+                values: vec![].into_iter(),
+            }
+        }
+
+        pub fn freeze(mut self) -> FrozenBTree<T>
+        where
+            T: Clone,
+        {
+            let mut values = Vec::with_capacity(self.len());
+            let mut cur_leaf = self.take_leaf_chain();
+
+            while let Some(leaf) = cur_leaf {
+                let mut leaf_ref = leaf.borrow_mut();
+                let leaf_ref = unsafe { leaf_ref.unwrap_as_leaf_mut_unchecked() };
+
+                values.extend(
+                    std::mem::take(&mut leaf_ref.values)
+                        .into_iter()
+                        .map(|v| Rc::try_unwrap(v).unwrap_or_else(|v| (*v).clone())),
+                );
+
+                cur_leaf = leaf_ref.next_leaf.take();
+            }
+
+            FrozenBTree { values }
+        }
+
+        pub fn cursor(&self, direction: BTreeDirection) -> BTreeCursor<T> {
+            let iter = match direction {
+                BTreeDirection::Forward => self.iter(),
+
+                BTreeDirection::Backward => self
+                    .last_position()
+                    .map(|(leaf, ind)| BTreeIter::new(Some(leaf), ind))
+                    .unwrap_or_default(),
+            };
+
+            BTreeCursor::new(iter, direction)
+        }
+
+        pub fn resume_from(&self, token: &BTreeCursorToken<T>) -> BTreeCursor<T> {
+            let root = match self.root.as_ref() {
+                Some(root) => root,
+                None => return BTreeCursor::new(BTreeIter::default(), token.direction),
+            };
+
+            let rank = self.token_rank(root, &token.position);
+
+            let rank = match token.direction {
+                BTreeDirection::Forward => Some(rank),
+                BTreeDirection::Backward => rank.checked_sub(1),
+            };
+
+            let iter = rank
+                .filter(|rank| *rank < self.len())
+                .map(|rank| {
+                    let (leaf, ind) = BTreeNode::position_at(root, rank);
+                    BTreeIter::new(Some(leaf), ind)
+                })
+                .unwrap_or_default();
+
+            BTreeCursor::new(iter, token.direction)
+        }
+
+        fn token_rank(
+            &self,
+            root: &Rc<RefCell<BTreeNode<T>>>,
+            token: &BTreePositionToken<T>,
+        ) -> usize {
+            let rank_of = |position: Option<(Rc<RefCell<BTreeNode<T>>>, usize)>| {
+                position
+                    .map(|(leaf, ind)| BTreeNode::position_rank(leaf, ind))
+                    .unwrap_or_else(|| self.len())
+            };
+
+            let first_equal = rank_of(BTreeNode::partition_point(root, &|v| *v < token.key));
+            let after_equal = rank_of(BTreeNode::partition_point(root, &|v| *v <= token.key));
+            (first_equal + token.offset).min(after_equal)
+        }
+
+        pub fn resume_iter(&self, token: &BTreePositionToken<T>) -> BTreeIter<T> {
+            let root = match self.root.as_ref() {
+                Some(root) => root,
+                None => return BTreeIter::default(),
+            };
+
+            let rank = self.token_rank(root, token);
+
+            match rank < self.len() {
+                true => {
+                    let (leaf, ind) = BTreeNode::position_at(root, rank);
+                    BTreeIter::new(Some(leaf), ind)
+                }
+
+                false => BTreeIter::default(),
+            }
+        }
+
+        #[inline]
+        pub fn iter_enumerated(&self) -> BTreeEnumerated<BTreeIter<T>> {
+            self.iter().enumerated()
+        }
+
+        pub fn get_stored<Q: Ord + ?Sized>(&self, value: &Q) -> Option<Rc<T>>
+        where
+            T: std::borrow::Borrow<Q>,
+        {
+            self.root
+                .as_ref()
+                .and_then(|root| {
+                    BTreeNode::partition_point(root, &|v| Self::borrow_value(v) < value)
+                })
+                .map(|(leaf, ind)| BTreeNode::value_at(&leaf, ind))
+                .filter(|stored| Self::borrow_value(stored) == value)
+        }
+
+        #[inline]
+        pub fn mid_keys(&self) -> BTreeMidKeys<T> {
+            BTreeMidKeys::new(self.root.clone())
+        }
+
+        #[inline]
+        pub fn traverse_events(&self) -> BTreeTraverseEvents<T> {
+            BTreeTraverseEvents::new(self.root.clone())
+        }
+
+        #[inline]
+        pub unsafe fn get_unchecked(&self, index: usize) -> Rc<T> {
+            BTreeNode::get(self.root.as_ref().unwrap(), index)
+        }
+
+        #[inline]
+        pub fn get(&self, index: usize) -> Option<Rc<T>> {
+            if index >= self.len() {
+                None
+            } else {
+                unsafe { Some(self.get_unchecked(index)) }
+            }
+        }
+
+        pub fn rank<Q: Ord + ?Sized>(&self, value: &Q) -> Option<usize>
+        where
+            T: std::borrow::Borrow<Q>,
+        {
+            self.root
+                .as_ref()
+                .and_then(|root| {
+                    BTreeNode::partition_point(root, &|v| Self::borrow_value(v) < value)
+                })
+                .filter(|(leaf, ind)| Self::borrow_value(&BTreeNode::value_at(leaf, *ind)) == value)
+                .map(|(leaf, ind)| BTreeNode::position_rank(leaf, ind))
+        }
+
+        pub fn predecessor<Q: Ord + ?Sized>(&self, value: &Q) -> Option<Rc<T>>
+        where
+            T: std::borrow::Borrow<Q>,
+        {
+            let root = self.root.as_ref()?;
+
+            match BTreeNode::partition_point(root, &|v| Self::borrow_value(v) < value) {
+                Some((leaf, ind)) => BTreeNode::value_before(&leaf, ind),
+                None => self.last(),
+            }
+        }
+
+        pub fn successor<Q: Ord + ?Sized>(&self, value: &Q) -> Option<Rc<T>>
+        where
+            T: std::borrow::Borrow<Q>,
+        {
+            self.root
+                .as_ref()
+                .and_then(|root| {
+                    BTreeNode::partition_point(root, &|v| Self::borrow_value(v) <= value)
+                })
+                .map(|(leaf, ind)| BTreeNode::value_at(&leaf, ind))
+        }
+
+        pub fn nearest<D: Ord>(&self, value: &T, distance: impl Fn(&T, &T) -> D) -> Option<Rc<T>> {
+            let root = self.root.as_ref()?;
+
+            let (floor, ceiling) = match BTreeNode::partition_point(root, &|v| v < value) {
+                Some((leaf, ind)) => (
+                    BTreeNode::value_before(&leaf, ind),
+                    Some(BTreeNode::value_at(&leaf, ind)),
+                ),
+
+                None => (self.last(), None),
+            };
+
+            match (floor, ceiling) {
+                (Some(floor), Some(ceiling)) => {
+                    match distance(&ceiling, value) < distance(&floor, value) {
+                        true => Some(ceiling),
+                        false => Some(floor),
+                    }
+                }
+
+                (floor, ceiling) => floor.or(ceiling),
+            }
+        }
+
+        #[inline]
+        pub fn find(&self, value: &T) -> BTreeIter<T> {
+            self.lower_bound(value)
+        }
+
+        pub fn lower_bound<Q: Ord + ?Sized>(&self, value: &Q) -> BTreeIter<T>
+        where
+            T: std::borrow::Borrow<Q>,
+        {
+            self.root
+                .as_ref()
+                .and_then(|root| {
+                    BTreeNode::partition_point(root, &|v| Self::borrow_value(v) < value)
+                })
+                .map(|(leaf, ind)| BTreeIter::new(Some(leaf), ind))
+                .unwrap_or_default()
+        }
+
+        pub fn upper_bound<Q: Ord + ?Sized>(&self, value: &Q) -> BTreeIter<T>
+        where
+            T: std::borrow::Borrow<Q>,
+        {
+            self.root
+                .as_ref()
+                .and_then(|root| {
+                    BTreeNode::partition_point(root, &|v| Self::borrow_value(v) <= value)
+                })
+                .map(|(leaf, ind)| BTreeIter::new(Some(leaf), ind))
+                .unwrap_or_default()
+        }
+
+        fn join_nodes(
+            left: Option<(Rc<RefCell<BTreeNode<T>>>, usize)>,
+            separator: Rc<T>,
+            right: Option<(Rc<RefCell<BTreeNode<T>>>, usize)>,
+        ) -> Option<(Rc<RefCell<BTreeNode<T>>>, usize)> {
+            let ((left, left_height), (right, right_height)) = match (left, right) {
+                (Some(left), Some(right)) => (left, right),
+                (left, right) => {
+                    // The piece may still point at the subtree it was cut from
+                    let piece = left.or(right);
+                    piece
+                        .iter()
+                        .for_each(|(node, _)| node.borrow_mut().set_parent(None));
+                    return piece;
+                }
+            };
+
+            left.borrow_mut().set_parent(None);
+            right.borrow_mut().set_parent(None);
+
+            match left_height.cmp(&right_height) {
+                Ordering::Equal => Some((
+                    Self::new_root_after_division(left, right, separator),
+                    left_height + 1,
+                )),
+
+                Ordering::Greater => {
+                    let mut node = left.clone();
+
+                    for _ in right_height + 1..left_height {
+                        node = unsafe {
+                            let node_ref = node.borrow();
+                            node_ref
+                                .unwrap_as_subtree_unchecked()
+                                .children
+                                .last()
+                                .unwrap()
+                                .clone()
+                        };
+                    }
+
+                    right.borrow_mut().set_parent(Some(Rc::downgrade(&node)));
+
+                    let key_ind = unsafe {
+                        let mut node_ref = node.borrow_mut();
+                        let node_tree = node_ref.unwrap_as_subtree_mut_unchecked();
+                        node_tree.children.push(right);
+                        node_tree.mid_keys.len()
+                    };
+
+                    Some(Self::attach_to_node(
+                        left,
+                        left_height,
+                        node,
+                        key_ind,
+                        separator,
+                    ))
+                }
+
+                Ordering::Less => {
+                    let mut node = right.clone();
+
+                    for _ in left_height + 1..right_height {
+                        node = unsafe {
+                            let node_ref = node.borrow();
+                            node_ref.unwrap_as_subtree_unchecked().children[0].clone()
+                        };
+                    }
+
+                    left.borrow_mut().set_parent(Some(Rc::downgrade(&node)));
+
+                    unsafe {
+                        node.borrow_mut()
+                            .unwrap_as_subtree_mut_unchecked()
+                            .children
+                            .insert(0, left);
+                    }
+
+                    Some(Self::attach_to_node(
+                        right,
+                        right_height,
+                        node,
+                        0,
+                        separator,
+                    ))
+                }
+            }
+        }
+
+        #[inline]
+        fn attach_to_node(
+            root: Rc<RefCell<BTreeNode<T>>>,
+            height: usize,
+            node: Rc<RefCell<BTreeNode<T>>>,
+            key_ind: usize,
+            separator: Rc<T>,
+        ) -> (Rc<RefCell<BTreeNode<T>>>, usize) {
+            BTreeNode::recount_values_number(&node);
+
+            let mut tree = Self {
+                root: Some(root.clone()),
+                ..Self::new()
+            };
+
+            tree.insert_mid_key_to_parent_subtree(node, key_ind, separator);
+            let new_root = tree.root.take().unwrap();
+
+            match Rc::ptr_eq(&root, &new_root) {
+                true => (new_root, height),
+                false => (new_root, height + 1),
+            }
+        }
+
+        fn split_off_rank(&mut self, rank: usize) -> Self {
+            if rank == 0 {
+                return std::mem::replace(self, self.empty_like());
+            }
+
+            if rank >= self.len() {
+                return self.empty_like();
+            }
+
+            let mut node = self.root.take().unwrap();
+            let mut height = BTreeNode::height(&node);
+            let mut rank = rank;
+            let mut left_pieces = vec![];
+            let mut right_pieces = vec![];
+
+            while height > 0 {
+                node = unsafe {
+                    let node_ref = node.borrow();
+                    let subtree = node_ref.unwrap_as_subtree_unchecked();
+                    let mut ind = 0;
+
+                    while rank >= BTreeNode::values_number(&subtree.children[ind]) {
+                        rank -= BTreeNode::values_number(&subtree.children[ind]);
+                        ind += 1;
+                    }
+
+                    left_pieces.extend((0..ind).map(|i| {
+                        (
+                            subtree.children[i].clone(),
+                            height - 1,
+                            subtree.mid_keys[i].clone(),
+                        )
+                    }));
+
+                    right_pieces.extend((ind + 1..subtree.children.len()).rev().map(|i| {
+                        (
+                            subtree.mid_keys[i - 1].clone(),
+                            subtree.children[i].clone(),
+                            height - 1,
+                        )
+                    }));
+
+                    subtree.children[ind].clone()
+                };
+
+                height -= 1;
+            }
+
+            let (left_leaf, right_leaf) = unsafe {
+                let mut leaf_ref = node.borrow_mut();
+                let leaf_ref = leaf_ref.unwrap_as_leaf_mut_unchecked();
+                leaf_ref.parent = None;
+
+                match rank {
+                    0 => {
+                        if let Some(prev_leaf) = leaf_ref.previous_leaf.take() {
+                            prev_leaf
+                                .upgrade()
+                                .unwrap()
+                                .borrow_mut()
+                                .unwrap_as_leaf_mut_unchecked()
+                                .next_leaf = None;
+                        }
+
+                        (None, node.clone())
+                    }
+
+                    _ => {
+                        let next_leaf = leaf_ref.next_leaf.take();
+
+                        let right_leaf = Rc::new(RefCell::new(BTreeNode::Leaf {
+                            leaf: BTreeLeaf::new(
+                                leaf_ref.values.split_off(rank),
+                                None,
+                                next_leaf.clone(),
+                                None,
+                            ),
+                        }));
+
+                        if let Some(next_leaf) = next_leaf {
+                            next_leaf
+                                .borrow_mut()
+                                .unwrap_as_leaf_mut_unchecked()
+                                .previous_leaf = Some(Rc::downgrade(&right_leaf));
+                        }
+
+                        (Some(node.clone()), right_leaf)
+                    }
+                }
+            };
+
+            let mut left = left_leaf.map(|leaf| (leaf, 0));
+
+            for (piece, piece_height, separator) in left_pieces.into_iter().rev() {
+                left = Self::join_nodes(Some((piece, piece_height)), separator, left);
+            }
+
+            let mut right = Some((right_leaf, 0));
+
+            for (separator, piece, piece_height) in right_pieces.into_iter().rev() {
+                right = Self::join_nodes(right, separator, Some((piece, piece_height)));
+            }
+
+            self.root = left.map(|(root, _)| root);
+
+            Self {
+                root: right.map(|(root, _)| root),
+                ..self.empty_like()
+            }
+        }
+
+        fn concat(&mut self, other: Self) {
+            let (left, right) = match (self.root.take(), other.root) {
+                (Some(left), Some(right)) => (left, right),
+                (left, right) => {
+                    self.root = left.or(right);
+                    return;
+                }
+            };
+
+            let last_leaf = BTreeNode::last_leaf(&left);
+            let first_leaf = BTreeNode::first_leaf(&right);
+
+            let separator = unsafe {
+                first_leaf
+                    .borrow_mut()
+                    .unwrap_as_leaf_mut_unchecked()
+                    .previous_leaf = Some(Rc::downgrade(&last_leaf));
+
+                last_leaf
+                    .borrow_mut()
+                    .unwrap_as_leaf_mut_unchecked()
+                    .next_leaf = Some(first_leaf.clone());
+
+                first_leaf.borrow().unwrap_as_leaf_unchecked().values[0].clone()
+            };
+
+            let left_height = BTreeNode::height(&left);
+            let right_height = BTreeNode::height(&right);
+
+            self.root = Self::join_nodes(
+                Some((left, left_height)),
+                separator,
+                Some((right, right_height)),
+            )
+            .map(|(root, _)| root);
+        }
+
+        pub fn extract_range<R: RangeBounds<T>>(&mut self, range: R) -> Self {
+            let (start, end) = self.range_ranks(&range);
+            let mut extracted = self.split_off_rank(start);
+            let rest = extracted.split_off_rank(end - start);
+            self.concat(rest);
+            extracted
+        }
+
+        pub fn count<Q: Ord + ?Sized>(&self, value: &Q) -> usize
+        where
+            T: std::borrow::Borrow<Q>,
+        {
+            let root = match self.root.as_ref() {
+                Some(root) => root,
+                None => return 0,
+            };
+
+            let lower = self.position_rank_or_len(BTreeNode::partition_point(root, &|v| {
+                Self::borrow_value(v) < value
+            }));
+
+            let upper = self.position_rank_or_len(BTreeNode::partition_point(root, &|v| {
+                Self::borrow_value(v) <= value
+            }));
+
+            upper - lower
+        }
+
+        #[inline]
+        pub fn equal_range<Q: Ord + ?Sized>(&self, value: &Q) -> std::iter::Take<BTreeIter<T>>
+        where
+            T: std::borrow::Borrow<Q>,
+        {
+            self.lower_bound(value).take(self.count(value))
+        }
+
+        #[inline]
+        pub fn clone_range<R: RangeBounds<T>>(&self, range: R) -> Self {
+            Self {
+                root: Self::from_sorted_rcs(self.range(range)).root,
+                ..self.empty_like()
+            }
+        }
+
+        #[inline]
+        pub fn count_in_range<R: RangeBounds<T>>(&self, range: R) -> usize {
+            let (start, end) = self.range_ranks(&range);
+            end - start
+        }
+
+        pub fn remove_range<R: RangeBounds<T>>(&mut self, range: R) -> usize {
+            let mut removed = self.extract_range(range);
+            let removed_number = removed.len();
+            removed.clear();
+            removed_number
+        }
+
+        pub fn split_off<Q: Ord + ?Sized>(&mut self, key: &Q) -> Self
+        where
+            T: std::borrow::Borrow<Q>,
+        {
+            let rank = match self.root.as_ref() {
+                Some(root) => self.position_rank_or_len(BTreeNode::partition_point(root, &|v| {
+                    Self::borrow_value(v) < key
+                })),
+
+                None => return self.empty_like(),
+            };
+
+            self.split_off_rank(rank)
+        }
+
+        pub fn append(&mut self, other: &mut Self) {
+            let other = std::mem::replace(other, other.empty_like());
+
+            let is_disjoint = match (self.last(), other.first()) {
+                (Some(last), Some(first)) => match self.mode {
+                    BTreeMode::Multiset => *last <= *first,
+                    BTreeMode::Set => *last < *first,
+                },
+
+                _ => true,
+            };
+
+            if is_disjoint {
+                return self.concat(other);
+            }
+
+            let len = self.len() + other.len();
+            let merged = Self::merge_values(self.take_values(), other, len, self.mode);
+            self.root = Self::from_sorted_rcs(merged).root;
+        }
+
+        // Changes are merged in one pass when they come in ascending order, as `diff`
+        // yields them; everything from the first out-of-order change on is applied
+        // with regular inserts and removals instead
+        pub fn apply_patch(&mut self, changes: impl IntoIterator<Item = BTreeChange<T>>) {
+            let mut values = self.take_values().into_iter().peekable();
+            let mut patched = Vec::with_capacity(values.len());
+            let mut changes = changes.into_iter();
+            let mut last_changed: Option<Rc<T>> = None;
+            let mut out_of_order = None;
+
+            for change in changes.by_ref() {
+                let (BTreeChange::Added(changed) | BTreeChange::Removed(changed)) = &change;
+
+                if last_changed.as_ref().is_some_and(|last| **changed < **last) {
+                    out_of_order = Some(change);
+                    break;
+                }
+
+                last_changed = Some(changed.clone());
+
+                match change {
+                    BTreeChange::Added(value) => {
+                        patched.extend(std::iter::from_fn(|| values.next_if(|v| **v <= *value)));
+
+                        let is_present = patched.last().is_some_and(|last| **last == *value);
+
+                        if !(is_present && self.mode == BTreeMode::Set) {
+                            patched.push(value);
+                        }
+                    }
+
+                    BTreeChange::Removed(value) => {
+                        patched.extend(std::iter::from_fn(|| values.next_if(|v| **v < *value)));
+
+                        if values.next_if(|v| **v == *value).is_none()
+                            && patched.last().is_some_and(|last| **last == *value)
+                        {
+                            patched.pop();
+                        }
+                    }
+                }
+            }
+
+            patched.extend(values);
+            self.root = Self::from_sorted_rcs(patched).root;
+
+            for change in out_of_order.into_iter().chain(changes) {
+                match change {
+                    BTreeChange::Added(value) => {
+                        if !(self.mode == BTreeMode::Set && self.contains(&value)) {
+                            self.insert_rc(value);
+                        }
+                    }
+
+                    BTreeChange::Removed(value) => {
+                        self.remove(&*value);
+                    }
+                }
+            }
+        }
+
+        pub fn merge(a: &Self, b: &Self) -> Self {
+            let merged = Self::merge_values(a.iter(), b.iter(), a.len() + b.len(), a.mode);
+
+            Self {
+                root: Self::from_sorted_rcs(merged).root,
+                ..a.empty_like()
+            }
+        }
+
+        pub fn merge_all<'a>(trees: impl IntoIterator<Item = &'a Self>) -> Self
+        where
+            T: 'a,
+        {
+            let trees = trees.into_iter().collect::<Vec<_>>();
+            let empty = trees
+                .first()
+                .map_or_else(Self::new, |tree| tree.empty_like());
+            let mode = empty.mode;
+            let mut iters = trees.into_iter().map(BTree::iter).collect::<Vec<_>>();
+            let mut heap = std::collections::BinaryHeap::with_capacity(iters.len());
+
+            iters.iter_mut().enumerate().for_each(|(ind, iter)| {
+                heap.extend(iter.next().map(|value| std::cmp::Reverse((value, ind))));
+            });
+
+            // Ties are broken by the tree index, so equal values keep the input order
+            let merged = std::iter::from_fn(|| {
+                let std::cmp::Reverse((value, ind)) = heap.pop()?;
+                heap.extend(iters[ind].next().map(|next| std::cmp::Reverse((next, ind))));
+                Some(value)
+            });
+
+            let mut last: Option<Rc<T>> = None;
+
+            Self {
+                root: Self::from_sorted_rcs(merged.filter(|value| {
+                    let is_repeated = last.as_ref().is_some_and(|last| **last == **value);
+                    last = Some(value.clone());
+                    !(is_repeated && mode == BTreeMode::Set)
+                }))
+                .root,
+                ..empty
+            }
+        }
+
+        fn merge_values(
+            values: impl IntoIterator<Item = Rc<T>>,
+            other_values: impl IntoIterator<Item = Rc<T>>,
+            len: usize,
+            mode: BTreeMode,
+        ) -> Vec<Rc<T>> {
+            let mut merged = Vec::with_capacity(len);
+            let mut values = values.into_iter().peekable();
+            let mut other_values = other_values.into_iter().peekable();
+
+            while let (Some(value), Some(other_value)) = (values.peek(), other_values.peek()) {
+                match **other_value < **value {
+                    true => merged.extend(other_values.next()),
+                    false => merged.extend(values.next()),
+                }
+            }
+
+            merged.extend(values);
+            merged.extend(other_values);
+
+            if mode == BTreeMode::Set {
+                merged.dedup_by(|value, prev| **value == **prev);
+            }
+
+            merged
+        }
+
+        fn take_values(&mut self) -> Vec<Rc<T>> {
+            let mut values = Vec::with_capacity(self.len());
+            let mut cur_leaf = self.take_leaf_chain();
+
+            while let Some(leaf) = cur_leaf {
+                let mut leaf_ref = leaf.borrow_mut();
+                let leaf_ref = unsafe { leaf_ref.unwrap_as_leaf_mut_unchecked() };
+                values.append(&mut leaf_ref.values);
+                cur_leaf = leaf_ref.next_leaf.take();
+            }
+
+            values
+        }
+
+        pub fn into_sorted_vec(mut self) -> Vec<T>
+        where
+            T: Clone,
+        {
+            self.take_values()
+                .into_iter()
+                .map(|value| Rc::try_unwrap(value).unwrap_or_else(|value| (*value).clone()))
+                .collect()
+        }
+
+        #[inline]
+        pub fn split_off_at(&mut self, index: usize) -> Self {
+            let len = self.len();
+            assert!(
+                index <= len,
+                "split index (is {index}) should be <= len (is {len})"
+            );
+            self.split_off_rank(index)
+        }
+
+        #[inline]
+        pub fn take_first_n(&mut self, n: usize) -> Self {
+            let rest = self.split_off_rank(n);
+            std::mem::replace(self, rest)
+        }
+
+        #[inline]
+        pub fn take_last_n(&mut self, n: usize) -> Self {
+            self.split_off_rank(self.len().saturating_sub(n))
+        }
+
+        #[inline]
+        pub fn truncate(&mut self, len: usize) {
+            if len < self.len() {
+                self.split_off_rank(len).clear();
+            }
+        }
+    }
+
+    impl<T: Ord + Eq> FrozenBTree<T> {
+        #[inline]
+        pub fn len(&self) -> usize {
+            self.values.len()
+        }
+
+        #[inline]
+        pub fn is_empty(&self) -> bool {
+            self.values.is_empty()
+        }
+
+        #[inline]
+        pub fn is_not_empty(&self) -> bool {
+            !self.is_empty()
+        }
+
+        #[inline]
+        pub fn first(&self) -> Option<&T> {
+            self.values.first()
+        }
+
+        #[inline]
+        pub fn last(&self) -> Option<&T> {
+            self.values.last()
+        }
+
+        #[inline]
+        pub fn get(&self, index: usize) -> Option<&T> {
+            self.values.get(index)
+        }
+
+        #[inline]
+        pub fn contains(&self, value: &T) -> bool {
+            self.values.binary_search(value).is_ok()
+        }
+
+        #[inline]
+        pub fn iter(&self) -> std::slice::Iter<'_, T> {
+            self.values.iter()
+        }
+
+        #[inline]
+        pub fn as_slice(&self) -> &[T] {
+            &self.values
+        }
+
+        pub fn range<R: RangeBounds<T>>(&self, range: R) -> std::slice::Iter<'_, T> {
+            let start = match range.start_bound() {
+                Bound::Included(start) => self.values.partition_point(|v| v < start),
+                Bound::Excluded(start) => self.values.partition_point(|v| v <= start),
+                Bound::Unbounded => 0,
+            };
+
+            let end = match range.end_bound() {
+                Bound::Included(end) => self.values.partition_point(|v| v <= end),
+                Bound::Excluded(end) => self.values.partition_point(|v| v < end),
+                Bound::Unbounded => self.values.len(),
+            };
+
+            self.values[start..end.max(start)].iter()
+        }
+    }
+
+    #[cfg(feature = "rand")]
+    impl<T: Ord + Eq> BTree<T> {
+        #[inline]
+        pub fn sample<R: rand::Rng + ?Sized>(&self, rng: &mut R) -> Option<Rc<T>> {
+            match self.is_empty() {
+                true => None,
+                false => self.get(rng.gen_range(0..self.len())),
+            }
+        }
+
+        pub fn sample_n<R: rand::Rng + ?Sized>(&self, rng: &mut R, n: usize) -> Vec<Rc<T>> {
+            let mut indices =
+                rand::seq::index::sample(rng, self.len(), n.min(self.len())).into_vec();
+            indices.sort_unstable();
+
+            self.get_many(&indices)
+                .into_iter()
+                .map(Option::unwrap)
+                .collect()
+        }
+    }
+
+    #[cfg(feature = "mmap")]
+    const MAPPED_MAGIC: &[u8; 8] = b"BT23FRZ\0";
+
+    #[cfg(feature = "mmap")]
+    const MAPPED_HEADER_LEN: usize = 24;
+
+    #[cfg(feature = "mmap")]
+    macro_rules! impl_fixed_size_key {
+        ($($key:ty),*) => {
+            $(
+                impl FixedSizeKey for $key {
+                    const SIZE: usize = std::mem::size_of::<$key>();
+
+                    #[inline]
+                    fn write_bytes(&self, bytes: &mut [u8]) {
+                        bytes.copy_from_slice(&self.to_le_bytes())
+                    }
+
+                    #[inline]
+                    fn read_bytes(bytes: &[u8]) -> Self {
+                        <$key>::from_le_bytes(bytes.try_into().unwrap())
+                    }
+                }
+            )*
+        };
+    }
+
+    #[cfg(feature = "mmap")]
+    impl_fixed_size_key!(u8, u16, u32, u64, u128, i8, i16, i32, i64, i128);
+
+    #[cfg(feature = "mmap")]
+    impl<T: FixedSizeKey> FrozenBTree<T> {
+        pub fn write_mapped<P: AsRef<std::path::Path>>(&self, path: P) -> std::io::Result<()> {
+            let mut bytes = vec![0; MAPPED_HEADER_LEN + self.len() * T::SIZE];
+            bytes[..8].copy_from_slice(MAPPED_MAGIC);
+            bytes[8..16].copy_from_slice(&(self.len() as u64).to_le_bytes());
+            bytes[16..24].copy_from_slice(&(T::SIZE as u64).to_le_bytes());
+
+            bytes[MAPPED_HEADER_LEN..]
+                .chunks_exact_mut(T::SIZE)
+                .zip(&self.values)
+                .for_each(|(chunk, value)| value.write_bytes(chunk));
+
+            std::fs::write(path, bytes)
+        }
+    }
+
+    #[cfg(feature = "mmap")]
+    impl<T: FixedSizeKey> MappedFrozenBTree<T> {
+        // Safety: the caller must guarantee that no one (this or another process)
+        // truncates or modifies the file while the returned tree is alive: a shrunk
+        // file faults with SIGBUS on access, and changed bytes break the sorted layout
+        // the lookups rely on
+        pub unsafe fn open<P: AsRef<std::path::Path>>(path: P) -> std::io::Result<Self> {
+            let file = std::fs::File::open(path)?;
+
+            // Read-only shared mapping that is never written through; the file itself
+            // staying unchanged is the caller's obligation (see above)
+            let map = unsafe { memmap2::Mmap::map(&file)? };
+
+            let invalid = |message| std::io::Error::new(std::io::ErrorKind::InvalidData, message);
+
+            if map.len() < MAPPED_HEADER_LEN || &map[..8] != MAPPED_MAGIC {
+                return Err(invalid("not a mapped frozen tree"));
+            }
+
+            let header_value = |range: std::ops::Range<usize>| {
+                u64::from_le_bytes(map[range].try_into().unwrap()) as usize
+            };
+
+            let len = header_value(8..16);
+
+            if header_value(16..24) != T::SIZE {
+                return Err(invalid("key size mismatch"));
+            }
+
+            if Some(map.len())
+                != len
+                    .checked_mul(T::SIZE)
+                    .and_then(|n| n.checked_add(MAPPED_HEADER_LEN))
+            {
+                return Err(invalid("truncated mapped frozen tree"));
+            }
+
+            Ok(Self {
+                map,
+                len,
+                marker: std::marker::PhantomData,
+            })
+        }
+
+        #[inline]
+        pub fn len(&self) -> usize {
+            self.len
+        }
+
+        #[inline]
+        pub fn is_empty(&self) -> bool {
+            self.len == 0
+        }
+
+        #[inline]
+        pub fn is_not_empty(&self) -> bool {
+            !self.is_empty()
+        }
+
+        #[inline]
+        fn value_at(&self, index: usize) -> T {
+            let offset = MAPPED_HEADER_LEN + index * T::SIZE;
+            T::read_bytes(&self.map[offset..offset + T::SIZE])
+        }
+
+        #[inline]
+        pub fn get(&self, index: usize) -> Option<T> {
+            (index < self.len).then(|| self.value_at(index))
+        }
+
+        #[inline]
+        pub fn first(&self) -> Option<T> {
+            self.get(0)
+        }
+
+        #[inline]
+        pub fn last(&self) -> Option<T> {
+            self.len.checked_sub(1).and_then(|index| self.get(index))
+        }
+
+        fn partition_point(&self, is_before: impl Fn(&T) -> bool) -> usize {
+            let (mut low, mut high) = (0, self.len);
+
+            while low < high {
+                let mid = low + (high - low) / 2;
+
+                match is_before(&self.value_at(mid)) {
+                    true => low = mid + 1,
+                    false => high = mid,
+                }
+            }
+
+            low
+        }
+
+        #[inline]
+        pub fn contains(&self, value: &T) -> bool {
+            self.get(self.partition_point(|v| v < value))
+                .is_some_and(|found| found == *value)
+        }
+
+        #[inline]
+        pub fn iter(&self) -> impl Iterator<Item = T> + '_ {
+            (0..self.len).map(|index| self.value_at(index))
+        }
+
+        pub fn range<R: RangeBounds<T>>(&self, range: R) -> impl Iterator<Item = T> + '_ {
+            let start = match range.start_bound() {
+                Bound::Included(start) => self.partition_point(|v| v < start),
+                Bound::Excluded(start) => self.partition_point(|v| v <= start),
+                Bound::Unbounded => 0,
+            };
+
+            let end = match range.end_bound() {
+                Bound::Included(end) => self.partition_point(|v| v <= end),
+                Bound::Excluded(end) => self.partition_point(|v| v < end),
+                Bound::Unbounded => self.len,
+            };
+
+            (start..end.max(start)).map(|index| self.value_at(index))
+        }
+    }
+
+    #[cfg(feature = "icu")]
+    impl Collation {
+        pub fn try_new(
+            locale: &icu_locid::Locale,
+            options: icu_collator::CollatorOptions,
+        ) -> Result<Self, icu_collator::CollatorError> {
+            icu_collator::Collator::try_new(&locale.into(), options).map(|collator| Self {
+                collator: Rc::new(collator),
+            })
+        }
+
+        #[inline]
+        pub fn compare(&self, left: &str, right: &str) -> Ordering {
+            self.collator.compare(left, right)
+        }
+
+        #[inline]
+        pub fn key<S: Into<String>>(&self, value: S) -> CollatedString {
+            CollatedString {
+                value: value.into(),
+                collation: self.clone(),
+            }
+        }
+
+        #[inline]
+        pub fn tree<S: Into<String>, I: IntoIterator<Item = S>>(
+            &self,
+            values: I,
+        ) -> BTree<CollatedString> {
+            BTree::from_iter(values.into_iter().map(|value| self.key(value)))
+        }
+
+        #[inline]
+        pub fn string_tree<S: AsRef<str>>(&self) -> BTreeBy<S, Collation> {
+            BTreeBy::new(self.clone())
+        }
+    }
+
+    #[cfg(feature = "icu")]
+    impl<S: AsRef<str> + ?Sized> BTreeComparator<S> for Collation {
+        #[inline]
+        fn compare(&self, left: &S, right: &S) -> Ordering {
+            Collation::compare(self, left.as_ref(), right.as_ref())
+        }
+    }
+
+    #[cfg(feature = "icu")]
+    impl Debug for Collation {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            f.debug_struct("Collation").finish_non_exhaustive()
+        }
+    }
+
+    #[cfg(feature = "icu")]
+    impl CollatedString {
+        #[inline]
+        pub fn as_str(&self) -> &str {
+            &self.value
+        }
+
+        #[inline]
+        pub fn into_string(self) -> String {
+            self.value
+        }
+    }
+
+    #[cfg(feature = "icu")]
+    impl Debug for CollatedString {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            f.debug_tuple("CollatedString").field(&self.value).finish()
+        }
+    }
+
+    #[cfg(feature = "icu")]
+    impl PartialEq for CollatedString {
+        #[inline]
+        fn eq(&self, other: &Self) -> bool {
+            self.cmp(other) == Ordering::Equal
+        }
+    }
+
+    #[cfg(feature = "icu")]
+    impl Eq for CollatedString {}
+
+    #[cfg(feature = "icu")]
+    impl PartialOrd for CollatedString {
+        #[inline]
+        fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+            Some(self.cmp(other))
+        }
+    }
+
+    #[cfg(feature = "icu")]
+    impl Ord for CollatedString {
+        #[inline]
+        fn cmp(&self, other: &Self) -> Ordering {
+            self.collation.compare(&self.value, &other.value)
+        }
+    }
+
+    macro_rules! impl_total_float {
+        ($total:ident, $float:ty) => {
+            impl $total {
+                #[inline]
+                pub const fn get(self) -> $float {
+                    self.0
+                }
+            }
+
+            impl PartialEq for $total {
+                #[inline]
+                fn eq(&self, other: &Self) -> bool {
+                    self.cmp(other) == Ordering::Equal
+                }
+            }
+
+            impl Eq for $total {}
+
+            impl PartialOrd for $total {
+                #[inline]
+                fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+                    Some(self.cmp(other))
+                }
+            }
+
+            impl Ord for $total {
+                #[inline]
+                fn cmp(&self, other: &Self) -> Ordering {
+                    self.0.total_cmp(&other.0)
+                }
+            }
+
+            impl From<$float> for $total {
+                #[inline]
+                fn from(value: $float) -> Self {
+                    Self(value)
+                }
+            }
+
+            impl From<$total> for $float {
+                #[inline]
+                fn from(value: $total) -> Self {
+                    value.0
+                }
+            }
+
+            impl Extend<$float> for BTree<$total> {
+                #[inline]
+                fn extend<I: IntoIterator<Item = $float>>(&mut self, iter: I) {
+                    iter.into_iter().for_each(|x| {
+                        self.insert($total(x));
+                    });
+                }
+            }
+
+            impl FromIterator<$float> for BTree<$total> {
+                #[inline]
+                fn from_iter<I: IntoIterator<Item = $float>>(iter: I) -> Self {
+                    let mut tree = BTree::new();
+                    tree.extend(iter);
+                    tree
+                }
+            }
+        };
+    }
+
+    impl_total_float!(TotalF64, f64);
+    impl_total_float!(TotalF32, f32);
+
+    impl<T: Ord + Eq + Clone> DeltaBTree<T> {
+        #[inline]
+        pub fn new(base: FrozenBTree<T>) -> Self {
+            Self {
+                base,
+                inserted: BTree::new(),
+                removed: BTree::new(),
+            }
+        }
+
+        #[inline]
+        pub fn len(&self) -> usize {
+            self.base.len() + self.inserted.len() - self.removed.len()
+        }
+
+        #[inline]
+        pub fn is_empty(&self) -> bool {
+            self.len() == 0
+        }
+
+        #[inline]
+        pub fn is_not_empty(&self) -> bool {
+            !self.is_empty()
+        }
+
+        #[inline]
+        pub fn base(&self) -> &FrozenBTree<T> {
+            &self.base
+        }
+
+        #[inline]
+        pub fn delta_len(&self) -> usize {
+            self.inserted.len() + self.removed.len()
+        }
+
+        #[inline]
+        pub fn count(&self, value: &T) -> usize {
+            self.base.range(value..=value).count() + self.inserted.range(value..=value).count()
+                - self.removed.range(value..=value).count()
+        }
+
+        #[inline]
+        pub fn contains(&self, value: &T) -> bool {
+            self.count(value) > 0
+        }
+
+        #[inline]
+        pub fn get(&self, value: &T) -> Option<&T> {
+            self.range(value..=value).next()
+        }
+
+        #[inline]
+        pub fn insert(&mut self, value: T) -> bool {
+            self.inserted.insert(value)
+        }
+
+        #[inline]
+        pub fn remove(&mut self, value: &T) -> bool {
+            match self.contains(value) {
+                true => {
+                    self.removed.insert(value.clone());
+                    true
+                }
+
+                false => false,
+            }
+        }
+
+        #[inline]
+        pub fn iter(&self) -> DeltaBTreeRange<'_, T> {
+            self.range(..)
+        }
+
+        pub fn range<R: RangeBounds<T> + Clone>(&self, range: R) -> DeltaBTreeRange<'_, T> {
+            DeltaBTreeRange {
+                base: self.base.range(range.clone()).peekable(),
+                inserted: self.inserted.range(range.clone()).peekable(),
+                removed: self.removed.range(range).peekable(),
+                overlay: &self.inserted,
+            }
+        }
+
+        pub fn merge_down(&mut self) {
+            if self.delta_len() == 0 {
+                return;
+            }
+
+            let values = self.iter().cloned().collect();
+            self.base = FrozenBTree { values };
+            self.inserted = BTree::new();
+            self.removed = BTree::new();
+        }
+    }
+
+    impl<T: Ord + Eq> ShardedBTree<T> {
+        pub fn with_boundaries(boundaries: Vec<T>) -> Self {
+            assert!(
+                boundaries.windows(2).all(|w| w[0] < w[1]),
+                "boundaries must be strictly increasing"
+            );
+
+            let shards = (0..=boundaries.len())
+                .map(|_| Mutex::new(ShardTree(BTree::new())))
+                .collect();
+
+            Self { boundaries, shards }
+        }
+
+        #[inline]
+        pub fn shard_count(&self) -> usize {
+            self.shards.len()
+        }
+
+        #[inline]
+        pub fn shard_index(&self, value: &T) -> usize {
+            self.boundaries
+                .partition_point(|boundary| boundary <= value)
+        }
+
+        #[inline]
+        pub fn lock_shard(&self, index: usize) -> ShardGuard<'_, T> {
+            ShardGuard {
+                shard: self.shards[index].lock().unwrap(),
+            }
+        }
+
+        #[inline]
+        pub fn try_lock_shard(&self, index: usize) -> Option<ShardGuard<'_, T>> {
+            self.shards[index]
+                .try_lock()
+                .ok()
+                .map(|shard| ShardGuard { shard })
+        }
+
+        #[inline]
+        pub fn len(&self) -> usize {
+            (0..self.shard_count())
+                .map(|index| self.lock_shard(index).len())
+                .sum()
+        }
+
+        #[inline]
+        pub fn is_empty(&self) -> bool {
+            self.len() == 0
+        }
+
+        #[inline]
+        pub fn is_not_empty(&self) -> bool {
+            !self.is_empty()
+        }
+
+        #[inline]
+        pub fn insert(&self, value: T) -> bool {
+            self.lock_shard(self.shard_index(&value)).insert(value)
+        }
+
+        #[inline]
+        pub fn remove(&self, value: &T) -> bool {
+            self.lock_shard(self.shard_index(value)).remove(value)
+        }
+
+        #[inline]
+        pub fn contains(&self, value: &T) -> bool {
+            self.lock_shard(self.shard_index(value)).contains(value)
+        }
+
+        // Each shard is locked only while its values are being copied out
+        #[inline]
+        pub fn iter(&self) -> ShardedBTreeIter<'_, T> {
+            ShardedBTreeIter {
+                sharded: self,
+                next_shard: 0,
+                cur_shard: vec![].into_iter(),
+            }
+        }
+    }
+
+    impl<T: Ord + Eq> Default for ShardedBTree<T> {
+        #[inline]
+        fn default() -> Self {
+            Self::with_boundaries(vec![])
+        }
+    }
+
+    impl<T: Ord + Eq> ShardGuard<'_, T> {
+        #[inline]
+        pub fn len(&self) -> usize {
+            self.shard.0.len()
+        }
+
+        #[inline]
+        pub fn is_empty(&self) -> bool {
+            self.shard.0.is_empty()
+        }
+
+        #[inline]
+        pub fn insert(&mut self, value: T) -> bool {
+            self.shard.0.insert(value)
+        }
+
+        #[inline]
+        pub fn remove(&mut self, value: &T) -> bool {
+            self.shard.0.remove(value).is_some()
+        }
+
+        #[inline]
+        pub fn contains(&self, value: &T) -> bool {
+            self.shard.0.get_stored(value).is_some()
+        }
+
+        #[inline]
+        pub fn to_vec(&self) -> Vec<T>
+        where
+            T: Clone,
+        {
+            self.shard.0.iter().map(|value| (*value).clone()).collect()
+        }
+    }
+
+    impl<T: Ord + Eq + Clone> Iterator for ShardedBTreeIter<'_, T> {
+        type Item = T;
+
+        #[inline]
+        fn next(&mut self) -> Option<Self::Item> {
+            loop {
+                if let Some(value) = self.cur_shard.next() {
+                    return Some(value);
+                }
+
+                if self.next_shard == self.sharded.shard_count() {
+                    return None;
+                }
+
+                self.cur_shard = self
+                    .sharded
+                    .lock_shard(self.next_shard)
+                    .to_vec()
+                    .into_iter();
+                self.next_shard += 1;
+            }
+        }
+    }
+
+    #[cfg(feature = "rayon")]
+    impl<'a, T: Ord + Eq + Sync> SharedNode<'a, T> {
+        #[inline]
+        fn of(node: &'a Rc<RefCell<BTreeNode<T>>>) -> Self {
+            SharedNode(unsafe { node.try_borrow_unguarded() }.unwrap())
+        }
+
+        // Probes are sorted, so every child gets a contiguous slice of them
+        fn contains_sorted(self, probes: &[&T], found: &mut [bool]) {
+            match self.0 {
+                BTreeNode::SubTree { subtree } => {
+                    let mut partitions = Vec::with_capacity(subtree.children.len());
+                    let (mut probes, mut found) = (probes, found);
+
+                    for (child, key) in subtree.children.iter().zip(&subtree.mid_keys) {
+                        let split = probes.partition_point(|probe| **probe <= **key);
+                        let (child_probes, rest_probes) = probes.split_at(split);
+                        let (child_found, rest_found) = found.split_at_mut(split);
+                        partitions.push((SharedNode::of(child), child_probes, child_found));
+                        (probes, found) = (rest_probes, rest_found);
+                    }
+
+                    partitions.push((
+                        SharedNode::of(subtree.children.last().unwrap()),
+                        probes,
+                        found,
+                    ));
+
+                    partitions
+                        .into_par_iter()
+                        .filter(|(_, probes, _)| !probes.is_empty())
+                        .for_each(|(child, probes, found)| child.contains_sorted(probes, found));
+                }
+
+                BTreeNode::Leaf { leaf } => {
+                    let next_first = leaf
+                        .next_leaf
+                        .as_ref()
+                        .map(|next_leaf| unsafe {
+                            SharedNode::of(next_leaf).0.unwrap_as_leaf_unchecked()
+                        })
+                        .and_then(|next_leaf| next_leaf.values.first());
+
+                    probes.iter().zip(found).for_each(|(probe, is_found)| {
+                        let ind = leaf.values.partition_point(|v| **v < **probe);
+                        *is_found = leaf
+                            .values
+                            .get(ind)
+                            .or(next_first)
+                            .is_some_and(|v| **v == **probe);
+                    });
+                }
+            }
+        }
+    }
+
+    #[inline]
+    fn skip_equal<T: Ord, I: Iterator<Item = Rc<T>>>(iter: &mut Peekable<I>, value: &T) {
+        while iter.next_if(|next| **next == *value).is_some() {}
+    }
+
+    #[inline]
+    fn skip_less<T: Ord, I: Iterator<Item = Rc<T>>>(iter: &mut Peekable<I>, value: &T) {
+        while iter.next_if(|next| **next < *value).is_some() {}
+    }
+
+    impl<T: Ord, A: Iterator<Item = Rc<T>>, B: Iterator<Item = Rc<T>>> BTreeUnion<T, A, B> {
+        #[inline]
+        pub fn new(a: A, b: B) -> Self {
+            Self {
+                a: a.peekable(),
+                b: b.peekable(),
+            }
+        }
+    }
+
+    impl<T: Ord, A: Iterator<Item = Rc<T>>, B: Iterator<Item = Rc<T>>> Iterator
+        for BTreeUnion<T, A, B>
+    {
+        type Item = Rc<T>;
+
+        fn next(&mut self) -> Option<Self::Item> {
+            let value = match (self.a.peek(), self.b.peek()) {
+                (Some(a), Some(b)) if **b < **a => self.b.next()?,
+                (Some(_), _) => self.a.next()?,
+                (None, _) => self.b.next()?,
+            };
+
+            skip_equal(&mut self.a, &value);
+            skip_equal(&mut self.b, &value);
+            Some(value)
+        }
+    }
+
+    impl<T: Ord, A: Iterator<Item = Rc<T>>, B: Iterator<Item = Rc<T>>> BTreeIntersection<T, A, B> {
+        #[inline]
+        pub fn new(a: A, b: B) -> Self {
+            Self {
+                a: a.peekable(),
+                b: b.peekable(),
+            }
+        }
+    }
+
+    impl<T: Ord, A: Iterator<Item = Rc<T>>, B: Iterator<Item = Rc<T>>> Iterator
+        for BTreeIntersection<T, A, B>
+    {
+        type Item = Rc<T>;
+
+        fn next(&mut self) -> Option<Self::Item> {
+            loop {
+                let value = self.a.next()?;
+                skip_equal(&mut self.a, &value);
+                skip_less(&mut self.b, &value);
+
+                if self.b.peek()? == &value {
+                    skip_equal(&mut self.b, &value);
+                    return Some(value);
+                }
+            }
+        }
+    }
+
+    impl<T: Ord, A: Iterator<Item = Rc<T>>, B: Iterator<Item = Rc<T>>> BTreeDifference<T, A, B> {
+        #[inline]
+        pub fn new(a: A, b: B) -> Self {
+            Self {
+                a: a.peekable(),
+                b: b.peekable(),
+            }
+        }
+    }
+
+    impl<T: Ord, A: Iterator<Item = Rc<T>>, B: Iterator<Item = Rc<T>>> Iterator
+        for BTreeDifference<T, A, B>
+    {
+        type Item = Rc<T>;
+
+        fn next(&mut self) -> Option<Self::Item> {
+            loop {
+                let value = self.a.next()?;
+                skip_equal(&mut self.a, &value);
+                skip_less(&mut self.b, &value);
+
+                if self.b.next_if(|b| **b == *value).is_none() {
+                    return Some(value);
+                }
+
+                skip_equal(&mut self.b, &value);
+            }
+        }
+    }
+
+    impl<T: Ord, A: Iterator<Item = Rc<T>>, B: Iterator<Item = Rc<T>>>
+        BTreeSymmetricDifference<T, A, B>
+    {
+        #[inline]
+        pub fn new(a: A, b: B) -> Self {
+            Self {
+                a: a.peekable(),
+                b: b.peekable(),
+            }
+        }
+    }
+
+    impl<T: Ord, A: Iterator<Item = Rc<T>>, B: Iterator<Item = Rc<T>>> Iterator
+        for BTreeSymmetricDifference<T, A, B>
+    {
+        type Item = Rc<T>;
+
+        fn next(&mut self) -> Option<Self::Item> {
+            loop {
+                let (value, is_shared) = match (self.a.peek(), self.b.peek()) {
+                    (Some(a), Some(b)) => match (**a).cmp(&**b) {
+                        Ordering::Less => (self.a.next()?, false),
+                        Ordering::Greater => (self.b.next()?, false),
+                        Ordering::Equal => (self.a.next()?, true),
+                    },
+
+                    (Some(_), None) => (self.a.next()?, false),
+                    (None, _) => (self.b.next()?, false),
+                };
+
+                skip_equal(&mut self.a, &value);
+                skip_equal(&mut self.b, &value);
+
+                if !is_shared {
+                    return Some(value);
+                }
+            }
+        }
+    }
+
+    impl<T: Ord + Eq> BTreeDiff<T> {
+        #[inline]
+        fn new(old: BTreeIter<T>, new: BTreeIter<T>) -> Self {
+            Self {
+                old: old.peekable(),
+                new: new.peekable(),
+            }
+        }
+    }
+
+    impl<T: Ord + Eq> Iterator for BTreeDiff<T> {
+        type Item = BTreeChange<T>;
+
+        fn next(&mut self) -> Option<Self::Item> {
+            loop {
+                match (self.old.peek(), self.new.peek()) {
+                    (Some(old), Some(new)) => match (**old).cmp(&**new) {
+                        Ordering::Less => return self.old.next().map(BTreeChange::Removed),
+                        Ordering::Greater => return self.new.next().map(BTreeChange::Added),
+
+                        Ordering::Equal => {
+                            self.old.next();
+                            self.new.next();
+                        }
+                    },
+
+                    (Some(_), None) => return self.old.next().map(BTreeChange::Removed),
+                    (None, _) => return self.new.next().map(BTreeChange::Added),
+                }
+            }
+        }
+    }
+
+    impl<'a, T: Ord + Eq> UnionView<'a, T> {
+        #[inline]
+        pub fn new(a: &'a BTree<T>, b: &'a BTree<T>) -> Self {
+            Self { a, b }
+        }
+
+        #[inline]
+        pub fn contains(&self, value: &T) -> bool {
+            self.a.contains(value) || self.b.contains(value)
+        }
+
+        #[inline]
+        pub fn iter(&self) -> BTreeUnion<T> {
+            BTreeUnion::new(self.a.iter(), self.b.iter())
+        }
+
+        #[inline]
+        pub fn len(&self) -> usize {
+            self.iter().count()
+        }
+
+        #[inline]
+        pub fn is_empty(&self) -> bool {
+            self.a.is_empty() && self.b.is_empty()
+        }
+    }
+
+    impl<'a, T: Ord + Eq> IntersectionView<'a, T> {
+        #[inline]
+        pub fn new(a: &'a BTree<T>, b: &'a BTree<T>) -> Self {
+            Self { a, b }
+        }
+
+        #[inline]
+        pub fn contains(&self, value: &T) -> bool {
+            self.a.contains(value) && self.b.contains(value)
+        }
+
+        #[inline]
+        pub fn iter(&self) -> BTreeIntersection<T> {
+            BTreeIntersection::new(self.a.iter(), self.b.iter())
+        }
+
+        #[inline]
+        pub fn len(&self) -> usize {
+            self.iter().count()
+        }
+
+        #[inline]
+        pub fn is_empty(&self) -> bool {
+            self.iter().next().is_none()
+        }
+    }
+
+    impl<'a, T: Ord + Eq> DifferenceView<'a, T> {
+        #[inline]
+        pub fn new(a: &'a BTree<T>, b: &'a BTree<T>) -> Self {
+            Self { a, b }
+        }
+
+        #[inline]
+        pub fn contains(&self, value: &T) -> bool {
+            self.a.contains(value) && !self.b.contains(value)
+        }
+
+        #[inline]
+        pub fn iter(&self) -> BTreeDifference<T> {
+            BTreeDifference::new(self.a.iter(), self.b.iter())
+        }
+
+        #[inline]
+        pub fn len(&self) -> usize {
+            self.iter().count()
+        }
+
+        #[inline]
+        pub fn is_empty(&self) -> bool {
+            self.iter().next().is_none()
+        }
+    }
+
+    impl<T: Clone> BTreeSeq<T> {
+        #[inline]
+        pub const fn new() -> Self {
+            Self { tree: BTree::new() }
+        }
+
+        #[inline]
+        pub fn len(&self) -> usize {
+            self.tree.len()
+        }
+
+        #[inline]
+        pub fn is_empty(&self) -> bool {
+            self.tree.is_empty()
+        }
+
+        #[inline]
+        pub fn is_not_empty(&self) -> bool {
+            self.tree.is_not_empty()
+        }
+
+        #[inline]
+        pub fn get(&self, index: usize) -> Option<&T> {
+            self.tree
+                .get(index)
+                .map(|item| unsafe { &self.tree.stored_ref(&item).0 })
+        }
+
+        #[inline]
+        pub fn first(&self) -> Option<&T> {
+            self.get(0)
+        }
+
+        #[inline]
+        pub fn last(&self) -> Option<&T> {
+            self.len().checked_sub(1).and_then(|index| self.get(index))
+        }
+
+        pub fn insert_at(&mut self, index: usize, value: T) {
+            let len = self.len();
+            assert!(
+                index <= len,
+                "insertion index (is {index}) should be <= len (is {len})"
+            );
+
+            self.tree.insert_at_rank(index, SeqItem(value))
+        }
+
+        #[inline]
+        pub fn push(&mut self, value: T) {
+            self.insert_at(self.len(), value)
+        }
+
+        pub fn remove_at(&mut self, index: usize) -> T {
+            let len = self.len();
+            assert!(
+                index < len,
+                "removal index (is {index}) should be < len (is {len})"
+            );
+
+            let item = self.tree.remove_at(index).unwrap();
+            Rc::try_unwrap(item).map_or_else(|item| item.0.clone(), |item| item.0)
+        }
+
+        #[inline]
+        pub fn iter(&self) -> BTreeSeqIter<'_, T> {
+            BTreeSeqIter {
+                seq: self,
+                iter: self.tree.iter(),
+            }
+        }
+    }
+
+    impl<T: Clone> Default for BTreeSeq<T> {
+        #[inline]
+        fn default() -> Self {
+            Self::new()
+        }
+    }
+
+    impl<'a, T: Clone> Iterator for BTreeSeqIter<'a, T> {
+        type Item = &'a T;
+
+        #[inline]
+        fn next(&mut self) -> Option<Self::Item> {
+            let seq = self.seq;
+
+            self.iter
+                .next()
+                .map(|item| unsafe { &seq.tree.stored_ref(&item).0 })
+        }
+    }
+
+    impl<T: Clone> FromIterator<T> for BTreeSeq<T> {
+        #[inline]
+        fn from_iter<I: IntoIterator<Item = T>>(iter: I) -> Self {
+            Self {
+                tree: BTree::from_sorted_rcs(iter.into_iter().map(|x| Rc::new(SeqItem(x)))),
+            }
+        }
+    }
+
+    impl<T> BTreeTrace<T> {
+        #[inline]
+        pub const fn new() -> Self {
+            Self { ops: vec![] }
+        }
+
+        #[inline]
+        pub fn ops(&self) -> &[BTreeOp<T>] {
+            &self.ops
+        }
+
+        #[inline]
+        pub fn len(&self) -> usize {
+            self.ops.len()
+        }
+
+        #[inline]
+        pub fn is_empty(&self) -> bool {
+            self.ops.is_empty()
+        }
+
+        #[inline]
+        pub fn push(&mut self, op: BTreeOp<T>) {
+            self.ops.push(op)
+        }
+    }
+
+    impl<T> Default for BTreeTrace<T> {
+        #[inline]
+        fn default() -> Self {
+            Self::new()
+        }
+    }
+
+    impl<T: Ord + Eq + Clone> RecordingBTree<T> {
+        #[inline]
+        pub const fn new() -> Self {
+            Self {
+                tree: BTree::new(),
+                trace: BTreeTrace::new(),
+            }
+        }
+
+        #[inline]
+        pub fn tree(&self) -> &BTree<T> {
+            &self.tree
+        }
+
+        #[inline]
+        pub fn trace(&self) -> &BTreeTrace<T> {
+            &self.trace
+        }
+
+        #[inline]
+        pub fn into_parts(self) -> (BTree<T>, BTreeTrace<T>) {
+            (self.tree, self.trace)
+        }
+
+        #[inline]
+        pub fn insert(&mut self, value: T) -> bool {
+            self.trace.push(BTreeOp::Insert(value.clone()));
+            self.tree.insert(value)
+        }
+
+        #[inline]
+        pub fn remove(&mut self, value: &T) -> Option<Rc<T>> {
+            self.trace.push(BTreeOp::Remove(value.clone()));
+            self.tree.remove(value)
+        }
+
+        #[inline]
+        pub fn push_max(&mut self, value: T) -> bool {
+            self.trace.push(BTreeOp::PushMax(value.clone()));
+            self.tree.push_max(value)
+        }
+
+        #[inline]
+        pub fn take_first_n(&mut self, n: usize) -> BTree<T> {
+            self.trace.push(BTreeOp::TakeFirstN(n));
+            self.tree.take_first_n(n)
+        }
+
+        #[inline]
+        pub fn take_last_n(&mut self, n: usize) -> BTree<T> {
+            self.trace.push(BTreeOp::TakeLastN(n));
+            self.tree.take_last_n(n)
+        }
+
+        #[inline]
+        pub fn pop_first(&mut self) -> Option<Rc<T>> {
+            self.trace.push(BTreeOp::PopFirst);
+            self.tree.pop_first()
+        }
+
+        #[inline]
+        pub fn pop_last(&mut self) -> Option<Rc<T>> {
+            self.trace.push(BTreeOp::PopLast);
+            self.tree.pop_last()
+        }
+
+        #[inline]
+        pub fn remove_at(&mut self, index: usize) -> Option<Rc<T>> {
+            self.trace.push(BTreeOp::RemoveAt(index));
+            self.tree.remove_at(index)
+        }
+
+        #[inline]
+        pub fn retain(&mut self, mut f: impl FnMut(&T) -> bool) {
+            self.extract_if(|value| !f(value));
+        }
+
+        pub fn extract_if(&mut self, mut pred: impl FnMut(&T) -> bool) -> Vec<Rc<T>> {
+            let mut ranks = vec![];
+            let mut rank = 0;
+
+            let extracted = self
+                .tree
+                .extract_if(|value| {
+                    let is_extracted = pred(value);
+
+                    if is_extracted {
+                        ranks.push(rank);
+                    }
+
+                    rank += 1;
+                    is_extracted
+                })
+                .collect();
+
+            self.trace.push(BTreeOp::RemoveRanks(ranks));
+            extracted
+        }
+
+        #[inline]
+        pub fn drain(&mut self) -> BTreeDrain<T> {
+            self.trace.push(BTreeOp::Clear);
+            self.tree.drain()
+        }
+
+        #[inline]
+        pub fn clear(&mut self) {
+            self.trace.push(BTreeOp::Clear);
+            self.tree.clear()
+        }
+
+        #[inline]
+        pub fn remove_range<R: RangeBounds<T>>(&mut self, range: R) -> usize {
+            self.trace.push(BTreeOp::RemoveRange(
+                range.start_bound().cloned(),
+                range.end_bound().cloned(),
+            ));
+            self.tree.remove_range(range)
+        }
+
+        #[inline]
+        pub fn split_off(&mut self, key: &T) -> BTree<T> {
+            self.trace.push(BTreeOp::SplitOff(key.clone()));
+            self.tree.split_off(key)
+        }
+
+        #[inline]
+        pub fn append(&mut self, other: &mut BTree<T>) {
+            self.trace.push(BTreeOp::Append(
+                other.iter().map(|value| (*value).clone()).collect(),
+            ));
+            self.tree.append(other)
+        }
+
+        #[inline]
+        pub fn truncate(&mut self, len: usize) {
+            self.trace.push(BTreeOp::Truncate(len));
+            self.tree.truncate(len)
+        }
+
+        #[inline]
+        pub fn dedup(&mut self) {
+            self.trace.push(BTreeOp::Dedup);
+            self.tree.dedup()
+        }
+
+        // A patch is recorded as the inserts and removals it is equivalent to
+        pub fn apply_patch(&mut self, changes: impl IntoIterator<Item = BTreeChange<T>>) {
+            let changes = changes.into_iter().collect::<Vec<_>>();
+
+            self.trace
+                .ops
+                .extend(changes.iter().map(|change| match change {
+                    BTreeChange::Added(value) => BTreeOp::Insert((**value).clone()),
+                    BTreeChange::Removed(value) => BTreeOp::Remove((**value).clone()),
+                }));
+
+            self.tree.apply_patch(changes)
+        }
+    }
+
+    impl<T: Ord + Eq + Clone> Default for RecordingBTree<T> {
+        #[inline]
+        fn default() -> Self {
+            Self::new()
+        }
+    }
+
+    impl<T> BTreeAugment<MultiSetRun<T>> for RunTotal {
+        type Summary = usize;
+
+        #[inline]
+        fn identity() -> usize {
+            0
+        }
+
+        #[inline]
+        fn summarize(run: &MultiSetRun<T>) -> usize {
+            run.count.get()
+        }
+
+        #[inline]
+        fn combine(left: &usize, right: &usize) -> usize {
+            left + right
+        }
+    }
+
+    impl<T: Ord + Eq> BTreeMultiSet<T> {
+        #[inline]
+        pub const fn new() -> Self {
+            Self {
+                runs: BTree::augmented(),
+                len: 0,
+            }
+        }
+
+        #[inline]
+        pub fn len(&self) -> usize {
+            self.len
+        }
+
+        #[inline]
+        pub fn is_empty(&self) -> bool {
+            self.len == 0
+        }
+
+        #[inline]
+        pub fn is_not_empty(&self) -> bool {
+            !self.is_empty()
+        }
+
+        #[inline]
+        pub fn distinct_len(&self) -> usize {
+            self.runs.len()
+        }
+
+        fn run_position(&self, value: &T) -> Option<BTreePosition<MultiSetRun<T>, RunTotal>> {
+            self.runs
+                .root
+                .as_ref()
+                .and_then(|root| {
+                    BTreeNode::partition_point(root, &|run: &MultiSetRun<T>| run.value < *value)
+                })
+                .filter(|(leaf, ind)| BTreeNode::value_at(leaf, *ind).value == *value)
+        }
+
+        // Counts change in place, so the totals above the run are refolded by hand
+        fn set_run_count((leaf, ind): BTreePosition<MultiSetRun<T>, RunTotal>, count: usize) {
+            BTreeNode::value_at(&leaf, ind).count.set(count);
+            BTreeNode::resummarize_ancestors(&leaf);
+        }
+
+        pub fn insert(&mut self, value: T) {
+            match self.run_position(&value) {
+                Some((leaf, ind)) => {
+                    let count = BTreeNode::value_at(&leaf, ind).count.get();
+                    Self::set_run_count((leaf, ind), count + 1);
+                }
+
+                None => {
+                    self.runs.insert(MultiSetRun {
+                        value,
+                        count: Cell::new(1),
+                    });
+                }
+            }
+
+            self.len += 1;
+        }
+
+        pub fn remove_one(&mut self, value: &T) -> bool {
+            let (leaf, ind) = match self.run_position(value) {
+                Some(position) => position,
+                None => return false,
+            };
+
+            let count = BTreeNode::value_at(&leaf, ind).count.get();
+
+            match count {
+                1 => {
+                    self.runs.remove_at_position(leaf, ind);
+                }
+
+                _ => Self::set_run_count((leaf, ind), count - 1),
+            }
+
+            self.len -= 1;
+            true
+        }
+
+        pub fn remove_all(&mut self, value: &T) -> usize {
+            let count = self
+                .runs
+                .remove(value)
+                .map(|run| run.count.get())
+                .unwrap_or_default();
+
+            self.len -= count;
+            count
+        }
+
+        #[inline]
+        pub fn count(&self, value: &T) -> usize {
+            self.run_position(value)
+                .map(|(leaf, ind)| BTreeNode::value_at(&leaf, ind).count.get())
+                .unwrap_or_default()
+        }
+
+        #[inline]
+        pub fn contains(&self, value: &T) -> bool {
+            self.runs.contains(value)
+        }
+
+        // Descends by the occurrence totals of subtrees instead of walking every run
+        pub fn get(&self, index: usize) -> Option<&T> {
+            let mut index = index;
+            let mut node = self.runs.root.clone()?;
+
+            let mut skip = |total: usize| match index < total {
+                true => false,
+
+                false => {
+                    index -= total;
+                    true
+                }
+            };
+
+            loop {
+                let child = match &*node.borrow() {
+                    BTreeNode::Leaf { leaf } => {
+                        return leaf
+                            .values
+                            .iter()
+                            .find(|run| !skip(run.count.get()))
+                            .map(|run| unsafe { &self.runs.stored_ref(run).value });
+                    }
+
+                    BTreeNode::SubTree { subtree } => subtree
+                        .children
+                        .iter()
+                        .find(|child| !skip(BTreeNode::summary(child)))?
+                        .clone(),
+                };
+
+                node = child;
+            }
+        }
+
+        #[inline]
+        pub fn iter(&self) -> BTreeMultiSetIter<'_, T> {
+            BTreeMultiSetIter {
+                set: self,
+                runs: self.runs.iter(),
+                cur_run: None,
+            }
+        }
+
+        #[inline]
+        pub fn iter_counts(&self) -> impl Iterator<Item = (&T, usize)> + '_ {
+            self.runs.iter().map(|run| {
+                let count = run.count.get();
+                (unsafe { &self.runs.stored_ref(&run).value }, count)
+            })
+        }
+    }
+
+    impl<T: Ord + Eq> Default for BTreeMultiSet<T> {
+        #[inline]
+        fn default() -> Self {
+            Self::new()
+        }
+    }
+
+    impl<'a, T: Ord + Eq> Iterator for BTreeMultiSetIter<'a, T> {
+        type Item = &'a T;
+
+        fn next(&mut self) -> Option<Self::Item> {
+            if let Some((value, remaining)) = &mut self.cur_run {
+                if *remaining > 0 {
+                    *remaining -= 1;
+                    return Some(*value);
+                }
+            }
+
+            let set = self.set;
+            let run = self.runs.next()?;
+            let value = unsafe { &set.runs.stored_ref(&run).value };
+            self.cur_run = Some((value, run.count.get() - 1));
+            Some(value)
+        }
+    }
+
+    impl<T: Ord + Eq> Extend<T> for BTreeMultiSet<T> {
+        #[inline]
+        fn extend<I: IntoIterator<Item = T>>(&mut self, iter: I) {
+            iter.into_iter().for_each(|x| self.insert(x));
+        }
+    }
+
+    impl<T: Ord + Eq> FromIterator<T> for BTreeMultiSet<T> {
+        #[inline]
+        fn from_iter<I: IntoIterator<Item = T>>(iter: I) -> Self {
+            let mut set = BTreeMultiSet::new();
+            set.extend(iter);
+            set
+        }
+    }
+
+    impl<K: Ord, V> BTreeMap23<K, V> {
+        #[inline]
+        pub const fn new() -> Self {
+            Self {
+                entries: BTree::new(),
+            }
+        }
+
+        #[inline]
+        pub fn len(&self) -> usize {
+            self.entries.len()
+        }
+
+        #[inline]
+        pub fn is_empty(&self) -> bool {
+            self.entries.is_empty()
+        }
+
+        #[inline]
+        pub fn is_not_empty(&self) -> bool {
+            !self.is_empty()
+        }
+
+        fn entry_position<Q: Ord + ?Sized>(&self, key: &Q) -> Option<BTreePosition<MapEntry<K, V>>>
+        where
+            K: std::borrow::Borrow<Q>,
+        {
+            self.entries
+                .root
+                .as_ref()
+                .and_then(|root| {
+                    BTreeNode::partition_point(root, &|entry: &MapEntry<K, V>| {
+                        entry.key.borrow() < key
+                    })
+                })
+                .filter(|(leaf, ind)| BTreeNode::value_at(leaf, *ind).key.borrow() == key)
+        }
+
+        #[inline]
+        fn stored_entry<Q: Ord + ?Sized>(&self, key: &Q) -> Option<&MapEntry<K, V>>
+        where
+            K: std::borrow::Borrow<Q>,
+        {
+            self.entry_position(key).map(|(leaf, ind)| unsafe {
+                self.entries.stored_ref(&BTreeNode::value_at(&leaf, ind))
+            })
+        }
+
+        pub fn insert(&mut self, key: K, value: V) -> Option<V> {
+            match self.stored_entry(&key) {
+                Some(entry) => Some(entry.value.replace(value)),
+
+                None => {
+                    self.entries.insert(MapEntry::new(key, value));
+
+                    None
+                }
+            }
+        }
+
+        #[inline]
+        pub fn get<Q: Ord + ?Sized>(&self, key: &Q) -> Option<&V>
+        where
+            K: std::borrow::Borrow<Q>,
+        {
+            // Values are only mutated through `&mut self`
+            self.stored_entry(key)
+                .map(|entry| unsafe { &*entry.value.as_ptr() })
+        }
+
+        #[inline]
+        pub fn get_key_value<Q: Ord + ?Sized>(&self, key: &Q) -> Option<(&K, &V)>
+        where
+            K: std::borrow::Borrow<Q>,
+        {
+            self.stored_entry(key)
+                .map(|entry| (&entry.key, unsafe { &*entry.value.as_ptr() }))
+        }
+
+        pub fn entry_ref<'a, 'b, Q: Ord + ?Sized>(
+            &'a mut self,
+            key: &'b Q,
+        ) -> BTreeMap23EntryRef<'a, 'b, K, V, Q>
+        where
+            K: std::borrow::Borrow<Q>,
+        {
+            match self.entry_position(key) {
+                Some((leaf, ind)) => {
+                    BTreeMap23EntryRef::Occupied(BTreeMap23OccupiedEntry::new(self, leaf, ind))
+                }
+
+                None => BTreeMap23EntryRef::Vacant(BTreeMap23VacantEntryRef { map: self, key }),
+            }
+        }
+
+        #[inline]
+        pub fn get_mut<Q: Ord + ?Sized>(&mut self, key: &Q) -> Option<&mut V>
+        where
+            K: std::borrow::Borrow<Q>,
+        {
+            self.stored_entry(key)
+                .map(|entry| unsafe { &mut *entry.value.as_ptr() })
+        }
+
+        pub fn get_disjoint_mut<Q: Ord + ?Sized, const N: usize>(
+            &mut self,
+            keys: [&Q; N],
+        ) -> [Option<&mut V>; N]
+        where
+            K: std::borrow::Borrow<Q>,
+        {
+            let entries = keys.map(|key| {
+                self.entry_position(key)
+                    .map(|(leaf, ind)| BTreeNode::value_at(&leaf, ind))
+            });
+
+            // Keys are compared by the entries they resolve to, so `Q`'s own
+            // equality cannot hand out two references to the same value
+            entries.iter().enumerate().for_each(|(i, entry)| {
+                if let Some(entry) = entry {
+                    assert!(
+                        entries[..i]
+                            .iter()
+                            .flatten()
+                            .all(|prev| !Rc::ptr_eq(prev, entry)),
+                        "keys should be disjoint"
+                    );
+                }
+            });
+
+            entries.map(|entry| {
+                entry.map(|entry| unsafe { &mut *self.entries.stored_ref(&entry).value.as_ptr() })
+            })
+        }
+
+        #[inline]
+        pub fn contains_key<Q: Ord + ?Sized>(&self, key: &Q) -> bool
+        where
+            K: std::borrow::Borrow<Q>,
+        {
+            self.entry_position(key).is_some()
+        }
+
+        #[inline]
+        pub fn remove<Q: Ord + ?Sized>(&mut self, key: &Q) -> Option<V>
+        where
+            K: std::borrow::Borrow<Q>,
+        {
+            let (leaf, ind) = self.entry_position(key)?;
+            Some(self.take_entry(leaf, ind).1)
+        }
+
+        fn take_entry(
+            &mut self,
+            leaf: Rc<RefCell<BTreeNode<MapEntry<K, V>>>>,
+            ind: usize,
+        ) -> (K, V) {
+            MapEntry::into_pair(self.entries.remove_at_position(leaf, ind))
+        }
+
+        pub fn map_values<U>(self, mut f: impl FnMut(&K, V) -> U) -> BTreeMap23<K, U> {
+            BTreeMap23 {
+                entries: self.entries.map_shape(|entry| {
+                    let (key, value) = MapEntry::into_pair(entry);
+                    let value = f(&key, value);
+                    Rc::new(MapEntry::new(key, value))
+                }),
+            }
+        }
+
+        pub fn merge_with(&mut self, mut other: Self, mut f: impl FnMut(&K, V, V) -> V) {
+            let mut merged = Vec::with_capacity(self.len() + other.len());
+            let mut entries = self.entries.take_values().into_iter().peekable();
+            let mut other_entries = other.entries.take_values().into_iter().peekable();
+
+            while let (Some(entry), Some(other_entry)) = (entries.peek(), other_entries.peek()) {
+                match entry.key.cmp(&other_entry.key) {
+                    Ordering::Less => merged.extend(entries.next()),
+                    Ordering::Greater => merged.extend(other_entries.next()),
+
+                    Ordering::Equal => {
+                        let (key, value) = MapEntry::into_pair(entries.next().unwrap());
+                        let (_, other_value) = MapEntry::into_pair(other_entries.next().unwrap());
+                        let value = f(&key, value, other_value);
+                        merged.push(Rc::new(MapEntry::new(key, value)));
+                    }
+                }
+            }
+
+            merged.extend(entries);
+            merged.extend(other_entries);
+            self.entries.root = BTree::from_sorted_rcs(merged).root;
+        }
+
+        #[inline]
+        pub fn first_entry(&mut self) -> Option<BTreeMap23OccupiedEntry<'_, K, V>> {
+            let leaf = BTreeNode::first_leaf(self.entries.root.as_ref()?);
+            Some(BTreeMap23OccupiedEntry::new(self, leaf, 0))
+        }
+
+        #[inline]
+        pub fn last_entry(&mut self) -> Option<BTreeMap23OccupiedEntry<'_, K, V>> {
+            let (leaf, ind) = self.entries.last_position()?;
+            Some(BTreeMap23OccupiedEntry::new(self, leaf, ind))
+        }
+
+        #[inline]
+        pub fn pop_first(&mut self) -> Option<(K, V)> {
+            self.first_entry()
+                .map(BTreeMap23OccupiedEntry::remove_entry)
+        }
+
+        #[inline]
+        pub fn pop_last(&mut self) -> Option<(K, V)> {
+            self.last_entry().map(BTreeMap23OccupiedEntry::remove_entry)
+        }
+
+        #[inline]
+        pub fn iter(&self) -> BTreeMap23Iter<'_, K, V> {
+            BTreeMap23Iter {
+                map: self,
+                entries: self.entries.iter(),
+            }
+        }
+
+        #[inline]
+        pub fn keys(&self) -> BTreeMap23Keys<'_, K, V> {
+            BTreeMap23Keys { iter: self.iter() }
+        }
+
+        #[inline]
+        pub fn values(&self) -> BTreeMap23Values<'_, K, V> {
+            BTreeMap23Values { iter: self.iter() }
+        }
+
+        #[inline]
+        pub fn values_mut(&mut self) -> BTreeMap23ValuesMut<'_, K, V> {
+            BTreeMap23ValuesMut {
+                entries: self.entries.iter(),
+                map: std::marker::PhantomData,
+            }
+        }
+    }
+
+    impl<K: Ord, V> Default for BTreeMap23<K, V> {
+        #[inline]
+        fn default() -> Self {
+            Self::new()
+        }
+    }
+
+    impl<'a, K: Ord, V> Iterator for BTreeMap23Iter<'a, K, V> {
+        type Item = (&'a K, &'a V);
+
+        #[inline]
+        fn next(&mut self) -> Option<Self::Item> {
+            let map = self.map;
+            let entry = unsafe { map.entries.stored_ref(&self.entries.next()?) };
+            Some((&entry.key, unsafe { &*entry.value.as_ptr() }))
+        }
+    }
+
+    impl<'a, K: Ord, V> BTreeMap23OccupiedEntry<'a, K, V> {
+        #[inline]
+        fn new(
+            map: &'a mut BTreeMap23<K, V>,
+            leaf: Rc<RefCell<BTreeNode<MapEntry<K, V>>>>,
+            ind: usize,
+        ) -> Self {
+            Self { map, leaf, ind }
+        }
+
+        #[inline]
+        fn entry(&self) -> &'a MapEntry<K, V> {
+            // The entry stays in the tree while the map is mutably borrowed
+            unsafe { &*Rc::as_ptr(&BTreeNode::value_at(&self.leaf, self.ind)) }
+        }
+
+        #[inline]
+        pub fn key(&self) -> &K {
+            &self.entry().key
+        }
+
+        #[inline]
+        pub fn get(&self) -> &V {
+            unsafe { &*self.entry().value.as_ptr() }
+        }
+
+        #[inline]
+        pub fn get_mut(&mut self) -> &mut V {
+            unsafe { &mut *self.entry().value.as_ptr() }
+        }
+
+        #[inline]
+        pub fn into_mut(self) -> &'a mut V {
+            unsafe { &mut *self.entry().value.as_ptr() }
+        }
+
+        #[inline]
+        pub fn insert(&mut self, value: V) -> V {
+            std::mem::replace(self.get_mut(), value)
+        }
+
+        #[inline]
+        pub fn remove_entry(self) -> (K, V) {
+            self.map.take_entry(self.leaf, self.ind)
+        }
+
+        #[inline]
+        pub fn remove(self) -> V {
+            self.remove_entry().1
+        }
+    }
+
+    impl<'a, 'b, K, V, Q> BTreeMap23VacantEntryRef<'a, 'b, K, V, Q>
+    where
+        K: Ord + std::borrow::Borrow<Q> + From<&'b Q>,
+        Q: Ord + ?Sized,
+    {
+        #[inline]
+        pub fn key(&self) -> &'b Q {
+            self.key
+        }
+
+        pub fn insert(self, value: V) -> &'a mut V {
+            let key = self.key;
+
+            let entry = self.map.entries.get_or_insert_by(
+                |entry| entry.key.borrow().cmp(key),
+                || MapEntry::new(K::from(key), value),
+            );
+
+            // The entry stays in the tree while the map is mutably borrowed
+            unsafe { &mut *entry.value.as_ptr() }
+        }
+    }
+
+    impl<'a, 'b, K, V, Q> BTreeMap23EntryRef<'a, 'b, K, V, Q>
+    where
+        K: Ord + std::borrow::Borrow<Q> + From<&'b Q>,
+        Q: Ord + ?Sized,
+    {
+        #[inline]
+        pub fn or_insert(self, default: V) -> &'a mut V {
+            self.or_insert_with(|| default)
+        }
+
+        #[inline]
+        pub fn or_insert_with(self, default: impl FnOnce() -> V) -> &'a mut V {
+            match self {
+                BTreeMap23EntryRef::Occupied(entry) => entry.into_mut(),
+                BTreeMap23EntryRef::Vacant(entry) => entry.insert(default()),
+            }
+        }
+
+        #[inline]
+        pub fn or_default(self) -> &'a mut V
+        where
+            V: Default,
+        {
+            self.or_insert_with(V::default)
+        }
+
+        #[inline]
+        pub fn and_modify(mut self, f: impl FnOnce(&mut V)) -> Self {
+            if let BTreeMap23EntryRef::Occupied(entry) = &mut self {
+                f(entry.get_mut());
+            }
+
+            self
+        }
+    }
+
+    impl<'a, K: Ord, V> Iterator for BTreeMap23Keys<'a, K, V> {
+        type Item = &'a K;
+
+        #[inline]
+        fn next(&mut self) -> Option<Self::Item> {
+            self.iter.next().map(|(key, _)| key)
+        }
+    }
+
+    impl<'a, K: Ord, V> Iterator for BTreeMap23Values<'a, K, V> {
+        type Item = &'a V;
+
+        #[inline]
+        fn next(&mut self) -> Option<Self::Item> {
+            self.iter.next().map(|(_, value)| value)
+        }
+    }
+
+    impl<'a, K: Ord, V> Iterator for BTreeMap23ValuesMut<'a, K, V> {
+        type Item = &'a mut V;
+
+        #[inline]
+        fn next(&mut self) -> Option<Self::Item> {
+            // Every entry is yielded once while the map is mutably borrowed
+            self.entries
+                .next()
+                .map(|entry| unsafe { &mut *entry.value.as_ptr() })
+        }
+    }
+
+    impl<K: Ord, V> Extend<(K, V)> for BTreeMap23<K, V> {
+        #[inline]
+        fn extend<I: IntoIterator<Item = (K, V)>>(&mut self, iter: I) {
+            iter.into_iter().for_each(|(key, value)| {
+                self.insert(key, value);
+            });
+        }
+    }
+
+    impl<K: Ord, V> FromIterator<(K, V)> for BTreeMap23<K, V> {
+        #[inline]
+        fn from_iter<I: IntoIterator<Item = (K, V)>>(iter: I) -> Self {
+            let mut map = BTreeMap23::new();
+            map.extend(iter);
+            map
+        }
+    }
+
+    impl<K: Ord, V> BTreeMultiMap<K, V> {
+        #[inline]
+        pub const fn new() -> Self {
+            Self {
+                groups: BTreeMap23::new(),
+                len: 0,
+            }
+        }
+
+        #[inline]
+        pub fn len(&self) -> usize {
+            self.len
+        }
+
+        #[inline]
+        pub fn is_empty(&self) -> bool {
+            self.len == 0
+        }
+
+        #[inline]
+        pub fn is_not_empty(&self) -> bool {
+            !self.is_empty()
+        }
+
+        #[inline]
+        pub fn keys_len(&self) -> usize {
+            self.groups.len()
+        }
+
+        pub fn insert(&mut self, key: K, value: V) {
+            match self.groups.get_mut(&key) {
+                Some(group) => group.push(value),
+
+                None => {
+                    self.groups.insert(key, vec![value]);
+                }
+            }
+
+            self.len += 1;
+        }
+
+        #[inline]
+        pub fn get_all<Q: Ord + ?Sized>(&self, key: &Q) -> &[V]
+        where
+            K: std::borrow::Borrow<Q>,
+        {
+            self.groups.get(key).map(Vec::as_slice).unwrap_or_default()
+        }
+
+        #[inline]
+        pub fn contains_key<Q: Ord + ?Sized>(&self, key: &Q) -> bool
+        where
+            K: std::borrow::Borrow<Q>,
+        {
+            self.groups.contains_key(key)
+        }
+
+        pub fn remove<Q: Ord + ?Sized>(&mut self, key: &Q, value: &V) -> bool
+        where
+            K: std::borrow::Borrow<Q>,
+            V: PartialEq,
+        {
+            let group = match self.groups.get_mut(key) {
+                Some(group) => group,
+                None => return false,
+            };
+
+            let ind = match group.iter().position(|stored| stored == value) {
+                Some(ind) => ind,
+                None => return false,
+            };
+
+            group.remove(ind);
+
+            if group.is_empty() {
+                self.groups.remove(key);
+            }
+
+            self.len -= 1;
+            true
+        }
+
+        #[inline]
+        pub fn remove_all<Q: Ord + ?Sized>(&mut self, key: &Q) -> Vec<V>
+        where
+            K: std::borrow::Borrow<Q>,
+        {
+            let group = self.groups.remove(key).unwrap_or_default();
+            self.len -= group.len();
+            group
+        }
+
+        #[inline]
+        pub fn groups(&self) -> impl Iterator<Item = (&K, &[V])> + '_ {
+            self.groups
+                .iter()
+                .map(|(key, group)| (key, group.as_slice()))
+        }
+
+        #[inline]
+        pub fn iter(&self) -> impl Iterator<Item = (&K, &V)> + '_ {
+            self.groups()
+                .flat_map(|(key, group)| group.iter().map(move |value| (key, value)))
+        }
+    }
+
+    impl<K: Ord, V> Default for BTreeMultiMap<K, V> {
+        #[inline]
+        fn default() -> Self {
+            Self::new()
+        }
+    }
+
+    impl<K: Ord, V> Extend<(K, V)> for BTreeMultiMap<K, V> {
+        #[inline]
+        fn extend<I: IntoIterator<Item = (K, V)>>(&mut self, iter: I) {
+            iter.into_iter()
+                .for_each(|(key, value)| self.insert(key, value));
+        }
+    }
+
+    impl<K: Ord, V> FromIterator<(K, V)> for BTreeMultiMap<K, V> {
+        #[inline]
+        fn from_iter<I: IntoIterator<Item = (K, V)>>(iter: I) -> Self {
+            let mut map = BTreeMultiMap::new();
+            map.extend(iter);
+            map
+        }
+    }
+
+    impl<L: Ord + Clone, R: Ord + Clone> BiBTreeMap<L, R> {
+        #[inline]
+        pub const fn new() -> Self {
+            Self {
+                left: BTreeMap23::new(),
+                right: BTreeMap23::new(),
+            }
+        }
+
+        #[inline]
+        pub fn len(&self) -> usize {
+            self.left.len()
+        }
+
+        #[inline]
+        pub fn is_empty(&self) -> bool {
+            self.left.is_empty()
+        }
+
+        #[inline]
+        pub fn is_not_empty(&self) -> bool {
+            !self.is_empty()
+        }
+
+        // Evicts the pairs holding either side, so both directions stay one-to-one
+        pub fn insert(&mut self, left: L, right: R) -> Vec<(L, R)> {
+            let evicted = [self.remove_by_left(&left), self.remove_by_right(&right)];
+
+            self.left.insert(left.clone(), right.clone());
+            self.right.insert(right, left);
+            evicted.into_iter().flatten().collect()
+        }
+
+        #[inline]
+        pub fn get_by_left<Q: Ord + ?Sized>(&self, left: &Q) -> Option<&R>
+        where
+            L: std::borrow::Borrow<Q>,
+        {
+            self.left.get(left)
+        }
+
+        #[inline]
+        pub fn get_by_right<Q: Ord + ?Sized>(&self, right: &Q) -> Option<&L>
+        where
+            R: std::borrow::Borrow<Q>,
+        {
+            self.right.get(right)
+        }
+
+        #[inline]
+        pub fn contains_left<Q: Ord + ?Sized>(&self, left: &Q) -> bool
+        where
+            L: std::borrow::Borrow<Q>,
+        {
+            self.left.contains_key(left)
+        }
+
+        #[inline]
+        pub fn contains_right<Q: Ord + ?Sized>(&self, right: &Q) -> bool
+        where
+            R: std::borrow::Borrow<Q>,
+        {
+            self.right.contains_key(right)
+        }
+
+        pub fn remove_by_left<Q: Ord + ?Sized>(&mut self, left: &Q) -> Option<(L, R)>
+        where
+            L: std::borrow::Borrow<Q>,
+        {
+            let right = self.left.remove(left)?;
+            let left = self.right.remove(&right).unwrap();
+            Some((left, right))
+        }
+
+        pub fn remove_by_right<Q: Ord + ?Sized>(&mut self, right: &Q) -> Option<(L, R)>
+        where
+            R: std::borrow::Borrow<Q>,
+        {
+            let left = self.right.remove(right)?;
+            let right = self.left.remove(&left).unwrap();
+            Some((left, right))
+        }
+
+        #[inline]
+        pub fn iter(&self) -> BTreeMap23Iter<'_, L, R> {
+            self.left.iter()
+        }
+
+        #[inline]
+        pub fn iter_by_right(&self) -> BTreeMap23Iter<'_, R, L> {
+            self.right.iter()
+        }
+    }
+
+    impl<L: Ord + Clone, R: Ord + Clone> Default for BiBTreeMap<L, R> {
+        #[inline]
+        fn default() -> Self {
+            Self::new()
+        }
+    }
+
+    impl<L: Ord + Clone, R: Ord + Clone> Extend<(L, R)> for BiBTreeMap<L, R> {
+        #[inline]
+        fn extend<I: IntoIterator<Item = (L, R)>>(&mut self, iter: I) {
+            iter.into_iter().for_each(|(left, right)| {
+                self.insert(left, right);
+            });
+        }
+    }
+
+    impl<L: Ord + Clone, R: Ord + Clone> FromIterator<(L, R)> for BiBTreeMap<L, R> {
+        #[inline]
+        fn from_iter<I: IntoIterator<Item = (L, R)>>(iter: I) -> Self {
+            let mut map = BiBTreeMap::new();
+            map.extend(iter);
+            map
+        }
+    }
+
+    impl<T: Ord + Clone> BTreeAugment<IntervalEntry<T>> for MaxEnd {
+        type Summary = Option<T>;
+
+        #[inline]
+        fn identity() -> Option<T> {
+            None
+        }
+
+        #[inline]
+        fn summarize(value: &IntervalEntry<T>) -> Option<T> {
+            Some(value.range.end.clone())
+        }
+
+        #[inline]
+        fn combine(left: &Option<T>, right: &Option<T>) -> Option<T> {
+            left.as_ref().max(right.as_ref()).cloned()
+        }
+    }
+
+    impl<T: Ord + Clone> BTreeIntervalSet<T> {
+        #[inline]
+        pub const fn new() -> Self {
+            Self {
+                intervals: BTree::augmented(),
+            }
+        }
+
+        #[inline]
+        pub fn len(&self) -> usize {
+            self.intervals.len()
+        }
+
+        #[inline]
+        pub fn is_empty(&self) -> bool {
+            self.intervals.is_empty()
+        }
+
+        #[inline]
+        pub fn is_not_empty(&self) -> bool {
+            !self.is_empty()
+        }
+
+        #[inline]
+        pub fn insert(&mut self, range: Range<T>) {
+            self.intervals.insert(IntervalEntry { range });
+        }
+
+        #[inline]
+        pub fn remove(&mut self, range: &Range<T>) -> bool {
+            self.intervals
+                .remove(&IntervalEntry {
+                    range: range.clone(),
+                })
+                .is_some()
+        }
+
+        #[inline]
+        pub fn contains(&self, range: &Range<T>) -> bool {
+            self.intervals.contains(&IntervalEntry {
+                range: range.clone(),
+            })
+        }
+
+        #[inline]
+        pub fn iter(&self) -> impl Iterator<Item = &Range<T>> + '_ {
+            self.intervals
+                .iter()
+                .map(|entry| unsafe { &self.intervals.stored_ref(&entry).range })
+        }
+
+        #[inline]
+        pub fn stab(&self, point: &T) -> Vec<&Range<T>> {
+            self.collect_overlapping(&|start| start <= point, &|end| end > point)
+        }
+
+        #[inline]
+        pub fn overlapping(&self, range: &Range<T>) -> Vec<&Range<T>> {
+            self.collect_overlapping(&|start| *start < range.end, &|end| *end > range.start)
+        }
+
+        // `starts_in` must hold for a prefix of starts and `ends_in` for a suffix of ends
+        fn collect_overlapping(
+            &self,
+            starts_in: &impl Fn(&T) -> bool,
+            ends_in: &impl Fn(&T) -> bool,
+        ) -> Vec<&Range<T>> {
+            let mut found = vec![];
+
+            if let Some(root) = &self.intervals.root {
+                self.collect_from_node(root, starts_in, ends_in, &mut found);
+            }
+
+            found
+        }
+
+        fn collect_from_node<'a>(
+            &'a self,
+            node: &Rc<RefCell<BTreeNode<IntervalEntry<T>, MaxEnd>>>,
+            starts_in: &impl Fn(&T) -> bool,
+            ends_in: &impl Fn(&T) -> bool,
+            found: &mut Vec<&'a Range<T>>,
+        ) {
+            match BTreeNode::summary(node) {
+                Some(max_end) if ends_in(&max_end) => {}
+                _ => return,
+            }
+
+            match &*node.borrow() {
+                BTreeNode::Leaf { leaf } => found.extend(
+                    leaf.values
+                        .iter()
+                        .filter(|entry| starts_in(&entry.range.start) && ends_in(&entry.range.end))
+                        .map(|entry| unsafe { &self.intervals.stored_ref(entry).range }),
+                ),
+
+                BTreeNode::SubTree { subtree } => {
+                    // A separator bounds the starts of every subtree to its right
+                    let visited = 1 + subtree
+                        .mid_keys
+                        .iter()
+                        .take_while(|key| starts_in(&key.range.start))
+                        .count();
+
+                    subtree.children[..visited]
+                        .iter()
+                        .for_each(|child| self.collect_from_node(child, starts_in, ends_in, found));
+                }
+            }
+        }
+    }
+
+    impl<T: Ord + Clone> Default for BTreeIntervalSet<T> {
+        #[inline]
+        fn default() -> Self {
+            Self::new()
+        }
+    }
+
+    impl<T: Ord + Clone> Extend<Range<T>> for BTreeIntervalSet<T> {
+        #[inline]
+        fn extend<I: IntoIterator<Item = Range<T>>>(&mut self, iter: I) {
+            iter.into_iter().for_each(|range| self.insert(range));
+        }
+    }
+
+    impl<T: Ord + Clone> FromIterator<Range<T>> for BTreeIntervalSet<T> {
+        #[inline]
+        fn from_iter<I: IntoIterator<Item = Range<T>>>(iter: I) -> Self {
+            let mut set = BTreeIntervalSet::new();
+            set.extend(iter);
+            set
+        }
+    }
+
+    impl<T: ?Sized, F: Fn(&T, &T) -> Ordering> BTreeComparator<T> for F {
+        #[inline]
+        fn compare(&self, left: &T, right: &T) -> Ordering {
+            self(left, right)
+        }
+    }
+
+    impl<T, C: BTreeComparator<T>> BTreeBy<T, C> {
+        #[inline]
+        pub fn new(comparator: C) -> Self {
+            Self {
+                values: BTree::new(),
+                comparator,
+            }
+        }
+
+        #[inline]
+        pub fn len(&self) -> usize {
+            self.values.len()
+        }
+
+        #[inline]
+        pub fn is_empty(&self) -> bool {
+            self.values.is_empty()
+        }
+
+        #[inline]
+        pub fn is_not_empty(&self) -> bool {
+            !self.is_empty()
+        }
+
+        #[inline]
+        pub fn insert(&mut self, value: T) {
+            let comparator = &self.comparator;
+
+            self.values.insert_rc_by(Rc::new(value), |stored, value| {
+                comparator.compare(stored, value)
+            });
+        }
+
+        // `compare` orders a stored value against the query
+        fn position_by(
+            &self,
+            compare: impl Fn(&T) -> Ordering,
+        ) -> Option<(Rc<RefCell<BTreeNode<T>>>, usize)> {
+            self.values
+                .root
+                .as_ref()
+                .and_then(|root| {
+                    BTreeNode::partition_point(root, &|stored: &T| {
+                        compare(stored) == Ordering::Less
+                    })
+                })
+                .filter(|(leaf, ind)| compare(&BTreeNode::value_at(leaf, *ind)) == Ordering::Equal)
+        }
+
+        #[inline]
+        fn value_at_position(&self, (leaf, ind): (Rc<RefCell<BTreeNode<T>>>, usize)) -> &T {
+            unsafe { self.values.stored_ref(&BTreeNode::value_at(&leaf, ind)) }
+        }
+
+        // Values never leave the tree as `Rc`, so the removed one is the last owner
+        #[inline]
+        fn remove_at_position(&mut self, (leaf, ind): (Rc<RefCell<BTreeNode<T>>>, usize)) -> T {
+            Rc::into_inner(self.values.remove_at_position(leaf, ind))
+                .expect("removed value should not be shared")
+        }
+
+        #[inline]
+        pub fn get(&self, value: &T) -> Option<&T> {
+            self.position_by(|stored| self.comparator.compare(stored, value))
+                .map(|position| self.value_at_position(position))
+        }
+
+        #[inline]
+        pub fn contains(&self, value: &T) -> bool {
+            self.get(value).is_some()
+        }
+
+        #[inline]
+        pub fn remove(&mut self, value: &T) -> Option<T> {
+            let position = self.position_by(|stored| self.comparator.compare(stored, value))?;
+            Some(self.remove_at_position(position))
+        }
+
+        #[inline]
+        pub fn first(&self) -> Option<&T> {
+            self.iter().next()
+        }
+
+        #[inline]
+        pub fn last(&self) -> Option<&T> {
+            let last = self.values.last()?;
+            Some(unsafe { self.values.stored_ref(&last) })
+        }
+
+        #[inline]
+        pub fn iter(&self) -> impl Iterator<Item = &T> + '_ {
+            self.values
+                .iter()
+                .map(|value| unsafe { self.values.stored_ref(&value) })
+        }
+
+        #[inline]
+        pub fn range<'a, R: RangeBounds<T> + 'a>(
+            &'a self,
+            range: R,
+        ) -> impl Iterator<Item = &'a T> + 'a {
+            self.range_by(range, |value, bound| self.comparator.compare(value, bound))
+        }
+
+        fn range_by<'a, B: ?Sized + 'a, R: RangeBounds<B> + 'a>(
+            &'a self,
+            range: R,
+            compare: impl Fn(&T, &B) -> Ordering + 'a,
+        ) -> impl Iterator<Item = &'a T> + 'a {
+            let start = self.values.root.as_ref().and_then(|root| {
+                BTreeNode::partition_point(root, &|stored: &T| match range.start_bound() {
+                    Bound::Included(start) => compare(stored, start) == Ordering::Less,
+                    Bound::Excluded(start) => compare(stored, start) != Ordering::Greater,
+                    Bound::Unbounded => false,
+                })
+            });
+
+            let (leaf, ind) = start.unzip();
+
+            BTreeIter::new(leaf, ind.unwrap_or_default())
+                .map(|value| unsafe { self.values.stored_ref(&value) })
+                .take_while(move |value| match range.end_bound() {
+                    Bound::Included(end) => compare(value, end) != Ordering::Greater,
+                    Bound::Excluded(end) => compare(value, end) == Ordering::Less,
+                    Bound::Unbounded => true,
+                })
+        }
+    }
+
+    impl<T: Ord + ?Sized> BTreeComparator<T> for Descending {
+        #[inline]
+        fn compare(&self, left: &T, right: &T) -> Ordering {
+            right.cmp(left)
+        }
+    }
+
+    impl<T: Ord> BTreeBy<T, Descending> {
+        #[inline]
+        pub fn descending() -> Self {
+            Self::new(Descending)
+        }
+    }
+
+    impl<T: Ord> FromIterator<T> for BTreeBy<T, Descending> {
+        #[inline]
+        fn from_iter<I: IntoIterator<Item = T>>(iter: I) -> Self {
+            let mut tree = BTreeDesc::descending();
+            tree.extend(iter);
+            tree
+        }
+    }
+
+    impl<T, K: Ord, F: Fn(&T) -> K> BTreeComparator<T> for ByKey<K, F> {
+        #[inline]
+        fn compare(&self, left: &T, right: &T) -> Ordering {
+            (self.key)(left).cmp(&(self.key)(right))
+        }
+    }
+
+    impl<T, K: Ord, F: Fn(&T) -> K> BTreeByKey<T, K, F> {
+        #[inline]
+        pub fn new(key: F) -> Self {
+            Self {
+                records: BTreeBy::new(ByKey {
+                    key,
+                    marker: std::marker::PhantomData,
+                }),
+            }
+        }
+
+        #[inline]
+        pub fn len(&self) -> usize {
+            self.records.len()
+        }
+
+        #[inline]
+        pub fn is_empty(&self) -> bool {
+            self.records.is_empty()
+        }
+
+        #[inline]
+        pub fn is_not_empty(&self) -> bool {
+            !self.is_empty()
+        }
+
+        #[inline]
+        pub fn key_of(&self, record: &T) -> K {
+            (self.records.comparator.key)(record)
+        }
+
+        #[inline]
+        pub fn insert(&mut self, record: T) {
+            self.records.insert(record);
+        }
+
+        #[inline]
+        pub fn get<Q: Ord + ?Sized>(&self, key: &Q) -> Option<&T>
+        where
+            K: std::borrow::Borrow<Q>,
+        {
+            self.records
+                .position_by(|record| self.key_of(record).borrow().cmp(key))
+                .map(|position| self.records.value_at_position(position))
+        }
+
+        #[inline]
+        pub fn contains_key<Q: Ord + ?Sized>(&self, key: &Q) -> bool
+        where
+            K: std::borrow::Borrow<Q>,
+        {
+            self.get(key).is_some()
+        }
+
+        #[inline]
+        pub fn remove<Q: Ord + ?Sized>(&mut self, key: &Q) -> Option<T>
+        where
+            K: std::borrow::Borrow<Q>,
+        {
+            let position = self
+                .records
+                .position_by(|record| self.key_of(record).borrow().cmp(key))?;
+
+            Some(self.records.remove_at_position(position))
+        }
+
+        #[inline]
+        pub fn first(&self) -> Option<&T> {
+            self.records.first()
+        }
+
+        #[inline]
+        pub fn last(&self) -> Option<&T> {
+            self.records.last()
+        }
+
+        #[inline]
+        pub fn iter(&self) -> impl Iterator<Item = &T> + '_ {
+            self.records.iter()
+        }
+
+        #[inline]
+        pub fn range<'a, R: RangeBounds<K> + 'a>(
+            &'a self,
+            range: R,
+        ) -> impl Iterator<Item = &'a T> + 'a {
+            self.records
+                .range_by(range, |record, key| self.key_of(record).cmp(key))
+        }
+    }
+
+    impl<T: Debug, K: Ord, F: Fn(&T) -> K> Debug for BTreeByKey<T, K, F> {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            self.records.fmt(f)
+        }
+    }
+
+    impl<T, K: Ord, F: Fn(&T) -> K> Extend<T> for BTreeByKey<T, K, F> {
+        #[inline]
+        fn extend<I: IntoIterator<Item = T>>(&mut self, iter: I) {
+            self.records.extend(iter);
+        }
+    }
+
+    impl<T: Debug, C: BTreeComparator<T>> Debug for BTreeBy<T, C> {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            f.debug_set().entries(self.iter()).finish()
+        }
+    }
+
+    impl<T, C: BTreeComparator<T>> Extend<T> for BTreeBy<T, C> {
+        #[inline]
+        fn extend<I: IntoIterator<Item = T>>(&mut self, iter: I) {
+            iter.into_iter().for_each(|value| self.insert(value));
+        }
+    }
+
+    impl<T: Clone, C: BTreeComparator<T> + Clone> Clone for BTreeBy<T, C> {
+        #[inline]
+        fn clone(&self) -> Self {
+            Self {
+                values: self.values.clone(),
+                comparator: self.comparator.clone(),
+            }
+        }
+    }
+
+    impl<T, C: BTreeComparator<T> + Default> Default for BTreeBy<T, C> {
+        #[inline]
+        fn default() -> Self {
+            Self::new(C::default())
+        }
+    }
+
+    impl<T: Ord> PartialOrd for IntervalEntry<T> {
+        #[inline]
+        fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+            Some(self.cmp(other))
+        }
+    }
+
+    impl<T: Ord> Ord for IntervalEntry<T> {
+        #[inline]
+        fn cmp(&self, other: &Self) -> Ordering {
+            (&self.range.start, &self.range.end).cmp(&(&other.range.start, &other.range.end))
+        }
+    }
+
+    impl<T: PartialEq> PartialEq for MultiSetRun<T> {
+        #[inline]
+        fn eq(&self, other: &Self) -> bool {
+            self.value == other.value
+        }
+    }
+
+    impl<T: Eq> Eq for MultiSetRun<T> {}
+
+    impl<T: Ord> PartialOrd for MultiSetRun<T> {
+        #[inline]
+        fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+            Some(self.cmp(other))
+        }
+    }
+
+    impl<T: Ord> Ord for MultiSetRun<T> {
+        #[inline]
+        fn cmp(&self, other: &Self) -> Ordering {
+            self.value.cmp(&other.value)
+        }
+    }
+
+    impl<T> std::borrow::Borrow<T> for MultiSetRun<T> {
+        #[inline]
+        fn borrow(&self) -> &T {
+            &self.value
+        }
+    }
+
+    impl<K, V> MapEntry<K, V> {
+        #[inline]
+        fn new(key: K, value: V) -> Self {
+            Self {
+                key,
+                value: RefCell::new(value),
+            }
+        }
+
+        // Separators only share stored entries, so a removed one is never shared
+        #[inline]
+        fn into_pair(entry: Rc<Self>) -> (K, V) {
+            let entry = Rc::into_inner(entry).expect("removed entry should not be shared");
+            (entry.key, entry.value.into_inner())
+        }
+    }
+
+    impl<K: PartialEq, V> PartialEq for MapEntry<K, V> {
+        #[inline]
+        fn eq(&self, other: &Self) -> bool {
+            self.key == other.key
+        }
+    }
+
+    impl<K: Eq, V> Eq for MapEntry<K, V> {}
+
+    impl<K: Ord, V> PartialOrd for MapEntry<K, V> {
+        #[inline]
+        fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+            Some(self.cmp(other))
+        }
+    }
+
+    impl<K: Ord, V> Ord for MapEntry<K, V> {
+        #[inline]
+        fn cmp(&self, other: &Self) -> Ordering {
+            self.key.cmp(&other.key)
+        }
+    }
+
+    impl<T: Clone> PartialEq for SeqItem<T> {
+        #[inline]
+        fn eq(&self, _: &Self) -> bool {
+            true
+        }
+    }
+
+    impl<T: Clone> Eq for SeqItem<T> {}
+
+    impl<T: Clone> PartialOrd for SeqItem<T> {
+        #[inline]
+        fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+            Some(self.cmp(other))
+        }
+    }
+
+    impl<T: Clone> Ord for SeqItem<T> {
+        #[inline]
+        fn cmp(&self, _: &Self) -> Ordering {
+            Ordering::Equal
+        }
+    }
+
+    impl<T: Ord + Eq + Clone> From<FrozenBTree<T>> for DeltaBTree<T> {
+        #[inline]
+        fn from(base: FrozenBTree<T>) -> Self {
+            Self::new(base)
+        }
+    }
+
+    impl<'a, T: Ord + Eq> Iterator for DeltaBTreeRange<'a, T> {
+        type Item = &'a T;
+
+        fn next(&mut self) -> Option<Self::Item> {
+            loop {
+                let next = match (self.base.peek(), self.inserted.peek()) {
+                    (Some(base), Some(inserted)) if **inserted < **base => {
+                        let inserted = self.inserted.next().unwrap();
+                        unsafe { self.overlay.stored_ref(&inserted) }
+                    }
+
+                    (Some(_), _) => self.base.next().unwrap(),
+
+                    (None, Some(_)) => {
+                        let inserted = self.inserted.next().unwrap();
+                        unsafe { self.overlay.stored_ref(&inserted) }
+                    }
+
+                    (None, None) => return None,
+                };
+
+                while self.removed.next_if(|removed| **removed < *next).is_some() {}
+
+                if self.removed.next_if(|removed| **removed == *next).is_none() {
+                    return Some(next);
+                }
+            }
+        }
+    }
+
+    impl<T: Ord + Eq> PartialEq<std::collections::BTreeSet<T>> for BTree<T> {
+        #[inline]
+        fn eq(&self, other: &std::collections::BTreeSet<T>) -> bool {
+            self.len() == other.len() && self.iter().zip(other).all(|(a, b)| *a == *b)
+        }
+    }
+
+    impl<T: Ord + Eq> PartialEq<[T]> for BTree<T> {
+        #[inline]
+        fn eq(&self, other: &[T]) -> bool {
+            self.len() == other.len() && self.iter().zip(other).all(|(a, b)| *a == *b)
+        }
+    }
+
+    impl<T: Ord + Eq> PartialEq<Vec<T>> for BTree<T> {
+        #[inline]
+        fn eq(&self, other: &Vec<T>) -> bool {
+            *self == *other.as_slice()
+        }
+    }
+
+    impl<T: Clone, A: BTreeAugment<T>> Clone for BTree<T, A> {
+        #[inline]
+        fn clone(&self) -> Self {
+            let mut tree = Self::augmented();
+            tree.clone_from(self);
+            tree
+        }
+
+        fn clone_from(&mut self, source: &Self) {
+            let mut prev_leaf = None;
+            self.mode = source.mode;
+            self.policy = source.policy;
+
+            self.root = source
+                .root
+                .as_ref()
+                .map(|root| BTreeNode::clone_into(root, self.root.take(), None, &mut prev_leaf));
+        }
+    }
+
+    impl<T: Ord + Eq, A: BTreeAugment<T>> Extend<T> for BTree<T, A> {
+        #[inline]
+        fn extend<I: IntoIterator<Item = T>>(&mut self, iter: I) {
+            iter.into_iter().for_each(|x| {
+                self.insert(x);
+            });
+        }
+    }
+
+    impl<T: Ord + Eq, A: BTreeAugment<T>> FromIterator<T> for BTree<T, A> {
+        #[inline]
+        fn from_iter<I: IntoIterator<Item = T>>(iter: I) -> Self {
+            let mut tree = Self::augmented();
+            tree.extend(iter);
+            tree
+        }
+    }
+
+    impl<T: Ord + Eq> IntoIterator for BTree<T> {
+        type Item = Rc<T>;
+        type IntoIter = BTreeIter<T>;
+
+        #[inline]
+        fn into_iter(self) -> Self::IntoIter {
+            self.root
+                .map(|root_node| BTreeNode::first_leaf(&root_node))
+                .map(|first_leaf| BTreeIter::new(Some(first_leaf), 0))
+                .unwrap_or_default()
+        }
+    }
+
+    impl<T: Debug> std::fmt::Display for OccupiedError<T> {
+        #[inline]
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(
+                f,
+                "failed to insert {:?}, equal value {:?} is already stored",
+                self.value, self.existing
+            )
+        }
+    }
+
+    impl<T: Debug> std::error::Error for OccupiedError<T> {}
+
+    impl<T: Ord + Eq> BitOr for &BTree<T> {
+        type Output = BTree<T>;
+
+        #[inline]
+        fn bitor(self, rhs: Self) -> Self::Output {
+            BTree {
+                root: BTree::from_sorted_rcs(self.union(rhs)).root,
+                ..self.empty_like()
+            }
+        }
+    }
+
+    impl<T: Ord + Eq> BitAnd for &BTree<T> {
+        type Output = BTree<T>;
+
+        #[inline]
+        fn bitand(self, rhs: Self) -> Self::Output {
+            BTree {
+                root: BTree::from_sorted_rcs(BTreeIntersection::new(self.iter(), rhs.iter())).root,
+                ..self.empty_like()
+            }
+        }
+    }
+
+    impl<T: Ord + Eq> Sub for &BTree<T> {
+        type Output = BTree<T>;
+
+        #[inline]
+        fn sub(self, rhs: Self) -> Self::Output {
+            BTree {
+                root: BTree::from_sorted_rcs(self.difference(rhs)).root,
+                ..self.empty_like()
+            }
+        }
+    }
+
+    impl<T: Ord + Eq> BitXor for &BTree<T> {
+        type Output = BTree<T>;
+
+        #[inline]
+        fn bitxor(self, rhs: Self) -> Self::Output {
+            BTree {
+                root: BTree::from_sorted_rcs(self.symmetric_difference(rhs)).root,
+                ..self.empty_like()
+            }
+        }
+    }
+
+    #[cfg(feature = "serde")]
+    #[derive(Debug, Default, Clone)]
+    pub struct StructuralBTree<T: Ord + Eq>(pub BTree<T>);
+
+    #[cfg(feature = "serde")]
+    mod serde_support {
+        use super::*;
+
+        use serde::{
+            de::Error,
+            ser::{SerializeSeq, SerializeStructVariant},
+            Deserialize, Deserializer, Serialize, Serializer,
+        };
+
+        struct SerValues<'a, T>(&'a [Rc<T>]);
+
+        struct SerNode<'a, T: Ord + Eq>(&'a Rc<RefCell<BTreeNode<T>>>);
+
+        #[derive(Deserialize)]
+        #[serde(rename = "Node")]
+        enum DeNode<T> {
+            Leaf(Vec<T>),
+            SubTree {
+                mid_keys: Vec<T>,
+                children: Vec<DeNode<T>>,
+            },
+        }
+
+        impl<T: Serialize> Serialize for SerValues<'_, T> {
+            #[inline]
+            fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                serializer.collect_seq(self.0.iter().map(|v| &**v))
+            }
+        }
+
+        impl<T: Ord + Eq + Serialize> Serialize for SerNode<'_, T> {
+            fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                match &*self.0.borrow() {
+                    BTreeNode::Leaf { leaf } => serializer.serialize_newtype_variant(
+                        "Node",
+                        0,
+                        "Leaf",
+                        &SerValues(&leaf.values),
+                    ),
+
+                    BTreeNode::SubTree { subtree } => {
+                        let mut state =
+                            serializer.serialize_struct_variant("Node", 1, "SubTree", 2)?;
+
+                        state.serialize_field("mid_keys", &SerValues(&subtree.mid_keys))?;
+
+                        state.serialize_field(
+                            "children",
+                            &subtree.children.iter().map(SerNode).collect::<Vec<_>>(),
+                        )?;
+
+                        state.end()
+                    }
+                }
+            }
+        }
+
+        impl<T: Ord + Eq + Serialize> Serialize for BTree<T> {
+            #[inline]
+            fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                let mut seq = serializer.serialize_seq(Some(self.len()))?;
+
+                for value in self.iter() {
+                    seq.serialize_element(&*value)?;
+                }
+
+                seq.end()
+            }
+        }
+
+        impl<'de, T: Ord + Eq + Deserialize<'de>> Deserialize<'de> for BTree<T> {
+            #[inline]
+            fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+                let mut values = Vec::<T>::deserialize(deserializer)?;
+                values.sort();
+                Ok(BTree::from_sorted_rcs(values.into_iter().map(Rc::new)))
+            }
+        }
+
+        impl<T: Ord + Eq + Serialize> Serialize for StructuralBTree<T> {
+            #[inline]
+            fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                self.0.root.as_ref().map(SerNode).serialize(serializer)
+            }
+        }
+
+        impl<'de, T: Ord + Eq + Deserialize<'de>> Deserialize<'de> for StructuralBTree<T> {
+            fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+                let root = match Option::<DeNode<T>>::deserialize(deserializer)? {
+                    Some(root) => root,
+                    None => return Ok(Self(BTree::new())),
+                };
+
+                let mut leaves = vec![];
+                let mut leaf_depth = None;
+                let root = build_node(root, None, None, 0, &mut leaf_depth, &mut leaves)
+                    .map_err(D::Error::custom)?;
+
+                leaves.windows(2).for_each(|w| unsafe {
+                    w[0].borrow_mut().unwrap_as_leaf_mut_unchecked().next_leaf = Some(w[1].clone());
+                    w[1].borrow_mut()
+                        .unwrap_as_leaf_mut_unchecked()
+                        .previous_leaf = Some(Rc::downgrade(&w[0]));
+                });
+
+                Ok(Self(BTree {
+                    root: Some(root),
+                    ..BTree::new()
+                }))
+            }
+        }
+
+        fn build_node<T: Ord + Eq>(
+            node: DeNode<T>,
+            lower: Option<&T>,
+            upper: Option<&T>,
+            depth: usize,
+            leaf_depth: &mut Option<usize>,
+            leaves: &mut Vec<Rc<RefCell<BTreeNode<T>>>>,
+        ) -> Result<Rc<RefCell<BTreeNode<T>>>, &'static str> {
+            let is_sorted = |values: &[T]| {
+                values.windows(2).all(|w| w[0] <= w[1])
+                    && values
+                        .first()
+                        .is_none_or(|first| lower.is_none_or(|lower| first >= lower))
+                    && values
+                        .last()
+                        .is_none_or(|last| upper.is_none_or(|upper| last <= upper))
+            };
+
+            match node {
+                DeNode::Leaf(values) => {
+                    if !(1..=MAX_KEYS).contains(&values.len()) || !is_sorted(&values) {
+                        return Err("invalid leaf");
+                    }
+
+                    if *leaf_depth.get_or_insert(depth) != depth {
+                        return Err("leaves must all have the same depth");
+                    }
+
+                    let leaf = Rc::new(RefCell::new(BTreeNode::Leaf {
+                        leaf: BTreeLeaf::new(
+                            values.into_iter().map(Rc::new).collect(),
+                            None,
+                            None,
+                            None,
+                        ),
+                    }));
+
+                    leaves.push(leaf.clone());
+                    Ok(leaf)
+                }
+
+                DeNode::SubTree { mid_keys, children } => {
+                    if !(2..=MAX_CHILDREN).contains(&children.len())
+                        || mid_keys.len() + 1 != children.len()
+                        || !is_sorted(&mid_keys)
+                    {
+                        return Err("invalid subtree");
+                    }
+
+                    let children = children
+                        .into_iter()
+                        .enumerate()
+                        .map(|(i, child)| {
+                            let child_lower = match i {
+                                0 => lower,
+                                _ => Some(&mid_keys[i - 1]),
+                            };
+
+                            let child_upper = mid_keys.get(i).or(upper);
+                            build_node(
+                                child,
+                                child_lower,
+                                child_upper,
+                                depth + 1,
+                                leaf_depth,
+                                leaves,
+                            )
+                        })
+                        .collect::<Result<Vec<_>, _>>()?;
+
+                    // The separators only bound the children here; the stored ones
+                    // share the first values of the children they precede
+                    let mid_keys = children[1..]
+                        .iter()
+                        .map(|child| BTreeNode::value_at(&BTreeNode::first_leaf(child), 0))
+                        .collect();
+
+                    let subtree = Rc::new(RefCell::new(BTreeNode::SubTree {
+                        subtree: BTreeSubTree::new(children.clone(), None, mid_keys),
+                    }));
+
+                    children.iter().for_each(|child| {
+                        child.borrow_mut().set_parent(Some(Rc::downgrade(&subtree)))
+                    });
+
+                    Ok(subtree)
+                }
+            }
+        }
+    }
+
+    #[cfg(test)]
+    fn assert_invariants<T: Ord + Eq + Debug>(tree: &BTree<T>) {
+        fn check<T: Ord + Eq + Debug>(
+            node: &Rc<RefCell<BTreeNode<T>>>,
+            parent: Option<&Rc<RefCell<BTreeNode<T>>>>,
+            lower: Option<&T>,
+            upper: Option<&T>,
+            depth: usize,
+            leaves: &mut Vec<(Rc<RefCell<BTreeNode<T>>>, usize)>,
+        ) -> usize {
+            let node_ref = node.borrow();
+
+            match (node_ref.get_parent().and_then(|p| p.upgrade()), parent) {
+                (Some(actual), Some(expected)) => assert!(Rc::ptr_eq(&actual, expected)),
+                (None, None) => {}
+                _ => panic!("broken parent link"),
+            }
+
+            match &*node_ref {
+                BTreeNode::Leaf { leaf } => {
+                    assert!((1..=MAX_KEYS).contains(&leaf.values.len()));
+                    assert!(leaf.values.windows(2).all(|w| w[0] <= w[1]));
+                    assert!(lower.is_none_or(|lower| leaf.values.iter().all(|v| **v >= *lower)));
+                    assert!(upper.is_none_or(|upper| leaf.values.iter().all(|v| **v <= *upper)));
+                    leaves.push((node.clone(), depth));
+                    leaf.values.len()
+                }
+
+                BTreeNode::SubTree { subtree } => {
+                    assert!((2..=MAX_CHILDREN).contains(&subtree.children.len()));
+                    assert_eq!(subtree.mid_keys.len() + 1, subtree.children.len());
+                    assert!(subtree.mid_keys.windows(2).all(|w| w[0] <= w[1]));
+
+                    let values_number = subtree
+                        .children
+                        .iter()
+                        .enumerate()
+                        .map(|(i, child)| {
+                            let child_lower = match i {
+                                0 => lower,
+                                _ => Some(&*subtree.mid_keys[i - 1]),
+                            };
+
+                            let child_upper = subtree.mid_keys.get(i).map(|key| &**key).or(upper);
+                            check(
+                                child,
+                                Some(node),
+                                child_lower,
+                                child_upper,
+                                depth + 1,
+                                leaves,
+                            )
+                        })
+                        .sum();
+
+                    assert_eq!(values_number, subtree.values_number);
+                    values_number
+                }
+            }
+        }
+
+        fn separators<T: Ord + Eq>(node: &Rc<RefCell<BTreeNode<T>>>, out: &mut Vec<Rc<T>>) {
+            if let BTreeNode::SubTree { subtree } = &*node.borrow() {
+                out.extend(subtree.mid_keys.iter().cloned());
+                subtree
+                    .children
+                    .iter()
+                    .for_each(|child| separators(child, out));
+            }
+        }
+
+        let mut leaves = vec![];
+
+        // A dangling root parent would upgrade to `None` and slip through `check`
+        assert!(tree
+            .root
+            .as_ref()
+            .is_none_or(|root| root.borrow().get_parent().is_none()));
+
+        let len = tree
+            .root
+            .as_ref()
+            .map(|root| check(root, None, None, None, 0, &mut leaves))
+            .unwrap_or_default();
+
+        // Separators must share stored values, so removed values are never kept alive
+        let mut mid_keys = vec![];
+        tree.root
+            .iter()
+            .for_each(|root| separators(root, &mut mid_keys));
+        let stored = tree
+            .iter()
+            .map(|v| Rc::as_ptr(&v))
+            .collect::<std::collections::HashSet<_>>();
+        assert!(mid_keys.iter().all(|key| stored.contains(&Rc::as_ptr(key))));
+
+        assert_eq!(len, tree.len());
+        assert!(leaves.windows(2).all(|w| w[0].1 == w[1].1));
+
+        leaves.windows(2).for_each(|w| unsafe {
+            let first = w[0].0.borrow();
+            let second = w[1].0.borrow();
+            let first = first.unwrap_as_leaf_unchecked();
+            let second = second.unwrap_as_leaf_unchecked();
+
+            assert!(Rc::ptr_eq(first.next_leaf.as_ref().unwrap(), &w[1].0));
+            assert!(Rc::ptr_eq(
+                &second.previous_leaf.as_ref().unwrap().upgrade().unwrap(),
+                &w[0].0
+            ));
+        });
+
+        if let (Some(first), Some(last)) = (leaves.first(), leaves.last()) {
+            unsafe {
+                assert!(first
+                    .0
+                    .borrow()
+                    .unwrap_as_leaf_unchecked()
+                    .previous_leaf
+                    .is_none());
+                assert!(last
+                    .0
+                    .borrow()
+                    .unwrap_as_leaf_unchecked()
+                    .next_leaf
+                    .is_none());
+            }
+        }
+
+        assert!(tree.iter().zip(tree.iter().skip(1)).all(|(a, b)| a <= b));
+        assert_eq!(tree.iter().count(), len);
+    }
+
+    // Random inserts and removals checked against a sorted `Vec` model
+    #[cfg(test)]
+    fn check_random_removals(policy: BTreeRebalancePolicy, seed: usize) {
+        let mut tree = BTree::new().with_rebalance_policy(policy);
+        let mut model = Vec::new();
+        let mut state = seed;
+
+        for _ in 0..3000 {
+            state = state
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+
+            let value = (state >> 40) % 300;
+
+            match (state >> 33) % 5 < 2 {
+                true => {
+                    let removed = tree.remove(&value).map(|v| *v);
+                    let pos = model.iter().position(|&v| v == value);
+                    assert_eq!(removed, pos.map(|pos| model.remove(pos)));
+                }
+
+                false => {
+                    tree.insert(value);
+                    let pos = model.partition_point(|&v| v <= value);
+                    model.insert(pos, value);
+                }
+            }
+
+            assert_invariants(&tree);
+        }
+
+        assert_eq!(tree, model);
+    }
+
+    #[cfg(test)]
+    mod allocation_counter {
+        use std::{
+            alloc::{GlobalAlloc, Layout, System},
+            cell::Cell,
+        };
+
+        thread_local! {
+            static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+        }
+
+        struct CountingAllocator;
+
+        #[global_allocator]
+        static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+        unsafe impl GlobalAlloc for CountingAllocator {
+            #[inline]
+            unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+                let _ = ALLOCATIONS.try_with(|allocations| allocations.set(allocations.get() + 1));
+                System.alloc(layout)
+            }
+
+            #[inline]
+            unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+                System.dealloc(ptr, layout)
+            }
+        }
+
+        pub fn allocations_in<R>(f: impl FnOnce() -> R) -> (usize, R) {
+            let before = ALLOCATIONS.with(Cell::get);
+            let result = f();
+            (ALLOCATIONS.with(Cell::get) - before, result)
+        }
+    }
+
+    #[test]
+    #[allow(clippy::unnecessary_fold)]
     fn tree_test() {
         let tree = BTree::from_iter(-1000..=1000);
         assert_eq!(tree.len(), 2001);
         assert_eq!(tree.first().map(|x| *x), Some(-1000));
         assert_eq!(tree.last().map(|x| *x), Some(1000));
 
-        assert!((0..tree.len())
-            .map(|i| *tree.get(i).unwrap())
-            .zip(-1000..=1000)
-            .all(|(tree_elem, val)| { tree_elem == val }));
+        assert!((0..tree.len())
+            .map(|i| *tree.get(i).unwrap())
+            .zip(-1000..=1000)
+            .all(|(tree_elem, val)| { tree_elem == val }));
+
+        assert!(tree
+            .iter()
+            .map(|v| *v + *v)
+            .zip((-1000..).map(|x| x + x))
+            .all(|(tree_elem, x)| tree_elem == x));
+
+        assert_eq!(
+            tree.iter().map(|x| *x * *x).fold(0, |acc, x| acc + x),
+            (-1000..=1000).fold(0, |acc, x| acc + x * x)
+        );
+
+        assert!(tree
+            .into_iter()
+            .map(|v| *v * *v)
+            .zip((-1000..).map(|x| x * x))
+            .all(|(tree_elem, x)| tree_elem == x));
+    }
+
+    #[test]
+    fn bulk_load_from_sorted_test() {
+        for len in 0..50 {
+            let tree = BTree::bulk_load_from_sorted(0..len);
+            assert_invariants(&tree);
+            assert!(tree.iter().map(|x| *x).eq(0..len));
+        }
+
+        let mut tree = BTree::bulk_load_from_sorted((0..10000).map(|x| x * 2));
+        assert_invariants(&tree);
+        assert_eq!(tree.get(1234).map(|x| *x), Some(2468));
+        assert!(tree
+            .range(100..=110)
+            .map(|x| *x)
+            .eq([100, 102, 104, 106, 108, 110]));
+
+        (0..10000).for_each(|x| {
+            tree.insert(x * 2 + 1);
+        });
+        assert_invariants(&tree);
+        assert!(tree.iter().map(|x| *x).eq(0..20000));
+
+        let tree = BTree::bulk_load_from_sorted([1, 1, 2, 2, 2, 3]);
+        assert_invariants(&tree);
+        assert_eq!(tree.len(), 6);
+    }
+
+    #[test]
+    #[should_panic]
+    fn bulk_load_from_unsorted_test() {
+        BTree::bulk_load_from_sorted([1, 3, 2]);
+    }
+
+    #[test]
+    fn range_test() {
+        use std::collections::BTreeSet;
+
+        let values = (0..300).map(|x| x * 2).collect::<Vec<_>>();
+        let tree = BTree::from_iter(values.iter().cloned());
+        let set = BTreeSet::from_iter(values.iter().cloned());
+
+        assert!((0..600).all(|x| tree.find(&(x * 2 % 600)).next().map(|v| *v) == Some(x * 2 % 600)));
+
+        let bounds = [-1, 0, 1, 2, 3, 299, 300, 301, 597, 598, 599, 600];
+
+        for &a in &bounds {
+            for &b in &bounds {
+                if a >= b {
+                    assert_eq!(tree.range(a..b).count(), 0);
+                    assert_eq!(
+                        tree.range((Bound::Excluded(a), Bound::Excluded(b))).count(),
+                        0
+                    );
+                }
+
+                if a > b {
+                    assert_eq!(tree.range(a..=b).count(), 0);
+                    continue;
+                }
+
+                assert!(tree.range(a..b).map(|x| *x).eq(set.range(a..b).cloned()));
+                assert!(tree.range(a..=b).map(|x| *x).eq(set.range(a..=b).cloned()));
+
+                assert!(tree
+                    .range(a..=b)
+                    .rev()
+                    .map(|x| *x)
+                    .eq(set.range(a..=b).rev().cloned()));
+
+                if a < b {
+                    assert!(tree
+                        .range((Bound::Excluded(a), Bound::Excluded(b)))
+                        .map(|x| *x)
+                        .eq(set.range((Bound::Excluded(a), Bound::Excluded(b))).cloned()));
+                }
+            }
+
+            assert!(tree.range(a..).map(|x| *x).eq(set.range(a..).cloned()));
+            assert!(tree
+                .range(..a)
+                .rev()
+                .map(|x| *x)
+                .eq(set.range(..a).rev().cloned()));
+        }
+
+        assert!(tree.range(..).map(|x| *x).eq(values.iter().cloned()));
+        assert_eq!(BTree::<i32>::new().range(..).next(), None);
+
+        for len in 0..10 {
+            let mut range = tree.range(100..100 + len * 2);
+            let mut collected = vec![];
+
+            while let Some(front) = range.next() {
+                collected.push(*front);
+
+                if let Some(back) = range.next_back() {
+                    collected.push(*back);
+                }
+            }
+
+            collected.sort();
+            assert!(collected.into_iter().eq((100..100 + len * 2).step_by(2)));
+        }
+
+        let mut iter = tree.find(&100);
+        assert!((0..=50)
+            .rev()
+            .all(|x| iter.next_back().map(|v| *v) == Some(x * 2)));
+        assert_eq!(iter.next_back(), None);
+    }
+
+    #[cfg(feature = "rayon")]
+    #[test]
+    fn par_contains_many_test() {
+        let tree = BTree::from_iter((0..10000).map(|x| x * 3));
+        let probes = (0..30000).rev().collect::<Vec<_>>();
+        let found = tree.par_contains_many(&probes);
+
+        assert!(probes
+            .iter()
+            .zip(found)
+            .all(|(probe, is_found)| is_found == (probe % 3 == 0)));
+
+        assert!(BTree::new()
+            .par_contains_many(&[1, 2, 3])
+            .iter()
+            .all(|x| !x));
+        assert!(tree.par_contains_many(&[]).is_empty());
+
+        let mut duplicates = BTree::from_iter((0..300).map(|x| x / 10));
+        (0..300).step_by(7).for_each(|x| {
+            duplicates.remove(&(x / 10));
+        });
+
+        let probes = (-5..40).collect::<Vec<_>>();
+        assert!(probes
+            .iter()
+            .zip(duplicates.par_contains_many(&probes))
+            .all(|(probe, is_found)| is_found == duplicates.contains(probe)));
+    }
+
+    #[test]
+    fn iter_enumerated_test() {
+        let tree = BTree::from_iter((0..500).map(|x| x * 2));
+
+        assert!(tree
+            .iter_enumerated()
+            .all(|(rank, value)| rank * 2 == *value as usize));
+
+        assert!(tree
+            .find(&301)
+            .enumerated()
+            .all(|(rank, value)| rank * 2 == *value as usize));
+
+        assert!(tree
+            .range(77..=777)
+            .enumerated()
+            .map(|(rank, value)| (rank, *value))
+            .eq((39..=388).map(|rank| (rank, rank as i32 * 2))));
+
+        assert_eq!(tree.range(2000..).enumerated().next(), None);
+        assert_eq!(BTree::<i32>::new().iter_enumerated().next(), None);
+
+        // Leaves of a dropped tree no longer know their rank, so counting starts over
+        assert!(BTree::<i32>::from_iter(0..10)
+            .into_iter()
+            .enumerated()
+            .map(|(rank, value)| (rank, *value))
+            .eq((0..10).map(|x| (x as usize, x))));
+
+        let mut owned = BTree::<i32>::from_iter(0..10).into_iter();
+        owned.nth(3);
+        assert!(owned
+            .enumerated()
+            .map(|(rank, value)| (rank, *value))
+            .eq((4..10).enumerate()));
+    }
+
+    #[test]
+    fn freeze_test() {
+        let frozen = BTree::from_iter((0..1000).rev().map(|x| x * 2)).freeze();
+
+        assert_eq!(frozen.len(), 1000);
+        assert!(frozen.iter().cloned().eq((0..1000).map(|x| x * 2)));
+        assert_eq!(frozen.first(), Some(&0));
+        assert_eq!(frozen.last(), Some(&1998));
+        assert_eq!(frozen.get(10), Some(&20));
+        assert_eq!(frozen.get(1000), None);
+        assert!(frozen.contains(&500));
+        assert!(!frozen.contains(&501));
+        assert!(frozen.range(11..=21).cloned().eq([12, 14, 16, 18, 20]));
+        assert!(frozen.range((Bound::Excluded(20), Bound::Unbounded)).next() == Some(&22));
+        assert_eq!(
+            frozen
+                .range((Bound::Included(30), Bound::Excluded(10)))
+                .count(),
+            0
+        );
+
+        let frozen = BTree::from_iter([String::from("frozen")]).freeze();
+        assert_eq!(frozen.as_slice(), [String::from("frozen")]);
+        assert!(BTree::<i32>::new().freeze().is_empty());
+    }
+
+    #[test]
+    fn delta_btree_test() {
+        assert!(DeltaBTree::new(BTree::<i32>::new().freeze()).is_empty());
+
+        let mut delta = DeltaBTree::new(BTree::from_iter((0..100).map(|x| x * 2)).freeze());
+        assert!(delta.is_not_empty());
+
+        assert!(delta.iter().cloned().eq((0..100).map(|x| x * 2)));
+
+        delta.insert(3);
+        delta.insert(4);
+        assert!(delta.remove(&10));
+        assert!(delta.remove(&4));
+        assert!(!delta.remove(&5));
+        assert!(delta.remove(&3));
+        assert!(!delta.remove(&3));
+
+        assert_eq!(delta.len(), 99);
+        assert_eq!(delta.delta_len(), 5);
+        assert_eq!(delta.count(&4), 1);
+        assert!(!delta.contains(&10));
+        assert!(!delta.contains(&3));
+        assert_eq!(delta.get(&4), Some(&4));
+        assert!(delta.range(0..=12).cloned().eq([0, 2, 4, 6, 8, 12]));
+
+        let expected = delta.iter().cloned().collect::<Vec<_>>();
+        delta.merge_down();
+
+        assert_eq!(delta.delta_len(), 0);
+        assert_eq!(delta.base().as_slice(), expected.as_slice());
+        assert_eq!(delta.len(), 99);
+    }
+
+    #[test]
+    fn partial_eq_test() {
+        use std::collections::BTreeSet;
+
+        let tree = BTree::from_iter([3, 1, 2]);
+
+        assert!(tree == vec![1, 2, 3]);
+        assert!(tree == *[1, 2, 3].as_slice());
+        assert!(tree == BTreeSet::from([1, 2, 3]));
+        assert!(tree != vec![3, 2, 1]);
+        assert!(tree != vec![1, 2]);
+        assert!(tree != BTreeSet::from([1, 2, 3, 4]));
+        assert!(BTree::<i32>::new() == Vec::new());
+
+        let tree = BTree::from_iter([1, 1]);
+        assert!(tree == vec![1, 1]);
+        assert!(tree != BTreeSet::from([1]));
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde_test() {
+        let tree = BTree::from_iter([5, 3, 1, 4, 2]);
+        let flat = serde_json::to_string(&tree).unwrap();
+        assert_eq!(flat, "[1,2,3,4,5]");
+
+        let tree = serde_json::from_str::<BTree<i32>>("[3,1,2,2]").unwrap();
+        assert_invariants(&tree);
+        assert!(tree == vec![1, 2, 2, 3]);
+
+        let tree = BTree::from_iter(0..100);
+        let structural = serde_json::to_string(&StructuralBTree(tree.clone())).unwrap();
+        let restored = serde_json::from_str::<StructuralBTree<i32>>(&structural).unwrap();
+        assert_invariants(&restored.0);
+
+        assert_eq!(
+            restored.0.traverse_events().collect::<Vec<_>>(),
+            tree.traverse_events().collect::<Vec<_>>()
+        );
+
+        assert_eq!(
+            serde_json::to_string(&StructuralBTree(BTree::from_iter([1, 2, 3]))).unwrap(),
+            r#"{"SubTree":{"mid_keys":[2],"children":[{"Leaf":[1]},{"Leaf":[2,3]}]}}"#
+        );
+
+        assert!(serde_json::from_str::<StructuralBTree<i32>>("null")
+            .unwrap()
+            .0
+            .is_empty());
+
+        assert!(serde_json::from_str::<StructuralBTree<i32>>(
+            r#"{"SubTree":{"mid_keys":[2],"children":[{"Leaf":[3]},{"Leaf":[2]}]}}"#
+        )
+        .is_err());
+
+        assert!(serde_json::from_str::<StructuralBTree<i32>>(
+            r#"{"SubTree":{"mid_keys":[2],"children":[{"Leaf":[1]},{"SubTree":{"mid_keys":[3],"children":[{"Leaf":[2]},{"Leaf":[3]}]}}]}}"#
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn get_stored_test() {
+        let mut interner = BTree::new();
+        ["b", "a", "c"].map(String::from).into_iter().for_each(|s| {
+            interner.insert(s);
+        });
+
+        let first = interner.get_stored("a").unwrap();
+        let second = interner.get_stored("a").unwrap();
+
+        assert!(Rc::ptr_eq(&first, &second));
+        assert_eq!(first.as_str(), "a");
+        assert_eq!(interner.get_stored("d"), None);
+        assert_eq!(interner.get_stored(""), None);
+        assert_eq!(BTree::<String>::new().get_stored("a"), None);
+
+        let tree = BTree::from_iter(0..1000);
+        assert!((0..1000).all(|x| tree.get_stored(&x).map(|v| *v) == Some(x)));
+    }
+
+    #[test]
+    fn read_path_allocations_test() {
+        use allocation_counter::allocations_in;
+
+        let tree = BTree::bulk_load_from_sorted(0..200_000);
+        let height = tree.mid_keys().map(|(depth, _)| depth + 1).max().unwrap();
+        assert!(height >= 10);
+
+        let (allocations, found) = allocations_in(|| {
+            (0..200_000).step_by(997).all(|x| {
+                tree.find(&x).next().map(|v| *v) == Some(x)
+                    && tree.get(x as usize).map(|v| *v) == Some(x)
+                    && tree.get_stored(&x).map(|v| *v) == Some(x)
+            })
+        });
+
+        assert!(found);
+        assert_eq!(allocations, 0);
+
+        let (allocations, bounds) = allocations_in(|| (tree.first(), tree.last()));
+        assert_eq!(allocations, 0);
+        assert_eq!(bounds.0.map(|v| *v), Some(0));
+        assert_eq!(bounds.1.map(|v| *v), Some(199_999));
+
+        let (allocations, sum) = allocations_in(|| tree.range(1000..2000).map(|v| *v).sum::<i32>());
+        assert_eq!(allocations, 0);
+        assert_eq!(sum, (1000..2000).sum::<i32>());
+    }
+
+    #[test]
+    fn sharded_btree_test() {
+        let sharded = ShardedBTree::with_boundaries(vec![100, 200, 300]);
+        assert_eq!(sharded.shard_count(), 4);
+
+        (0..400).rev().for_each(|x| {
+            sharded.insert(x);
+        });
+
+        assert_eq!(sharded.len(), 400);
+        assert!(sharded.iter().eq(0..400));
+        assert!((0..400).all(|x| sharded.contains(&x)));
+        assert!(!sharded.contains(&400));
+        assert_eq!(sharded.shard_index(&99), 0);
+        assert_eq!(sharded.shard_index(&100), 1);
+        assert_eq!(sharded.shard_index(&1000), 3);
+        assert_eq!(sharded.lock_shard(2).len(), 100);
+        assert!(sharded.is_not_empty() && !sharded.lock_shard(3).is_empty());
+
+        let locked = sharded.lock_shard(1);
+        assert!(sharded.try_lock_shard(1).is_none());
+        assert!(sharded.try_lock_shard(2).is_some());
+        drop(locked);
+
+        assert!(sharded.remove(&150));
+        assert!(!sharded.remove(&150));
+        assert!(!sharded.contains(&150));
+
+        let empty = ShardedBTree::<i32>::with_boundaries(vec![]);
+        assert!(empty.is_empty());
+        assert_eq!(empty.iter().next(), None);
+
+        let default = ShardedBTree::default();
+        assert!(default.insert(1));
+        assert_eq!(default.shard_count(), 1);
+        assert!(default.iter().eq([1]));
+    }
+
+    #[test]
+    fn sharded_btree_threads_test() {
+        let sharded = ShardedBTree::with_boundaries(vec![1000, 2000, 3000]);
+
+        std::thread::scope(|scope| {
+            (0..4).for_each(|thread| {
+                let sharded = &sharded;
+
+                scope.spawn(move || {
+                    (0..4000).filter(|x| x % 4 == thread).for_each(|x| {
+                        sharded.insert(x);
+                    });
+                });
+            });
+        });
+
+        assert_eq!(sharded.len(), 4000);
+        assert!(sharded.iter().eq(0..4000));
+
+        std::thread::scope(|scope| {
+            (0..4).for_each(|thread| {
+                let sharded = &sharded;
+                scope.spawn(move || {
+                    (0..4000)
+                        .filter(|x| x % 8 == thread)
+                        .all(|x| sharded.remove(&x))
+                });
+            });
+        });
+
+        assert_eq!(sharded.len(), 2000);
+        assert!(sharded.iter().eq((0..4000).filter(|x| x % 8 >= 4)));
+    }
+
+    #[test]
+    fn set_views_test() {
+        use std::collections::BTreeSet;
+
+        let a = BTree::from_iter([1, 2, 2, 3, 5, 8, 13]);
+        let b = BTree::from_iter([2, 3, 3, 4, 5, 6, 7, 8]);
+        let set_a = BTreeSet::from_iter(a.iter().map(|x| *x));
+        let set_b = BTreeSet::from_iter(b.iter().map(|x| *x));
+
+        let union = UnionView::new(&a, &b);
+        assert!(union.iter().map(|x| *x).eq(set_a.union(&set_b).cloned()));
+        assert_eq!(union.len(), set_a.union(&set_b).count());
+        assert!(union.contains(&13) && union.contains(&4) && !union.contains(&9));
+
+        let intersection = IntersectionView::new(&a, &b);
+        assert!(intersection
+            .iter()
+            .map(|x| *x)
+            .eq(set_a.intersection(&set_b).cloned()));
+        assert_eq!(intersection.len(), 4);
+        assert!(intersection.contains(&5) && !intersection.contains(&13));
+
+        let difference = DifferenceView::new(&a, &b);
+        assert!(difference
+            .iter()
+            .map(|x| *x)
+            .eq(set_a.difference(&set_b).cloned()));
+        assert!(DifferenceView::new(&b, &a)
+            .iter()
+            .map(|x| *x)
+            .eq(set_b.difference(&set_a).cloned()));
+        assert!(difference.contains(&1) && !difference.contains(&2));
+
+        let empty = BTree::new();
+        assert!(UnionView::new(&empty, &empty).is_empty());
+        assert!(IntersectionView::new(&a, &empty).is_empty());
+        assert!(DifferenceView::new(&empty, &a).is_empty());
+        assert_eq!(DifferenceView::new(&a, &empty).len(), 6);
+    }
+
+    #[test]
+    fn for_each_in_range_test() {
+        let tree = BTree::from_iter((0..100).rev());
+
+        let mut visited = vec![];
+        let flow = tree.for_each_in_range(10..20, |&v| {
+            visited.push(v);
+            ControlFlow::Continue(())
+        });
+        assert_eq!(flow, ControlFlow::Continue(()));
+        assert_eq!(visited, (10..20).collect::<Vec<_>>());
+
+        let mut visited = vec![];
+        let flow = tree.for_each_in_range(50.., |&v| {
+            visited.push(v);
+            match visited.len() {
+                3 => ControlFlow::Break(()),
+                _ => ControlFlow::Continue(()),
+            }
+        });
+        assert_eq!(flow, ControlFlow::Break(()));
+        assert_eq!(visited, vec![50, 51, 52]);
+
+        let mut count = 0;
+        let _ = tree.for_each_in_range((Bound::Excluded(97), Bound::Unbounded), |_| {
+            count += 1;
+            ControlFlow::Continue(())
+        });
+        assert_eq!(count, 2);
+
+        let _ = tree.for_each_in_range(200..=300, |_| panic!("range is empty"));
+        let _ = BTree::<i32>::new().for_each_in_range(.., |_| panic!("tree is empty"));
+    }
+
+    #[test]
+    fn take_first_n_test() {
+        for len in [0, 1, 2, 3, 7, 30, 200] {
+            for n in 0..=len + 1 {
+                let mut tree = BTree::from_iter((0..len).rev());
+                let first = tree.take_first_n(n);
+
+                assert_invariants(&first);
+                assert_invariants(&tree);
+                assert!(first.iter().map(|x| *x).eq(0..n.min(len)));
+                assert!(tree.iter().map(|x| *x).eq(n.min(len)..len));
+
+                let mut tree = BTree::bulk_load_from_sorted(0..len);
+                let last = tree.take_last_n(n);
+
+                assert_invariants(&last);
+                assert_invariants(&tree);
+                assert!(last.iter().map(|x| *x).eq(len.saturating_sub(n)..len));
+                assert!(tree.iter().map(|x| *x).eq(0..len.saturating_sub(n)));
+            }
+        }
+
+        let mut tree = BTree::from_iter([3, 1, 2, 2, 2, 5, 2, 4]);
+        let first = tree.take_first_n(3);
+        assert!(first.iter().map(|x| *x).eq([1, 2, 2]));
+        assert!(tree.iter().map(|x| *x).eq([2, 2, 3, 4, 5]));
+
+        tree.insert(2);
+        tree.insert(6);
+        assert_invariants(&tree);
+        assert!(tree.iter().map(|x| *x).eq([2, 2, 2, 3, 4, 5, 6]));
+    }
+
+    #[test]
+    fn btree_seq_test() {
+        let mut seq = BTreeSeq::new();
+        let mut model = Vec::new();
+        let mut state = 7_usize;
+
+        for step in 0..600 {
+            state = state
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            let r = state >> 33;
+
+            match model.is_empty() || !r.is_multiple_of(3) {
+                true => {
+                    let index = r % (model.len() + 1);
+                    seq.insert_at(index, step);
+                    model.insert(index, step);
+                }
+
+                false => {
+                    let index = r % model.len();
+                    assert_eq!(seq.remove_at(index), model.remove(index));
+                }
+            }
+
+            assert_invariants(&seq.tree);
+        }
+
+        assert_eq!(seq.len(), model.len());
+        assert!(seq.iter().eq(model.iter()));
+        assert!((0..model.len()).all(|i| seq.get(i) == Some(&model[i])));
+        assert_eq!(seq.get(model.len()), None);
+        assert_eq!(seq.first(), model.first());
+        assert_eq!(seq.last(), model.last());
+        assert_eq!(seq.is_empty(), model.is_empty());
+        assert_eq!(seq.is_not_empty(), !model.is_empty());
+
+        let mut seq = BTreeSeq::from_iter(["b", "d"]);
+        seq.insert_at(0, "a");
+        seq.insert_at(2, "c");
+        seq.push("e");
+        assert!(seq.iter().copied().eq(["a", "b", "c", "d", "e"]));
+    }
+
+    #[test]
+    fn stable_equal_order_test() {
+        #[derive(Debug, Clone)]
+        struct Task {
+            priority: usize,
+            id: usize,
+        }
+
+        impl PartialEq for Task {
+            fn eq(&self, other: &Self) -> bool {
+                self.priority == other.priority
+            }
+        }
+
+        impl Eq for Task {}
+
+        impl PartialOrd for Task {
+            fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+                Some(self.cmp(other))
+            }
+        }
+
+        impl Ord for Task {
+            fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+                self.priority.cmp(&other.priority)
+            }
+        }
+
+        let tree = BTree::from_iter((0..500).map(|id| Task {
+            priority: id * 7 % 5,
+            id,
+        }));
+
+        assert_invariants(&tree);
+
+        let tasks = tree.iter().collect::<Vec<_>>();
+        assert!(tasks
+            .windows(2)
+            .all(|w| match w[0].priority == w[1].priority {
+                true => w[0].id < w[1].id,
+                false => w[0].priority < w[1].priority,
+            }));
+    }
+
+    #[test]
+    fn position_token_test() {
+        let mut tree = BTree::from_iter([5, 1, 3, 3, 3, 3, 7, 3, 9, 2]);
+        let mut pages = vec![];
+        let mut iter = tree.iter();
+
+        loop {
+            pages.push(iter.by_ref().take(3).map(|x| *x).collect::<Vec<_>>());
+
+            match iter.position_token() {
+                Some(token) => iter = tree.resume_iter(&token),
+                None => break,
+            }
+        }
+
+        assert_eq!(
+            pages,
+            vec![vec![1, 2, 3], vec![3, 3, 3], vec![3, 5, 7], vec![9]]
+        );
+
+        let mut iter = tree.iter();
+        iter.by_ref().take(4).for_each(drop);
+        let token = iter.position_token().unwrap();
+        assert_eq!(token, BTreePositionToken { key: 3, offset: 2 });
+
+        #[cfg(feature = "serde")]
+        let token = serde_json::from_str::<BTreePositionToken<i32>>(
+            &serde_json::to_string(&token).unwrap(),
+        )
+        .unwrap();
+
+        tree.insert(0);
+        tree.insert(4);
+        assert!(tree
+            .resume_iter(&token)
+            .map(|x| *x)
+            .eq([3, 3, 3, 4, 5, 7, 9]));
+
+        let token = BTreePositionToken { key: 3, offset: 10 };
+        assert!(tree.resume_iter(&token).map(|x| *x).eq([4, 5, 7, 9]));
+
+        let token = BTreePositionToken { key: 10, offset: 0 };
+        assert_eq!(tree.resume_iter(&token).next(), None);
+        assert_eq!(BTree::new().resume_iter(&token).next(), None);
+
+        let owned = BTree::<i32>::from_iter(0..10).into_iter();
+        assert_eq!(owned.position_token(), None);
+        assert!(owned.map(|x| *x).eq(0..10));
+    }
+
+    #[test]
+    fn push_max_test() {
+        let mut tree = BTree::new();
+        (0..300).for_each(|x| {
+            tree.push_max(x / 2);
+        });
+        assert_invariants(&tree);
+        assert!(tree.iter().map(|x| *x).eq((0..300).map(|x| x / 2)));
+
+        tree.push_max(100);
+        tree.push_max(-1);
+        tree.push_max(150);
+        assert_invariants(&tree);
+        assert_eq!(tree.len(), 303);
+        assert_eq!(*tree.first().unwrap(), -1);
+        assert_eq!(*tree.last().unwrap(), 150);
+        assert!(tree.iter().map(|x| *x).is_sorted());
+        assert_eq!(tree.range(100..=100).count(), 3);
+    }
+
+    #[test]
+    fn multiset_test() {
+        let values = (0..400).map(|x| x * 17 % 23 % 6).collect::<Vec<_>>();
+        let set = BTreeMultiSet::from_iter(values.iter().copied());
+
+        let mut sorted = values.clone();
+        sorted.sort();
+
+        assert_eq!(set.len(), 400);
+        assert_eq!(set.distinct_len(), 6);
+        assert!(set.iter().eq(sorted.iter()));
+        assert!((0..400).step_by(7).all(|i| set.get(i) == Some(&sorted[i])));
+        assert_eq!(set.get(400), None);
+
+        (0..6).for_each(|x| assert_eq!(set.count(&x), values.iter().filter(|&&v| v == x).count()));
+
+        assert_eq!(set.count(&6), 0);
+        assert!(set.contains(&5) && !set.contains(&-1));
+        assert!(set.iter_counts().map(|(v, _)| *v).eq(0..6));
+        assert!(BTreeMultiSet::<i32>::new().iter().next().is_none());
+        assert!(set.is_not_empty() && !BTreeMultiSet::<i32>::new().is_not_empty());
+    }
+
+    #[test]
+    fn multiset_remove_test() {
+        let mut set = (0..300).map(|x| x % 7).collect::<BTreeMultiSet<_>>();
+        assert_eq!((set.len(), set.count(&3)), (300, 43));
+
+        assert!(set.remove_one(&3));
+        assert_eq!((set.len(), set.count(&3)), (299, 42));
+
+        assert_eq!(set.remove_all(&3), 42);
+        assert_eq!(set.remove_all(&3), 0);
+        assert!(!set.remove_one(&3) && !set.contains(&3));
+        assert_eq!((set.len(), set.distinct_len()), (257, 6));
+
+        set.insert(9);
+        assert!(set.remove_one(&9));
+        assert_eq!(set.count(&9), 0);
+        assert!(set.iter_counts().map(|(v, _)| *v).eq([0, 1, 2, 4, 5, 6]));
+
+        let sorted = set.iter().copied().collect::<Vec<_>>();
+        assert!((0..sorted.len()).all(|i| set.get(i) == Some(&sorted[i])));
+        assert_eq!(set.get(sorted.len()), None);
+
+        (0..7).for_each(|x| {
+            set.remove_all(&x);
+        });
+
+        assert!(set.is_empty() && set.iter().next().is_none());
+    }
+
+    #[test]
+    fn map_test() {
+        use std::collections::BTreeMap;
+
+        let mut map = BTreeMap23::new();
+        let mut model = BTreeMap::new();
+
+        for x in 0..600 {
+            let key = (x * 37 % 211).to_string();
+            assert_eq!(map.insert(key.clone(), x), model.insert(key, x));
+        }
+
+        assert_eq!(map.len(), model.len());
+        assert!(map.iter().eq(model.iter()));
+
+        for x in (0..300).step_by(3) {
+            let key = x.to_string();
+            assert_eq!(map.remove(key.as_str()), model.remove(&key));
+            assert_eq!(map.get(key.as_str()), None);
+        }
+
+        assert_eq!(map.len(), model.len());
+        assert!(map.iter().eq(model.iter()));
+        assert!(model
+            .keys()
+            .all(|key| map.get(key.as_str()) == model.get(key)));
+        assert!(map.contains_key("1") && !map.contains_key("3"));
+        assert!(map.is_not_empty() && !BTreeMap23::<i32, i32>::new().is_not_empty());
+        assert!(BTreeMap23::<i32, i32>::new().iter().next().is_none());
+    }
+
+    #[test]
+    fn map_get_mut_test() {
+        let mut counters = BTreeMap23::from_iter((0..100).map(|x| (x, 0)));
+
+        for x in 0..1000 {
+            *counters.get_mut(&(x * x % 100)).unwrap() += 1;
+        }
+
+        assert_eq!(counters.get(&0), Some(&100));
+        assert_eq!(counters.get(&2), Some(&0));
+        assert_eq!(counters.iter().map(|(_, count)| count).sum::<i32>(), 1000);
+        assert_eq!(counters.get_mut(&100), None);
+    }
+
+    #[test]
+    fn map_non_clone_values_test() {
+        #[derive(Debug, PartialEq)]
+        struct Payload(i32);
+
+        let mut map = BTreeMap23::from_iter((0..500).map(|x| (x, Payload(x))));
+
+        for x in (0..500).step_by(2) {
+            assert_eq!(map.remove(&x), Some(Payload(x)));
+        }
+
+        assert_eq!(map.pop_first(), Some((1, Payload(1))));
+        assert_eq!(map.pop_last(), Some((499, Payload(499))));
+        assert_eq!(map.insert(3, Payload(-3)), Some(Payload(3)));
+        assert!(map.iter().all(|(key, value)| value.0.abs() == *key));
+        assert_eq!(map.len(), 248);
+
+        #[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
+        struct Key(i32);
+
+        let mut map = BTreeMap23::from_iter((0..100).rev().map(|x| (Key(x), Payload(x))));
+        assert_eq!(map.remove(&Key(42)), Some(Payload(42)));
+        assert_eq!(map.insert(Key(7), Payload(-7)), Some(Payload(7)));
+        assert_eq!(map.pop_first(), Some((Key(0), Payload(0))));
+        assert!(map
+            .keys()
+            .map(|key| key.0)
+            .eq((1..100).filter(|x| *x != 42)));
+
+        let mut multimap = BTreeMultiMap::from_iter((0..30).map(|x| (Key(x % 3), Payload(x))));
+        assert_eq!(multimap.get_all(&Key(1)).len(), 10);
+        assert_eq!(multimap.remove_all(&Key(2)).len(), 10);
+        assert_eq!((multimap.len(), multimap.keys_len()), (20, 2));
+    }
+
+    #[test]
+    fn map_projections_test() {
+        let mut map = BTreeMap23::from_iter((0..200).rev().map(|x| (x, x * 10)));
+
+        assert!(map.keys().copied().eq(0..200));
+        assert!(map.values().copied().eq((0..200).map(|x| x * 10)));
+
+        map.values_mut().for_each(|value| *value += 1);
+        assert!(map.values().copied().eq((0..200).map(|x| x * 10 + 1)));
+        assert!(map.iter().all(|(key, value)| *value == key * 10 + 1));
+
+        let mut empty = BTreeMap23::<i32, i32>::new();
+        assert!(empty.keys().next().is_none() && empty.values_mut().next().is_none());
+    }
+
+    #[test]
+    fn map_first_last_entry_test() {
+        let mut queue = BTreeMap23::from_iter((0..300).rev().map(|x| (x * 7 % 300, x)));
+
+        {
+            let mut first = queue.first_entry().unwrap();
+            assert_eq!((*first.key(), *first.get()), (0, 0));
+            assert_eq!(first.insert(-1), 0);
+            *first.get_mut() -= 1;
+        }
+
+        assert_eq!(queue.get(&0), Some(&-2));
+        *queue.last_entry().unwrap().into_mut() = 1000;
+        assert_eq!(queue.get(&299), Some(&1000));
+
+        assert_eq!(queue.pop_first(), Some((0, -2)));
+        assert_eq!(queue.pop_last(), Some((299, 1000)));
+        assert_eq!(queue.last_entry().unwrap().remove(), 298 * 43 % 300);
+
+        let mut popped = vec![];
+
+        while let Some((key, _)) = queue.pop_first() {
+            popped.push(key);
+        }
+
+        assert_eq!(popped, (1..298).collect::<Vec<_>>());
+        assert!(queue.is_empty() && queue.first_entry().is_none());
+    }
+
+    #[test]
+    fn map_entry_ref_test() {
+        let mut counts = BTreeMap23::<String, usize>::new();
+        let text = "the quick fox jumps over the lazy dog the end";
+
+        for word in text.split(' ') {
+            *counts.entry_ref(word).or_default() += 1;
+        }
+
+        assert_eq!(counts.get("the"), Some(&3));
+        assert_eq!(counts.len(), 8);
+
+        let (key, value) = counts.get_key_value("fox").unwrap();
+        assert_eq!((key.as_str(), *value), ("fox", 1));
+        assert_eq!(counts.get_key_value("cat"), None);
+
+        counts
+            .entry_ref("dog")
+            .and_modify(|count| *count += 10)
+            .or_insert(0);
+        counts
+            .entry_ref("cat")
+            .and_modify(|count| *count += 10)
+            .or_insert(7);
+        assert_eq!(counts.get("dog"), Some(&11));
+        assert_eq!(counts.get("cat"), Some(&7));
+
+        match counts.entry_ref("emu") {
+            BTreeMap23EntryRef::Vacant(entry) => {
+                assert_eq!(entry.key(), "emu");
+                *entry.insert(1) += 1;
+            }
+
+            BTreeMap23EntryRef::Occupied(_) => unreachable!(),
+        }
+
+        assert_eq!(counts.get("emu"), Some(&2));
+        assert!(counts
+            .keys()
+            .map(String::as_str)
+            .eq(["cat", "dog", "emu", "end", "fox", "jumps", "lazy", "over", "quick", "the"]));
+    }
+
+    #[test]
+    fn map_merge_with_test() {
+        let mut shard = (0..100)
+            .step_by(2)
+            .map(|key| (key, vec![key]))
+            .collect::<BTreeMap23<_, _>>();
+
+        let other_shard = (0..100)
+            .step_by(3)
+            .map(|key| (key, vec![key * 10]))
+            .collect::<BTreeMap23<_, _>>();
+
+        let mut collisions = vec![];
+
+        shard.merge_with(other_shard, |key, mut mine, theirs| {
+            collisions.push(*key);
+            mine.extend(theirs);
+            mine
+        });
+
+        assert!(collisions.into_iter().eq((0..100).step_by(6)));
+        assert!(shard
+            .keys()
+            .copied()
+            .eq((0..100).filter(|key| key % 2 == 0 || key % 3 == 0)));
+
+        assert_eq!(shard.get(&12), Some(&vec![12, 120]));
+        assert_eq!(shard.get(&4), Some(&vec![4]));
+        assert_eq!(shard.get(&9), Some(&vec![90]));
+
+        shard.merge_with(BTreeMap23::new(), |_, mine, _| mine);
+        assert_eq!(shard.len(), 67);
+    }
+
+    #[test]
+    fn map_values_test() {
+        let mut prices = (0..200)
+            .map(|id| (id, Rc::new(id * 10)))
+            .collect::<BTreeMap23<_, _>>();
+
+        (0..200).step_by(3).for_each(|id| {
+            prices.remove(&id);
+        });
+
+        let shared = prices.get(&1).unwrap().clone();
+
+        let labels = prices.map_values(|id, price| format!("{id}: {price}"));
+        assert_eq!(labels.len(), 133);
+        assert_eq!(labels.get(&1).map(String::as_str), Some("1: 10"));
+        assert_eq!(labels.get(&3), None);
+        assert_eq!(labels.get(&199).map(String::as_str), Some("199: 1990"));
+        assert!(labels.keys().copied().eq((0..200).filter(|id| id % 3 != 0)));
+        assert_eq!(Rc::strong_count(&shared), 1);
+
+        let mut lengths = labels.map_values(|_, label| label.len());
+        lengths.insert(3, 0);
+        assert_eq!(lengths.remove(&2), Some(5));
+        assert_eq!(lengths.get(&3), Some(&0));
+        assert_eq!(lengths.pop_last(), Some((199, 9)));
+        assert_eq!(BTreeMap23::<i32, i32>::new().map_values(|_, v| v).len(), 0);
+    }
+
+    #[test]
+    fn map_get_disjoint_mut_test() {
+        let mut buckets = [("a", 10), ("b", 5), ("c", 0)]
+            .map(|(name, qty)| (name.to_string(), qty))
+            .into_iter()
+            .collect::<BTreeMap23<_, _>>();
+
+        if let [Some(from), Some(to)] = buckets.get_disjoint_mut(["a", "c"]) {
+            *from -= 4;
+            *to += 4;
+        }
+
+        assert!(buckets.values().copied().eq([6, 5, 4]));
+
+        let [b, missing, c] = buckets.get_disjoint_mut(["b", "z", "c"]);
+        assert_eq!((b, missing, c), (Some(&mut 5), None, Some(&mut 4)));
+    }
+
+    #[test]
+    #[should_panic(expected = "keys should be disjoint")]
+    fn map_get_disjoint_mut_overlap_test() {
+        let mut buckets = BTreeMap23::<i32, i32>::new();
+        buckets.insert(1, 1);
+        let _ = buckets.get_disjoint_mut([&1, &2, &1]);
+    }
+
+    #[test]
+    #[should_panic(expected = "keys should be disjoint")]
+    fn map_get_disjoint_mut_ord_alias_test() {
+        // Equality tolerates a difference of one, ordering only sees tens
+        #[derive(Debug, Clone, Eq)]
+        struct Key(i32);
+
+        impl PartialEq for Key {
+            fn eq(&self, other: &Self) -> bool {
+                self.0.abs_diff(other.0) <= 1
+            }
+        }
+
+        impl PartialOrd for Key {
+            fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+                Some(self.cmp(other))
+            }
+        }
+
+        impl Ord for Key {
+            fn cmp(&self, other: &Self) -> Ordering {
+                (self.0 / 10).cmp(&(other.0 / 10))
+            }
+        }
+
+        let mut map = BTreeMap23::new();
+        map.insert(Key(11), 0);
+
+        let _ = map.get_disjoint_mut([&Key(10), &Key(12)]);
+    }
+
+    #[test]
+    fn multimap_test() {
+        let users = [
+            (1, "ann", "oslo"),
+            (2, "bob", "rome"),
+            (3, "cid", "oslo"),
+            (4, "dan", "kyiv"),
+            (5, "eve", "oslo"),
+        ];
+
+        let mut by_city = users
+            .iter()
+            .map(|&(id, _, city)| (city.to_string(), id))
+            .collect::<BTreeMultiMap<_, _>>();
+
+        assert_eq!((by_city.len(), by_city.keys_len()), (5, 3));
+        assert_eq!(by_city.get_all("oslo"), &[1, 3, 5]);
+        assert!(by_city.get_all("lima").is_empty());
+
+        assert!(by_city.remove("oslo", &3));
+        assert!(!by_city.remove("oslo", &3));
+        assert!(!by_city.remove("lima", &1));
+        assert_eq!(by_city.get_all("oslo"), &[1, 5]);
+
+        assert!(by_city.remove("rome", &2));
+        assert!(!by_city.contains_key("rome"));
+
+        by_city.insert("kyiv".to_string(), 6);
+
+        assert!(by_city
+            .groups()
+            .map(|(city, ids)| (city.as_str(), ids.len()))
+            .eq([("kyiv", 2), ("oslo", 2)]));
+
+        assert!(by_city.iter().map(|(_, id)| *id).eq([4, 6, 1, 5]));
+        assert_eq!(by_city.remove_all("oslo"), vec![1, 5]);
+        assert_eq!((by_city.len(), by_city.keys_len()), (2, 1));
+        assert!(by_city.is_not_empty());
+
+        by_city.remove_all("kyiv");
+        assert!(by_city.is_empty());
+    }
+
+    #[test]
+    fn interval_set_test() {
+        let ranges = (0..300)
+            .map(|i| {
+                let start = i * 37 % 500;
+                start..start + i * 13 % 60
+            })
+            .collect::<Vec<_>>();
+
+        let mut set = ranges.iter().cloned().collect::<BTreeIntervalSet<_>>();
+        let mut expected = ranges.clone();
+
+        let check = |set: &BTreeIntervalSet<i32>, expected: &[Range<i32>]| {
+            for point in (-5..570).step_by(7) {
+                let mut stabbed = expected
+                    .iter()
+                    .filter(|range| range.contains(&point))
+                    .cloned()
+                    .collect::<Vec<_>>();
+
+                stabbed.sort_by_key(|range| (range.start, range.end));
+                assert!(set.stab(&point).into_iter().eq(stabbed.iter()));
+
+                let query = point..point + 25;
+
+                let overlapping = stabbed.len()
+                    + expected
+                        .iter()
+                        .filter(|range| {
+                            range.start < query.end
+                                && range.end > query.start
+                                && !range.contains(&point)
+                        })
+                        .count();
+
+                assert_eq!(set.overlapping(&query).len(), overlapping);
+            }
+        };
+
+        check(&set, &expected);
+
+        for i in (0..300).step_by(4) {
+            assert!(set.remove(&ranges[i]));
+            let pos = expected
+                .iter()
+                .position(|range| *range == ranges[i])
+                .unwrap();
+            expected.remove(pos);
+        }
+
+        assert!(!set.contains(&ranges[0]) && set.contains(&ranges[1]));
+        check(&set, &expected);
+
+        set.insert(-10..1000);
+        expected.push(-10..1000);
+        check(&set, &expected);
+
+        assert_eq!(set.len(), 226);
+        assert!(set.is_not_empty() && BTreeIntervalSet::<i32>::new().is_empty());
+        assert!(set
+            .iter()
+            .is_sorted_by_key(|range| (range.start, range.end)));
+        assert!(BTreeIntervalSet::<i32>::new().stab(&0).is_empty());
+    }
+
+    #[test]
+    fn fold_range_test() {
+        struct Sum;
+
+        impl BTreeAugment<i64> for Sum {
+            type Summary = i64;
+
+            fn identity() -> i64 {
+                0
+            }
+
+            fn summarize(value: &i64) -> i64 {
+                *value
+            }
+
+            fn combine(left: &i64, right: &i64) -> i64 {
+                left + right
+            }
+        }
+
+        struct Span;
+
+        impl BTreeAugment<i64> for Span {
+            type Summary = (i64, i64);
+
+            fn identity() -> (i64, i64) {
+                (i64::MAX, i64::MIN)
+            }
+
+            fn summarize(value: &i64) -> (i64, i64) {
+                (*value, *value)
+            }
+
+            fn combine(left: &(i64, i64), right: &(i64, i64)) -> (i64, i64) {
+                (left.0.min(right.0), left.1.max(right.1))
+            }
+        }
+
+        let mut sums = (0..500).map(|x| x * 7 % 311).collect::<BTree<i64, Sum>>();
+        let mut spans = sums
+            .iter()
+            .map(|x| *x)
+            .collect::<BTree<i64, Span>>()
+            .with_rebalance_policy(BTreeRebalancePolicy::Merge);
+        let mut values = sums.iter().map(|x| *x).collect::<Vec<_>>();
+
+        let check = |sums: &BTree<i64, Sum>, spans: &BTree<i64, Span>, values: &[i64]| {
+            for (lo, hi) in [
+                (0, 311),
+                (17, 18),
+                (40, 260),
+                (100, 99),
+                (-5, 3),
+                (300, 400),
+            ] {
+                let in_range = values.iter().filter(|x| (lo..hi).contains(*x));
+                let expected = in_range.clone().sum::<i64>();
+
+                assert_eq!(sums.fold_range(lo..hi).unwrap_or_default(), expected);
+
+                assert_eq!(
+                    spans.fold_range(lo..hi),
+                    in_range
+                        .clone()
+                        .next()
+                        .map(|first| (*first, *in_range.clone().next_back().unwrap()))
+                );
+            }
+
+            assert_eq!(
+                sums.fold_range(..),
+                values.iter().copied().reduce(|a, b| a + b)
+            );
+        };
+
+        check(&sums, &spans, &values);
+
+        (0..311).step_by(3).for_each(|x| {
+            sums.remove(&x);
+            spans.remove(&x);
+            values.remove(values.binary_search(&x).unwrap());
+        });
+
+        check(&sums, &spans, &values);
+
+        (1000..1100).for_each(|x| {
+            sums.insert(x % 330);
+            spans.insert(x % 330);
+            values.insert(values.partition_point(|v| *v <= x % 330), x % 330);
+        });
+
+        check(&sums, &spans, &values);
+        assert_eq!(BTree::<i64, Sum>::augmented().fold_range(..), None);
+    }
+
+    #[test]
+    fn btree_by_test() {
+        let mut by_age = BTreeBy::new(|a: &(&str, u32), b: &(&str, u32)| a.1.cmp(&b.1));
+        by_age.extend([
+            ("ann", 41),
+            ("bob", 19),
+            ("cid", 33),
+            ("dan", 19),
+            ("eve", 64),
+        ]);
+
+        assert!(by_age
+            .iter()
+            .map(|p| p.0)
+            .eq(["bob", "dan", "cid", "ann", "eve"]));
+        assert_eq!(by_age.get(&("", 33)), Some(&("cid", 33)));
+        assert!(by_age.contains(&("anyone", 64)) && !by_age.contains(&("ann", 40)));
+        assert!(by_age
+            .range(("", 20)..=("", 41))
+            .map(|p| p.0)
+            .eq(["cid", "ann"]));
+        assert_eq!(by_age.remove(&("", 19)), Some(("bob", 19)));
+        assert_eq!(by_age.first(), Some(&("dan", 19)));
+
+        let mut reversed = BTreeBy::new(|a: &i32, b: &i32| b.cmp(a));
+        reversed.extend(0..100);
+
+        assert_eq!((reversed.first(), reversed.last()), (Some(&99), Some(&0)));
+        assert!(reversed
+            .range((Bound::Included(60), Bound::Excluded(55)))
+            .eq([&60, &59, &58, &57, &56]));
+        assert!(reversed.range(..3).copied().eq((4..100).rev()));
+        assert_eq!(reversed.range(5..).count(), 6);
+
+        let mut names =
+            BTreeBy::new(|a: &String, b: &String| a.to_lowercase().cmp(&b.to_lowercase()));
+
+        names.extend(["delta", "Alpha", "charlie", "Bravo"].map(String::from));
+        assert!(names.iter().eq(["Alpha", "Bravo", "charlie", "delta"]));
+        assert_eq!(
+            names.get(&"ALPHA".to_string()).map(String::as_str),
+            Some("Alpha")
+        );
+        assert_eq!(names.len(), 4);
+
+        let mut descending = BTreeDesc::default();
+        descending.extend([3, 1, 2, 3]);
+
+        let mut copy = descending.clone();
+        assert_eq!(copy.remove(&3), Some(3));
+        assert!(descending.iter().eq([&3, &3, &2, &1]));
+        assert!(copy.iter().eq([&3, &2, &1]));
+        assert!(copy.is_not_empty() && BTreeDesc::<i32>::default().is_empty());
+    }
+
+    #[test]
+    fn btree_by_key_test() {
+        #[derive(Debug, Clone, PartialEq)]
+        struct Order {
+            id: String,
+            total: f64,
+        }
+
+        let order = |id: &str, total| Order {
+            id: id.to_string(),
+            total,
+        };
+
+        let mut orders = BTreeByKey::new(|order: &Order| order.id.clone());
+        orders.extend([order("c-17", 3.5), order("a-02", 12.0), order("b-40", 7.25)]);
+        orders.insert(order("a-10", 1.0));
+
+        assert!(orders
+            .iter()
+            .map(|o| o.id.as_str())
+            .eq(["a-02", "a-10", "b-40", "c-17"]));
+        assert_eq!(orders.get("b-40").map(|o| o.total), Some(7.25));
+        assert!(orders.contains_key("a-10") && !orders.contains_key("z-00"));
+
+        assert!(orders
+            .range("a-05".to_string().."c-00".to_string())
+            .map(|o| o.id.as_str())
+            .eq(["a-10", "b-40"]));
+
+        assert_eq!(orders.remove("a-02"), Some(order("a-02", 12.0)));
+        assert_eq!(orders.remove("a-02"), None);
+        assert_eq!(
+            orders.first().map(|o| orders.key_of(o)),
+            Some("a-10".to_string())
+        );
+        assert_eq!(orders.last().map(|o| o.total), Some(3.5));
+        assert_eq!(orders.len(), 3);
+
+        let mut by_len = BTreeByKey::new(|word: &&str| word.len());
+        by_len.extend(["kiwi", "fig", "banana", "apple"]);
+        assert!(by_len.range(4..=5).eq([&"kiwi", &"apple"]));
+        assert_eq!(by_len.get(&6), Some(&"banana"));
+        assert!(by_len.is_not_empty() && !by_len.is_empty());
+    }
+
+    #[test]
+    fn descending_test() {
+        let mut tree = (0..300).map(|x| x * 7 % 101).collect::<BTreeDesc<_>>();
+
+        assert_eq!((tree.first(), tree.last()), (Some(&100), Some(&0)));
+        assert!(tree.iter().is_sorted_by(|a, b| a >= b));
+        assert_eq!(tree.len(), 300);
+
+        assert!(tree
+            .range((Bound::Included(50), Bound::Included(48)))
+            .eq([&50, &50, &50, &49, &49, &49, &48, &48, &48]));
+        assert_eq!(tree.range(..97).count(), 9);
+        assert_eq!(tree.range(2..).count(), 9);
+
+        assert_eq!(tree.remove(&100), Some(100));
+        assert!(tree.contains(&100));
+        (0..2).for_each(|_| assert_eq!(tree.remove(&100), Some(100)));
+        assert_eq!(tree.first(), Some(&99));
+
+        tree.insert(1000);
+        assert_eq!(tree.first(), Some(&1000));
+        assert!(BTreeDesc::<i32>::descending().first().is_none());
+    }
+
+    #[test]
+    fn bimap_test() {
+        let mut codes = [("de", 49), ("fr", 33), ("it", 39), ("jp", 81)]
+            .map(|(country, code)| (country.to_string(), code))
+            .into_iter()
+            .collect::<BiBTreeMap<_, _>>();
+
+        assert_eq!(codes.get_by_left("fr"), Some(&33));
+        assert_eq!(codes.get_by_right(&81).map(String::as_str), Some("jp"));
+        assert!(codes.contains_left("it") && !codes.contains_right(&1));
+
+        assert!(codes.insert("us".to_string(), 1).is_empty());
+        assert_eq!(
+            codes.insert("fr".to_string(), 1),
+            vec![("fr".to_string(), 33), ("us".to_string(), 1)]
+        );
+        assert_eq!(codes.get_by_right(&1).map(String::as_str), Some("fr"));
+        assert!(!codes.contains_left("us") && !codes.contains_right(&33));
+
+        assert_eq!(
+            codes.insert("de".to_string(), 49),
+            vec![("de".to_string(), 49)]
+        );
+        assert_eq!(codes.remove_by_right(&39), Some(("it".to_string(), 39)));
+        assert_eq!(codes.remove_by_left("it"), None);
+
+        assert!(codes.iter().map(|(c, _)| c.as_str()).eq(["de", "fr", "jp"]));
+        assert!(codes.iter_by_right().map(|(code, _)| *code).eq([1, 49, 81]));
+        assert_eq!(codes.len(), 3);
+        assert!(codes.is_not_empty() && BiBTreeMap::<i32, i32>::new().is_empty());
+    }
+
+    #[test]
+    fn replay_test() {
+        let mut recording = RecordingBTree::new();
+        (0..50).for_each(|x| {
+            recording.insert(x * 31 % 50);
+        });
+        (50..60).for_each(|x| {
+            recording.push_max(x);
+        });
+        assert_eq!(recording.take_first_n(5).len(), 5);
+        assert_eq!(recording.take_last_n(3).len(), 3);
+        recording.push_max(0);
+
+        let (tree, trace) = recording.into_parts();
+        assert_eq!(trace.len(), 63);
+        assert_eq!(trace.ops()[0], BTreeOp::Insert(0));
+        assert_eq!(trace.ops()[60], BTreeOp::TakeFirstN(5));
+
+        #[cfg(feature = "serde")]
+        let trace =
+            serde_json::from_str::<BTreeTrace<i32>>(&serde_json::to_string(&trace).unwrap())
+                .unwrap();
+
+        let mut lens = vec![];
+        let replayed = BTree::replay_with(&trace, |step, _, tree| {
+            assert_invariants(tree);
+            lens.push((step, tree.len()));
+        });
+
+        assert!(replayed.iter().eq(tree.iter()));
+        assert_eq!(lens[59], (59, 60));
+        assert_eq!(lens[60], (60, 55));
+        assert_eq!(lens[62], (62, 53));
+        assert!(BTree::replay(&trace).iter().eq(tree.iter()));
+
+        let mut recording = RecordingBTree::new();
+        (0..100).for_each(|x| {
+            recording.insert(x * 37 % 100 / 2);
+        });
+
+        assert_eq!(recording.pop_first().map(|x| *x), Some(0));
+        assert_eq!(recording.pop_last().map(|x| *x), Some(49));
+        assert_eq!(recording.remove_at(10).map(|x| *x), Some(5));
+        recording.retain(|x| x % 7 != 0);
+        assert_eq!(recording.extract_if(|x| x % 5 == 0).len(), 15);
+        assert_eq!(recording.remove_range(20..25), 6);
+        assert_eq!(recording.split_off(&45).len(), 6);
+        recording.append(&mut BTree::from_iter([46, 47, 47]));
+        recording.dedup();
+        recording.truncate(20);
+        recording.apply_patch([
+            BTreeChange::Added(Rc::new(3)),
+            BTreeChange::Removed(Rc::new(4)),
+        ]);
+
+        let len = recording.tree().len();
+        assert_eq!(recording.remove(&3).map(|x| *x), Some(3));
+        assert_eq!(recording.tree().len(), len - 1);
+
+        let (tree, trace) = recording.into_parts();
+        assert_eq!(trace.ops()[100], BTreeOp::PopFirst);
+        assert_eq!(
+            trace.ops()[105],
+            BTreeOp::RemoveRange(Bound::Included(20), Bound::Excluded(25))
+        );
+
+        #[cfg(feature = "serde")]
+        let trace =
+            serde_json::from_str::<BTreeTrace<i32>>(&serde_json::to_string(&trace).unwrap())
+                .unwrap();
+
+        let replayed = BTree::replay_with(&trace, |_, _, tree| assert_invariants(tree));
+        assert!(replayed.iter().eq(tree.iter()));
+
+        let mut recording = RecordingBTree::new();
+        recording.insert(1);
+        assert_eq!(recording.drain().count(), 1);
+        recording.insert(2);
+        recording.clear();
+        assert!(BTree::replay(recording.trace()).is_empty());
+    }
+
+    #[cfg(feature = "mmap")]
+    #[test]
+    fn mapped_frozen_test() {
+        let path = std::env::temp_dir().join(format!("btree23-mapped-{}", std::process::id()));
+        let frozen = BTree::from_iter((0..1000_i64).map(|x| x * 3 - 500)).freeze();
+        frozen.write_mapped(&path).unwrap();
+
+        let first = unsafe { MappedFrozenBTree::<i64>::open(&path) }.unwrap();
+        let second = unsafe { MappedFrozenBTree::<i64>::open(&path) }.unwrap();
+
+        assert_eq!(first.len(), 1000);
+        assert!(first.is_not_empty() && !first.is_empty());
+        assert!(first.iter().eq(frozen.iter().copied()));
+        assert!(second.iter().eq(first.iter()));
+        assert_eq!(first.first(), Some(-500));
+        assert_eq!(first.last(), Some(2497));
+        assert_eq!(first.get(1000), None);
+        assert!(first.contains(&-497) && !first.contains(&-496));
+        assert!(first.range(0..12).eq([1, 4, 7, 10]));
+        assert!(unsafe { MappedFrozenBTree::<i32>::open(&path) }.is_err());
+
+        // The file may only be rewritten once nothing maps it
+        drop((first, second));
+        std::fs::write(&path, b"garbage").unwrap();
+        assert!(unsafe { MappedFrozenBTree::<i64>::open(&path) }.is_err());
+
+        let overflowing_len = (u64::MAX - 8) / 8;
+        let header = [
+            *MAPPED_MAGIC,
+            overflowing_len.to_le_bytes(),
+            8u64.to_le_bytes(),
+        ]
+        .concat();
+        std::fs::write(&path, header).unwrap();
+        assert!(unsafe { MappedFrozenBTree::<i64>::open(&path) }.is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn sorted_iter_set_ops_test() {
+        let tree = BTree::from_iter([9, 1, 4, 4, 6, 12, 15]);
+        let stream = || [0, 4, 5, 6, 6, 15, 20].into_iter();
+
+        assert!(tree
+            .union_sorted(stream())
+            .map(|x| *x)
+            .eq([0, 1, 4, 5, 6, 9, 12, 15, 20]));
+
+        assert!(tree.intersect_sorted(stream()).map(|x| *x).eq([4, 6, 15]));
+        assert!(tree.difference_sorted(stream()).map(|x| *x).eq([1, 9, 12]));
+        assert!(tree
+            .difference_sorted([])
+            .map(|x| *x)
+            .eq([1, 4, 6, 9, 12, 15]));
+        assert_eq!(BTree::new().intersect_sorted(stream()).next(), None);
+        assert_eq!(BTree::new().union_sorted(stream()).count(), 6);
+    }
+
+    #[test]
+    fn union_test() {
+        let a = BTree::from_iter([7, 1, 3, 3, 5, 9]);
+        let b = BTree::from_iter([2, 3, 4, 4, 9, 10]);
+
+        assert!(a.union(&b).map(|x| *x).eq([1, 2, 3, 4, 5, 7, 9, 10]));
+        assert!(b.union(&a).map(|x| *x).eq([1, 2, 3, 4, 5, 7, 9, 10]));
+        assert!(a.union(&BTree::new()).map(|x| *x).eq([1, 3, 5, 7, 9]));
+        assert_eq!(BTree::<i32>::new().union(&BTree::new()).next(), None);
+    }
+
+    #[test]
+    fn difference_test() {
+        let a = BTree::from_iter([7, 1, 3, 3, 5, 9]);
+        let b = BTree::from_iter([2, 3, 4, 4, 9, 10]);
+
+        assert!(a.difference(&b).map(|x| *x).eq([1, 5, 7]));
+        assert!(b.difference(&a).map(|x| *x).eq([2, 4, 10]));
+        assert!(a.difference(&BTree::new()).map(|x| *x).eq([1, 3, 5, 7, 9]));
+        assert_eq!(a.difference(&a).next(), None);
+    }
+
+    #[test]
+    fn symmetric_difference_test() {
+        let a = BTree::from_iter([7, 1, 3, 3, 5, 9]);
+        let b = BTree::from_iter([2, 3, 4, 4, 9, 10]);
+
+        assert!(a
+            .symmetric_difference(&b)
+            .map(|x| *x)
+            .eq([1, 2, 4, 5, 7, 10]));
+        assert!(b
+            .symmetric_difference(&a)
+            .map(|x| *x)
+            .eq([1, 2, 4, 5, 7, 10]));
+        assert!(a
+            .symmetric_difference(&BTree::new())
+            .map(|x| *x)
+            .eq([1, 3, 5, 7, 9]));
+        assert_eq!(a.symmetric_difference(&a).next(), None);
+    }
+
+    #[test]
+    fn subset_test() {
+        let a = BTree::from_iter([3, 1, 3, 7]);
+        let b = BTree::from_iter([0, 1, 2, 3, 5, 7, 9]);
+        let c = BTree::from_iter([2, 4, 4, 6]);
+        let empty = BTree::new();
+
+        assert!(a.is_subset(&b) && !b.is_subset(&a));
+        assert!(b.is_superset(&a) && !a.is_superset(&b));
+        assert!(a.is_subset(&a) && a.is_superset(&a));
+        assert!(!c.is_subset(&b));
+        assert!(empty.is_subset(&a) && a.is_superset(&empty));
+
+        assert!(a.is_disjoint(&c) && c.is_disjoint(&a));
+        assert!(!b.is_disjoint(&c));
+        assert!(empty.is_disjoint(&empty));
+    }
+
+    #[test]
+    fn set_operators_test() {
+        use std::collections::BTreeSet;
+
+        let a = BTree::from_iter([7, 1, 3, 3, 5, 9, 11]);
+        let b = BTree::from_iter([2, 3, 4, 4, 9, 10, 11]);
+        let set_a = BTreeSet::from_iter(a.iter().map(|x| *x));
+        let set_b = BTreeSet::from_iter(b.iter().map(|x| *x));
+
+        for (tree, set) in [
+            (&a | &b, &set_a | &set_b),
+            (&a & &b, &set_a & &set_b),
+            (&a - &b, &set_a - &set_b),
+            (&b - &a, &set_b - &set_a),
+            (&a ^ &b, &set_a ^ &set_b),
+            (&a | &BTree::new(), set_a.clone()),
+            (&a & &BTree::new(), BTreeSet::new()),
+        ] {
+            assert_invariants(&tree);
+            assert_eq!(tree, set);
+        }
+
+        let mut c = BTree::with_mode(BTreeMode::Set);
+        c.extend([3, 4, 5]);
+
+        for mut tree in [&c | &a, &c & &b, &c - &a, &c ^ &b] {
+            assert_eq!(tree.mode(), BTreeMode::Set);
+            assert!(!tree.insert(*tree.first().unwrap()));
+        }
+
+        assert_eq!((&a | &c).mode(), BTreeMode::Multiset);
+    }
+
+    #[test]
+    fn extract_range_test() {
+        let bounds = [
+            (Bound::Unbounded, Bound::Unbounded),
+            (Bound::Included(10), Bound::Excluded(20)),
+            (Bound::Excluded(10), Bound::Included(20)),
+            (Bound::Included(-5), Bound::Included(3)),
+            (Bound::Included(95), Bound::Unbounded),
+            (Bound::Excluded(40), Bound::Excluded(41)),
+            (Bound::Included(30), Bound::Included(10)),
+            (Bound::Included(200), Bound::Unbounded),
+        ];
+
+        for range in bounds {
+            let mut tree = BTree::from_iter((0..100).map(|x| x / 2 * 2));
+            let expected = (0..100)
+                .map(|x| x / 2 * 2)
+                .partition::<Vec<_>, _>(|x| range.contains(x));
+
+            let extracted = tree.extract_range(range);
+            assert_invariants(&extracted);
+            assert_invariants(&tree);
+            assert_eq!(extracted, expected.0);
+            assert_eq!(tree, expected.1);
+        }
+
+        assert!(BTree::<i32>::new().extract_range(..).is_empty());
+    }
+
+    #[test]
+    fn count_test() {
+        let values = (0..500).map(|x| x * x % 37).collect::<Vec<_>>();
+        let tree = BTree::from_iter(values.iter().copied());
+
+        for x in -1..40 {
+            assert_eq!(tree.count(&x), values.iter().filter(|&&v| v == x).count());
+        }
+
+        let words = BTree::from_iter(["b", "a", "b", "c", "b"].map(String::from));
+        assert_eq!(words.count("b"), 3);
+        assert_eq!(words.count("d"), 0);
+        assert_eq!(BTree::<i32>::new().count(&0), 0);
+    }
+
+    #[test]
+    fn equal_range_test() {
+        let tree = BTree::from_iter((0..300).rev().map(|x| x / 7));
+
+        for key in -1..45 {
+            let run = tree.equal_range(&key).map(|x| *x).collect::<Vec<_>>();
+            let expected = (0..300).filter(|x| x / 7 == key).count();
+            assert_eq!(run, vec![key; expected]);
+        }
+
+        assert_eq!(BTree::<i32>::new().equal_range(&0).next(), None);
+    }
+
+    #[test]
+    fn clone_range_test() {
+        let tree = BTree::from_iter((0..300).rev().map(|x| x / 2));
+
+        for (a, b) in [(0, 150), (10, 20), (-5, 3), (149, 400), (70, 70), (90, 40)] {
+            let cloned = tree.clone_range(a..b);
+            assert_invariants(&cloned);
+            assert_eq!(
+                cloned,
+                (0..300)
+                    .map(|x| x / 2)
+                    .filter(|x| (a..b).contains(x))
+                    .collect::<Vec<_>>()
+            );
+        }
+
+        assert_eq!(tree.len(), 300);
+        assert!(Rc::ptr_eq(
+            &tree.clone_range(40..=40).first().unwrap(),
+            &tree.get_stored(&40).unwrap()
+        ));
+    }
+
+    #[test]
+    fn count_in_range_test() {
+        let values = (0..300).map(|x| x / 3 * 2).collect::<Vec<_>>();
+        let tree = BTree::from_iter(values.iter().rev().copied());
+
+        for a in (-4..204).step_by(5) {
+            for b in (a - 10..a + 60).step_by(7) {
+                let count = |range: (Bound<i32>, Bound<i32>)| {
+                    values.iter().filter(|v| range.contains(v)).count()
+                };
+
+                for range in [
+                    (Bound::Included(a), Bound::Excluded(b)),
+                    (Bound::Excluded(a), Bound::Included(b)),
+                    (Bound::Included(a), Bound::Unbounded),
+                    (Bound::Unbounded, Bound::Included(b)),
+                ] {
+                    assert_eq!(tree.count_in_range(range), count(range));
+                }
+            }
+        }
+
+        assert_eq!(tree.count_in_range(..), 300);
+        assert_eq!(BTree::<i32>::new().count_in_range(..), 0);
+    }
+
+    #[test]
+    fn remove_range_test() {
+        let mut tree = BTree::from_iter((0..1000).rev());
+        assert_eq!(tree.remove_range(..250), 250);
+        assert_invariants(&tree);
+        assert_eq!(tree, (250..1000).collect::<Vec<_>>());
+
+        assert_eq!(tree.remove_range(400..=599), 200);
+        assert_invariants(&tree);
+        assert_eq!(tree, (250..400).chain(600..1000).collect::<Vec<_>>());
+
+        assert_eq!(tree.remove_range(450..550), 0);
+        assert_eq!(tree.remove_range(900..), 100);
+        assert_eq!(tree.remove_range(..), 450);
+        assert!(tree.is_empty());
+    }
+
+    #[cfg(feature = "icu")]
+    #[test]
+    fn collation_test() {
+        use icu_collator::{CollatorOptions, Numeric, Strength};
+
+        let locale = "en".parse::<icu_locid::Locale>().unwrap();
+        let collation = Collation::try_new(&locale, CollatorOptions::new()).unwrap();
+        let tree = collation.tree(["zebra", "Éclair", "apple", "cherry", "Banana"]);
+
+        assert!(tree
+            .iter()
+            .map(|s| s.as_str().to_owned())
+            .eq(["apple", "Banana", "cherry", "Éclair", "zebra"]));
+
+        let mut options = CollatorOptions::new();
+        options.strength = Some(Strength::Primary);
+        options.numeric = Some(Numeric::On);
+
+        let collation = Collation::try_new(&locale, options).unwrap();
+        let tree = collation.tree(["item10", "Item2", "item1"]);
+
+        assert!(tree
+            .iter()
+            .map(|s| s.as_str().to_owned())
+            .eq(["item1", "Item2", "item10"]));
+
+        let found = tree.get_stored(&collation.key("ITEM2")).unwrap();
+        assert_eq!(found.as_str(), "Item2");
+        assert_eq!((*found).clone().into_string(), "Item2");
+        assert!(tree.get_stored(&collation.key("item3")).is_none());
+    }
+
+    #[cfg(feature = "icu")]
+    #[test]
+    fn collation_string_tree_test() {
+        use icu_collator::{CollatorOptions, Strength};
+
+        let locale = "sv".parse::<icu_locid::Locale>().unwrap();
+        let mut options = CollatorOptions::new();
+        options.strength = Some(Strength::Secondary);
+
+        let collation = Collation::try_new(&locale, options).unwrap();
+        let mut tree = collation.string_tree::<String>();
+        tree.extend(["Öl", "zebra", "Ärlig", "apa", "Åsa", "Banan"].map(String::from));
+
+        assert!(tree
+            .iter()
+            .eq(["apa", "Banan", "zebra", "Åsa", "Ärlig", "Öl"]));
+
+        assert_eq!(tree.get(&"ÖL".to_string()).map(String::as_str), Some("Öl"));
+        assert!(!tree.contains(&"ol".to_string()));
+
+        assert!(tree
+            .range("b".to_string().."å".to_string())
+            .eq(["Banan", "zebra"]));
+
+        assert!(tree
+            .range("z".to_string()..)
+            .eq(["zebra", "Åsa", "Ärlig", "Öl"]));
+        assert_eq!(tree.remove(&"APA".to_string()).as_deref(), Some("apa"));
+        assert_eq!(tree.first().map(String::as_str), Some("Banan"));
+    }
+
+    #[test]
+    fn total_float_test() {
+        let tree = BTree::<TotalF64>::from_iter([2.5, -0.0, f64::NAN, 0.0, -1.0, f64::INFINITY]);
+        let values = tree.iter().map(|x| x.get()).collect::<Vec<_>>();
+
+        assert_eq!(values[..5], [-1.0, -0.0, 0.0, 2.5, f64::INFINITY]);
+        assert!(values[0].is_sign_negative() && values[1].is_sign_negative());
+        assert!(values[2].is_sign_positive() && values[5].is_nan());
+        assert!(tree.get_stored(&TotalF64::from(2.5)).is_some());
+        assert_eq!(tree.range(TotalF64(0.0)..).count(), 4);
+
+        let mut tree = BTree::<TotalF32>::new();
+        tree.extend([3.0_f32, 1.5, 2.25]);
+        assert_eq!(f32::from(*tree.first().unwrap()), 1.5);
+        assert_eq!(f32::from(*tree.last().unwrap()), 3.0);
+    }
+
+    #[test]
+    fn clone_from_test() {
+        use allocation_counter::allocations_in;
+
+        let tree = BTree::from_iter((0..500).rev());
+        let mut cloned = tree.clone();
+        assert_invariants(&cloned);
+        assert_eq!(cloned, (0..500).collect::<Vec<_>>());
+
+        cloned.insert(1000);
+        assert_eq!(tree.len(), 500);
+        assert_invariants(&tree);
+        assert_invariants(&cloned);
+
+        for source in [
+            BTree::from_iter(0..3),
+            BTree::bulk_load_from_sorted(0..2000),
+            BTree::new(),
+            BTree::from_iter((0..700).map(|x| x % 50)),
+        ] {
+            cloned.clone_from(&source);
+            assert_invariants(&cloned);
+            assert!(cloned.iter().eq(source.iter()));
+        }
+
+        let source = BTree::from_iter(0..1000);
+        let mut scratch = source.clone();
+        let (allocations, _) = allocations_in(|| scratch.clone_from(&source));
+        assert_eq!(allocations, 0);
+        assert_invariants(&scratch);
+        assert!(scratch.iter().eq(source.iter()));
+    }
+
+    #[test]
+    fn remove_test() {
+        check_random_removals(BTreeRebalancePolicy::default(), 11);
+
+        let mut tree = BTree::bulk_load_from_sorted(0..1000);
+        assert_eq!(tree.remove(&1000), None);
+
+        for x in (0..1000).rev().step_by(2).chain((0..1000).step_by(2)) {
+            assert_eq!(tree.remove(&x).map(|v| *v), Some(x));
+            assert_invariants(&tree);
+        }
+
+        assert!(tree.is_empty());
+        assert_eq!(tree.remove(&0), None);
+        tree.insert(5);
+        assert_eq!(tree, vec![5]);
+    }
+
+    #[test]
+    fn rebalance_policy_test() {
+        let leaves_number = |tree: &BTree<usize>| {
+            let mut cur_leaf = tree.root.as_ref().map(BTreeNode::first_leaf);
+            let mut number = 0;
+
+            while let Some(leaf) = cur_leaf {
+                cur_leaf = unsafe { leaf.borrow().unwrap_as_leaf_unchecked().next_leaf.clone() };
+                number += 1;
+            }
+
+            number
+        };
+
+        let mut leaves = vec![];
+
+        for policy in [BTreeRebalancePolicy::Borrow, BTreeRebalancePolicy::Merge] {
+            check_random_removals(policy, 7);
+
+            let mut tree = BTree::bulk_load_from_sorted(0..1000).with_rebalance_policy(policy);
+
+            for x in (0..1000).step_by(2) {
+                assert_eq!(tree.remove(&x).map(|v| *v), Some(x));
+                assert_invariants(&tree);
+            }
+
+            assert_eq!(tree.rebalance_policy(), policy);
+            assert_eq!(tree.clone().rebalance_policy(), policy);
+            assert!(tree.iter().map(|x| *x).eq((1..1000).step_by(2)));
+            leaves.push(leaves_number(&tree));
+        }
+
+        assert_eq!(leaves, vec![500, 333]);
+    }
+
+    #[test]
+    fn pop_first_last_test() {
+        let mut tree = BTree::from_iter((0..200).map(|x| x * 7 % 200));
+
+        for x in 0..100 {
+            assert_eq!(tree.pop_first().map(|v| *v), Some(x));
+            assert_eq!(tree.pop_last().map(|v| *v), Some(199 - x));
+            assert_invariants(&tree);
+        }
+
+        assert_eq!(tree.pop_first(), None);
+        assert_eq!(tree.pop_last(), None);
+
+        let mut queue = BTree::from_iter([3, 1, 2]);
+        queue.insert(0);
+        assert_eq!(queue.pop_first().map(|v| *v), Some(0));
+        assert_eq!(queue.pop_last().map(|v| *v), Some(3));
+        assert_eq!(queue, vec![1, 2]);
+    }
+
+    #[test]
+    fn contains_test() {
+        let mut tree = BTree::<i32>::from_iter((0..300).map(|x| x * 2));
+        assert!((0..600).all(|x| tree.contains(&x) == (x % 2 == 0)));
+        assert!(!tree.contains(&-1) && !tree.contains(&600));
+
+        (0..300)
+            .step_by(3)
+            .for_each(|x| drop(tree.remove(&(x * 2))));
+        assert!((0..600).all(|x| tree.contains(&x) == (x % 2 == 0 && x % 6 != 0)));
+        assert!(!BTree::<i32>::new().contains(&0));
+
+        let words = BTree::<String>::from_iter(["beta".to_owned(), "alpha".to_owned()]);
+        assert!(words.contains("alpha") && !words.contains("gamma"));
+    }
+
+    #[test]
+    fn clear_test() {
+        let mut tree = BTree::bulk_load_from_sorted(0..300_000);
+        let first = tree.first().unwrap();
+        tree.clear();
+
+        assert!(tree.is_empty());
+        assert_eq!(tree.iter().next(), None);
+        assert_eq!(Rc::strong_count(&first), 1);
+
+        tree.extend([3, 1, 2]);
+        assert_invariants(&tree);
+        assert_eq!(tree, vec![1, 2, 3]);
+
+        tree.clear();
+        tree.clear();
+        assert!(tree.is_empty());
+    }
+
+    #[test]
+    fn retain_test() {
+        let mut tree = BTree::from_iter((0..1000).map(|x| x * 37 % 500));
+        let mut visited = 0;
+
+        tree.retain(|&x| {
+            visited += 1;
+            x % 3 == 0
+        });
+
+        assert_eq!(visited, 1000);
+        assert_invariants(&tree);
+        assert!(tree
+            .iter()
+            .map(|x| *x)
+            .eq((0..500).filter(|x| x % 3 == 0).flat_map(|x| [x, x])));
+
+        tree.retain(|_| false);
+        assert!(tree.is_empty());
+        tree.insert(1);
+        assert_invariants(&tree);
+    }
+
+    #[test]
+    fn drain_test() {
+        let mut tree = BTree::from_iter((0..500).rev());
+        let drained = tree.drain().collect::<Vec<_>>();
+        assert!(tree.is_empty());
+        assert!(drained.iter().map(|x| **x).eq(0..500));
+        assert!(drained.iter().all(|x| Rc::strong_count(x) == 1));
+
+        tree.extend([2, 1]);
+        assert_invariants(&tree);
+        assert_eq!(
+            tree.drain().take(1).map(|x| *x).collect::<Vec<_>>(),
+            vec![1]
+        );
+        assert!(tree.is_empty());
+
+        let mut tree = BTree::bulk_load_from_sorted(0..300_000);
+        drop(tree.drain());
+        assert_eq!(tree.drain().next(), None);
+    }
+
+    #[test]
+    fn extract_if_test() {
+        let mut tree = BTree::from_iter((0..400).map(|x| x * 13 % 400));
+        let extracted = tree
+            .extract_if(|x| x % 4 == 1)
+            .map(|x| *x)
+            .collect::<Vec<_>>();
+
+        assert_invariants(&tree);
+        assert!(extracted.into_iter().eq((0..400).filter(|x| x % 4 == 1)));
+        assert!(tree.iter().map(|x| *x).eq((0..400).filter(|x| x % 4 != 1)));
+
+        {
+            let mut extract = tree.extract_if(|&x| x >= 100);
+            assert_eq!(extract.next().map(|x| *x), Some(100));
+            assert_eq!(extract.next().map(|x| *x), Some(102));
+        }
+
+        assert_invariants(&tree);
+        assert_eq!(tree.len(), 298);
+        assert!(tree.contains(&103) && !tree.contains(&102));
+        assert_eq!(tree.extract_if(|_| true).count(), 298);
+        assert!(tree.is_empty());
+    }
+
+    #[test]
+    fn split_off_test() {
+        for key in [-1, 0, 1, 57, 100, 198, 199, 500] {
+            let mut tree = BTree::from_iter((0..200).rev().map(|x| x / 2 * 2));
+            let upper = tree.split_off(&key);
+
+            assert_invariants(&tree);
+            assert_invariants(&upper);
+            assert!(tree.iter().all(|x| *x < key));
+            assert!(upper.iter().all(|x| *x >= key));
+            assert_eq!(tree.len() + upper.len(), 200);
+        }
+
+        let mut words = BTree::from_iter(["pear", "apple", "fig", "kiwi"].map(String::from));
+        let upper = words.split_off("g");
+        assert!(words
+            .iter()
+            .map(|s| s.as_str().to_owned())
+            .eq(["apple", "fig"]));
+        assert!(upper
+            .iter()
+            .map(|s| s.as_str().to_owned())
+            .eq(["kiwi", "pear"]));
+        assert!(BTree::<i32>::new().split_off(&0).is_empty());
+    }
+
+    #[test]
+    fn split_off_at_test() {
+        let mut tree = BTree::bulk_load_from_sorted(0..1000);
+        let mut chunks = vec![];
+
+        while tree.is_not_empty() {
+            let tail = tree.split_off_at(tree.len().min(128));
+            assert_invariants(&tree);
+            assert_invariants(&tail);
+            chunks.push(std::mem::replace(&mut tree, tail));
+        }
+
+        assert_eq!(chunks.len(), 8);
+        assert!(chunks[..7].iter().all(|chunk| chunk.len() == 128));
+        assert!(chunks
+            .iter()
+            .flat_map(|chunk| chunk.iter())
+            .map(|x| *x)
+            .eq(0..1000));
+        assert!(chunks[0].split_off_at(128).is_empty());
+    }
+
+    #[test]
+    fn split_small_trees_test() {
+        for len in 1..=10 {
+            for rank in 0..=len {
+                let mut tree = BTree::from_iter(0..len);
+                let mut tail = tree.split_off_at(rank as usize);
+
+                for (half, values) in [(&mut tree, 0..rank), (&mut tail, rank..len)] {
+                    assert_invariants(half);
+                    assert!(values
+                        .clone()
+                        .all(|x| half.rank(&x) == Some((x - values.start) as usize)));
+
+                    half.insert(len);
+                    assert_invariants(half);
+                    assert!(values.clone().all(|x| half.remove(&x).is_some()));
+                    assert_invariants(half);
+                    assert_eq!(*half, vec![len]);
+                }
+            }
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "split index")]
+    fn split_off_at_out_of_bounds_test() {
+        BTree::from_iter(0..3).split_off_at(4);
+    }
+
+    #[test]
+    fn append_test() {
+        let mut log = BTree::new();
+
+        for segment in 0..10 {
+            let mut other = BTree::from_iter(segment * 37..(segment + 1) * 37);
+            log.append(&mut other);
+            assert!(other.is_empty());
+            assert_invariants(&log);
+        }
+
+        assert!(log.iter().map(|x| *x).eq(0..370));
+
+        let mut small = BTree::from_iter([1000]);
+        small.append(&mut log);
+        assert_invariants(&small);
+        assert!(small.iter().map(|x| *x).eq((0..370).chain([1000])));
+
+        let mut overlapping = BTree::from_iter((0..100).map(|x| x * 2));
+        small.append(&mut overlapping);
+        assert_invariants(&small);
+        assert_eq!(small.len(), 471);
+        assert!(small.iter().map(|x| *x).is_sorted());
+
+        let mut empty = BTree::new();
+        empty.append(&mut small);
+        assert_eq!(empty.len(), 471);
+        assert!(small.is_empty());
+    }
+
+    #[test]
+    fn range_by_index_test() {
+        let tree = BTree::from_iter((0..3000).rev().map(|x| x * 2));
+
+        assert!(tree
+            .range_by_index(1000..2000)
+            .map(|x| *x)
+            .eq((1000..2000).map(|x| x * 2)));
+        assert!(tree
+            .range_by_index(2990..)
+            .map(|x| *x)
+            .eq((2990..3000).map(|x| x * 2)));
+        assert!(tree.range_by_index(..=2).map(|x| *x).eq([0, 2, 4]));
+        assert!(tree
+            .range_by_index(..)
+            .map(|x| *x)
+            .eq((0..3000).map(|x| x * 2)));
+        assert_eq!(tree.range_by_index(3000..3000).next(), None);
+        assert_eq!(BTree::<i32>::new().range_by_index(..).next(), None);
+
+        assert!(tree
+            .range_by_index(1000..2000)
+            .rev()
+            .map(|x| *x)
+            .eq((1000..2000).rev().map(|x| x * 2)));
+        assert!(tree.range_by_index(..=2).rev().map(|x| *x).eq([4, 2, 0]));
+        assert_eq!(
+            tree.range_by_index(5..6)
+                .rev()
+                .map(|x| *x)
+                .collect::<Vec<_>>(),
+            [10]
+        );
+        assert_eq!(tree.range_by_index(7..7).next_back(), None);
+
+        let mut both_ends = tree.range_by_index(10..14);
+        assert_eq!(both_ends.next().map(|x| *x), Some(20));
+        assert_eq!(both_ends.next_back().map(|x| *x), Some(26));
+        assert!(both_ends.map(|x| *x).eq([22, 24]));
+    }
+
+    #[test]
+    #[should_panic(expected = "range end")]
+    fn range_by_index_out_of_bounds_test() {
+        let _ = BTree::from_iter(0..10).range_by_index(5..11);
+    }
+
+    #[test]
+    fn remove_at_test() {
+        let mut tree = BTree::from_iter((0..500).rev());
+        let mut model = (0..500).collect::<Vec<_>>();
+        let mut index = 0;
+
+        while !model.is_empty() {
+            index = (index + 7919) % model.len();
+            assert_eq!(tree.remove_at(index).map(|x| *x), Some(model.remove(index)));
+            assert_invariants(&tree);
+        }
+
+        assert_eq!(tree.remove_at(0), None);
+
+        let mut tree = BTree::from_iter([5, 5, 1, 9]);
+        assert_eq!(tree.remove_at(4), None);
+        assert_eq!(tree.remove_at(2).map(|x| *x), Some(5));
+        assert_eq!(tree, vec![1, 5, 9]);
+    }
+
+    #[test]
+    fn truncate_test() {
+        let mut tree = BTree::from_iter((0..300).rev());
+        tree.truncate(500);
+        assert_eq!(tree.len(), 300);
+
+        for len in [299, 150, 77, 3, 1, 0] {
+            tree.truncate(len);
+            assert_invariants(&tree);
+            assert_eq!(tree, (0..len as i32).collect::<Vec<_>>());
+        }
+
+        for len in 0..=3 {
+            let mut tree = BTree::from_iter(0..3);
+            tree.truncate(len);
+            assert!((0..len as i32).all(|x| tree.rank(&x) == Some(x as usize)));
+
+            tree.insert(10);
+            assert_invariants(&tree);
+            assert!((0..len as i32).all(|x| tree.remove(&x).is_some()));
+            assert_invariants(&tree);
+            assert_eq!(tree, vec![10]);
+        }
+    }
+
+    #[test]
+    fn set_mode_test() {
+        let mut multiset = BTree::new();
+        assert_eq!(multiset.mode(), BTreeMode::Multiset);
+        assert!(multiset.insert(1) && multiset.insert(1));
+        assert_eq!(multiset, vec![1, 1]);
+
+        let mut set = BTree::with_mode(BTreeMode::Set);
+
+        for x in (0..200).chain(0..200).map(|x| x * 7 % 100) {
+            let is_new = !set.contains(&x);
+            assert_eq!(set.insert(x), is_new);
+            assert_invariants(&set);
+        }
+
+        assert_eq!(set, (0..100).collect::<Vec<_>>());
+        assert!(!set.push_max(99) && set.push_max(100) && !set.push_max(50));
+
+        let mut tail = set.split_off(&50);
+        assert_eq!(tail.mode(), BTreeMode::Set);
+        assert!(!tail.insert(75));
+        assert_eq!(set.clone().mode(), BTreeMode::Set);
+
+        let mut other = BTree::from_iter([40, 40, 49, 50]);
+        set.append(&mut other);
+        assert_invariants(&set);
+        assert_eq!(set, (0..51).collect::<Vec<_>>());
 
+        set.append(&mut tail);
+        assert_eq!(set, (0..101).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn try_insert_test() {
+        let mut tree = BTree::new();
+        assert_eq!(tree.try_insert(String::from("b")), Ok(()));
+        assert_eq!(tree.try_insert(String::from("a")), Ok(()));
+
+        let stored = tree.get_stored("b").unwrap();
+        let error = tree.try_insert(String::from("b")).unwrap_err();
+        assert!(Rc::ptr_eq(&error.existing, &stored));
+        assert_eq!(error.value, "b");
+        assert_eq!(
+            error.to_string(),
+            r#"failed to insert "b", equal value "b" is already stored"#
+        );
+
+        assert_eq!(tree, vec![String::from("a"), String::from("b")]);
+    }
+
+    #[test]
+    fn replace_test() {
+        #[derive(Debug, Clone)]
+        struct Entry(i32, &'static str);
+
+        impl PartialEq for Entry {
+            fn eq(&self, other: &Self) -> bool {
+                self.0 == other.0
+            }
+        }
+
+        impl Eq for Entry {}
+
+        impl PartialOrd for Entry {
+            fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+                Some(self.cmp(other))
+            }
+        }
+
+        impl Ord for Entry {
+            fn cmp(&self, other: &Self) -> Ordering {
+                self.0.cmp(&other.0)
+            }
+        }
+
+        let mut tree = BTree::from_iter((0..50).map(|x| Entry(x, "old")));
+
+        for x in (0..60).step_by(3) {
+            let previous = tree.replace(Entry(x, "new"));
+            assert_eq!(previous.map(|entry| entry.1), (x < 50).then_some("old"));
+            assert_invariants(&tree);
+        }
+
+        assert_eq!(tree.len(), 53);
         assert!(tree
             .iter()
-            .map(|v| *v + *v)
-            .zip((-1000..).map(|x| x + x))
-            .all(|(tree_elem, x)| tree_elem == x));
+            .all(|entry| entry.1 == if entry.0 % 3 == 0 { "new" } else { "old" }));
+    }
+
+    #[test]
+    fn get_or_insert_with_test() {
+        let mut interner = BTree::new();
+        let mut created = 0;
+
+        for word in [
+            "kiwi", "apple", "fig", "apple", "plum", "kiwi", "lime", "fig",
+        ] {
+            let stored = interner.get_or_insert_with(word, |word| {
+                created += 1;
+                String::from(word)
+            });
+
+            assert_eq!(stored.as_str(), word);
+            assert!(Rc::ptr_eq(&stored, &interner.get_stored(word).unwrap()));
+            assert_invariants(&interner);
+        }
 
+        assert_eq!(created, 5);
         assert_eq!(
-            tree.iter().map(|x| *x * *x).fold(0, |acc, x| acc + x),
-            (-1000..=1000).fold(0, |acc, x| acc + x * x)
+            interner,
+            ["apple", "fig", "kiwi", "lime", "plum"]
+                .map(String::from)
+                .to_vec()
         );
 
+        let mut tree = BTree::new();
+
+        for x in (0..300).chain(0..300).map(|x| x * 7 % 150) {
+            tree.get_or_insert_with(&x, |x| *x);
+            assert_invariants(&tree);
+        }
+
+        assert_eq!(tree, (0..150).collect::<Vec<_>>());
+
+        (0..150).step_by(2).for_each(|x| {
+            tree.remove(&x);
+        });
+
+        for x in (0..150).rev() {
+            tree.get_or_insert_with(&x, |x| *x);
+            assert_invariants(&tree);
+        }
+
+        assert_eq!(tree, (0..150).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn predecessor_successor_test() {
+        let tree = BTree::from_iter((0..100).map(|x| x / 2 * 4));
+
+        for x in -3..205 {
+            let predecessor = (0..100).map(|x| x / 2 * 4).filter(|&v| v < x).max();
+            let successor = (0..100).map(|x| x / 2 * 4).find(|&v| v > x);
+            assert_eq!(tree.predecessor(&x).map(|v| *v), predecessor);
+            assert_eq!(tree.successor(&x).map(|v| *v), successor);
+        }
+
+        assert_eq!(BTree::<i32>::new().predecessor(&0), None);
+        assert_eq!(BTree::<i32>::new().successor(&0), None);
+    }
+
+    #[test]
+    fn bounds_test() {
+        let values = (0..200).map(|x| x / 3 * 2).collect::<Vec<_>>();
+        let tree = BTree::from_iter(values.iter().rev().copied());
+
+        for x in -2..140 {
+            let lower = values.partition_point(|&v| v < x);
+            let upper = values.partition_point(|&v| v <= x);
+
+            assert!(tree
+                .lower_bound(&x)
+                .map(|v| *v)
+                .eq(values[lower..].iter().copied()));
+            assert!(tree
+                .upper_bound(&x)
+                .map(|v| *v)
+                .eq(values[upper..].iter().copied()));
+            assert_eq!(tree.find(&x).next().map(|v| *v), values.get(lower).copied());
+        }
+
+        assert_eq!(BTree::<i32>::new().lower_bound(&0).next(), None);
+    }
+
+    #[test]
+    fn rank_test() {
+        let mut tree = BTree::from_iter((0..500).rev().map(|x| x / 2 * 3));
+
+        for x in -1..760 {
+            let expected = (x % 3 == 0 && x < 750).then_some(x as usize / 3 * 2);
+            assert_eq!(tree.rank(&x), expected);
+        }
+
+        (0..750).step_by(6).for_each(|x| {
+            tree.remove(&x);
+        });
+
+        for (index, value) in tree.iter().enumerate() {
+            let first_equal = tree.iter().position(|v| *v == *value).unwrap();
+            assert_eq!(tree.rank(&*value), Some(first_equal));
+            assert!(first_equal == index || tree.get(index - 1) == Some(value));
+        }
+
+        assert_eq!(tree.rank(&7), None);
+        assert_eq!(BTree::<i32>::new().rank(&0), None);
+    }
+
+    #[test]
+    fn advance_by_test() {
+        let tree = BTree::<i32>::from_iter((0..1000).rev());
+
+        for start in [0, 1, 2, 499, 998, 999, 1000] {
+            for n in [0usize, 1, 2, 3, 17, 500, 999, 1000, 1500] {
+                let mut iter = tree.iter();
+                iter.advance_by(start).unwrap_or_default();
+
+                let remaining = 1000 - start.min(1000);
+                let expected = n
+                    .checked_sub(remaining)
+                    .and_then(std::num::NonZeroUsize::new);
+                assert_eq!(iter.advance_by(n).err(), expected);
+                assert_eq!(
+                    iter.next().map(|x| *x),
+                    (start + n < 1000).then_some((start + n) as i32)
+                );
+            }
+        }
+
+        let mut iter = tree.iter();
+        assert_eq!(iter.nth(10).map(|x| *x), Some(10));
+        assert_eq!(iter.nth(100).map(|x| *x), Some(111));
+        assert_eq!(iter.nth(1000), None);
+
+        let mut iter = tree.iter();
+        iter.next();
+        assert_eq!(
+            iter.advance_by(usize::MAX).err(),
+            std::num::NonZeroUsize::new(usize::MAX - 999)
+        );
+
+        let mut iter = tree.iter();
+        iter.next();
+        assert_eq!(iter.nth(usize::MAX), None);
+
+        let mut detached = BTree::from_iter(0..100).into_iter();
+        assert_eq!(detached.nth(50).map(|x| *x), Some(50));
+        assert_eq!(
+            detached.advance_by(60).err(),
+            std::num::NonZeroUsize::new(11)
+        );
+        assert_eq!(
+            detached.advance_by(usize::MAX).err(),
+            std::num::NonZeroUsize::new(usize::MAX)
+        );
+    }
+
+    #[test]
+    fn nearest_test() {
+        let tree = BTree::from_iter([100, 10, 40, 41, 70, 0]);
+        let distance = |a: &i32, b: &i32| a.abs_diff(*b);
+
+        for (query, expected) in [
+            (-50, 0),
+            (0, 0),
+            (4, 0),
+            (5, 0),
+            (6, 10),
+            (25, 10),
+            (26, 40),
+            (41, 41),
+            (55, 41),
+            (56, 70),
+            (85, 70),
+            (86, 100),
+            (1000, 100),
+        ] {
+            assert_eq!(tree.nearest(&query, distance).map(|x| *x), Some(expected));
+        }
+
+        assert_eq!(BTree::new().nearest(&5, distance), None);
+    }
+
+    #[test]
+    fn dedup_test() {
+        let mut tree = BTree::from_iter((0..600).rev().map(|x| x * x % 97));
+        let first = tree.get_stored(&0).unwrap();
+
+        tree.dedup();
+        assert_invariants(&tree);
+        assert_eq!(
+            tree,
+            std::collections::BTreeSet::from_iter((0..600).map(|x| x * x % 97))
+        );
+        assert!(Rc::ptr_eq(&first, &tree.get_stored(&0).unwrap()));
+
+        tree.dedup();
+        assert_eq!(tree.len(), 49);
+
+        let mut empty = BTree::<i32>::new();
+        empty.dedup();
+        assert!(empty.is_empty());
+    }
+
+    #[test]
+    fn quantile_test() {
+        let tree = BTree::from_iter((1..=101).rev());
+
+        assert_eq!(tree.median().map(|x| *x), Some(51));
+        assert_eq!(tree.quantile(0.0).map(|x| *x), Some(1));
+        assert_eq!(tree.quantile(0.25).map(|x| *x), Some(26));
+        assert_eq!(tree.quantile(0.999).map(|x| *x), Some(100));
+        assert_eq!(tree.quantile(1.0).map(|x| *x), Some(101));
+
+        let qs = [0.9, 0.1, 0.5, 0.5, 1.0, 0.0];
         assert!(tree
+            .quantiles(&qs)
             .into_iter()
-            .map(|v| *v * *v)
-            .zip((-1000..).map(|x| x * x))
-            .all(|(tree_elem, x)| tree_elem == x));
+            .map(|x| *x)
+            .eq(qs.iter().map(|&q| *tree.quantile(q).unwrap())));
+
+        let even = BTree::from_iter([4, 1, 3, 2]);
+        assert_eq!(even.median().map(|x| *x), Some(2));
+
+        assert_eq!(BTree::<i32>::new().median(), None);
+        assert!(BTree::<i32>::new().quantiles(&[0.5]).is_empty());
+    }
+
+    #[test]
+    #[should_panic(expected = "quantile (is 1.5) should be in 0.0..=1.0")]
+    fn quantile_out_of_range_test() {
+        BTree::from_iter([1, 2, 3]).quantile(1.5);
+    }
+
+    #[cfg(feature = "rand")]
+    #[test]
+    fn sample_test() {
+        use rand::{rngs::StdRng, SeedableRng};
+
+        let mut rng = StdRng::seed_from_u64(23);
+        let tree = BTree::from_iter((0..1000).rev());
+        let mut hits = [0; 10];
+
+        for _ in 0..5000 {
+            let value = *tree.sample(&mut rng).unwrap();
+            assert!((0..1000).contains(&value));
+            hits[value as usize / 100] += 1;
+        }
+
+        assert!(hits.iter().all(|&hit| (350..650).contains(&hit)));
+
+        let sampled = tree.sample_n(&mut rng, 50);
+        assert_eq!(sampled.len(), 50);
+        assert!(sampled.windows(2).all(|w| w[0] < w[1]));
+
+        assert_eq!(tree.sample_n(&mut rng, 2000).len(), 1000);
+        assert_eq!(BTree::<i32>::new().sample(&mut rng), None);
+        assert!(BTree::<i32>::new().sample_n(&mut rng, 3).is_empty());
+    }
+
+    #[test]
+    fn into_sorted_vec_test() {
+        let tree = BTree::from_iter((0..300).rev().map(|x| (x % 100).to_string()));
+        let shared = tree.get_stored("42").unwrap();
+
+        let mut expected = (0..300).map(|x| (x % 100).to_string()).collect::<Vec<_>>();
+        expected.sort();
+
+        assert_eq!(tree.into_sorted_vec(), expected);
+        assert_eq!(Rc::strong_count(&shared), 1);
+        assert!(BTree::<i32>::new().into_sorted_vec().is_empty());
+    }
+
+    #[test]
+    fn merge_test() {
+        let a = BTree::from_iter((0..500).rev().map(|x| (x * 3 % 500, 'a')));
+        let b = BTree::from_iter((0..300).map(|x| (x * 7 % 300, 'b')));
+        let merged = BTree::merge(&a, &b);
+
+        let mut expected = a.iter().chain(b.iter()).map(|x| *x).collect::<Vec<_>>();
+        expected.sort();
+
+        assert_invariants(&merged);
+        assert_eq!(merged, expected);
+        assert_eq!(a.len(), 500);
+        assert_eq!(b.len(), 300);
+
+        let evens = BTree::from_iter((0..20).step_by(2));
+        let odds = BTree::from_iter((1..20).step_by(2));
+        assert_eq!(BTree::merge(&evens, &odds), (0..20).collect::<Vec<_>>());
+        assert_eq!(
+            BTree::merge(&BTree::new(), &odds),
+            odds.iter().map(|x| *x).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn merge_all_test() {
+        let shards = (0..5)
+            .map(|shard| BTree::from_iter((0..200).map(|x| (x * (shard + 3) % 150, shard))))
+            .collect::<Vec<_>>();
+
+        let merged = BTree::merge_all(&shards);
+
+        let mut expected = shards
+            .iter()
+            .flat_map(|shard| shard.iter().map(|x| *x))
+            .collect::<Vec<_>>();
+
+        expected.sort();
+
+        assert_invariants(&merged);
+        assert_eq!(merged, expected);
+        assert!(BTree::<i32>::merge_all([]).is_empty());
+        assert_eq!(
+            BTree::merge_all([&BTree::new(), &BTree::from_iter([2, 1])]),
+            vec![1, 2]
+        );
+
+        let mut set = BTree::with_mode(BTreeMode::Set);
+        set.extend([1, 2, 3]);
+
+        let merged = BTree::merge_all([&set, &BTree::from_iter([2, 3, 3, 4])]);
+        assert_invariants(&merged);
+        assert_eq!(merged.mode(), BTreeMode::Set);
+        assert_eq!(merged, vec![1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn diff_test() {
+        let old = BTree::from_iter([1, 3, 3, 5, 8, 13]);
+        let new = BTree::from_iter([0, 3, 5, 5, 8, 21]);
+
+        let changes = old
+            .diff(&new)
+            .map(|change| match change {
+                BTreeChange::Added(value) => (*value, true),
+                BTreeChange::Removed(value) => (*value, false),
+            })
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            changes,
+            [
+                (0, true),
+                (1, false),
+                (3, false),
+                (5, true),
+                (13, false),
+                (21, true)
+            ]
+        );
+
+        assert_eq!(old.diff(&old).next(), None);
+        assert_eq!(BTree::new().diff(&new).count(), 6);
+        assert_eq!(old.diff(&BTree::new()).count(), 6);
+    }
+
+    #[test]
+    fn apply_patch_test() {
+        let mut old = BTree::from_iter((0..400).map(|x| x * 7 % 300));
+        let new = BTree::from_iter((0..350).map(|x| x * 11 % 320));
+
+        let changes = old.diff(&new).collect::<Vec<_>>();
+        old.apply_patch(changes);
+        assert_invariants(&old);
+        assert_eq!(old, new.iter().map(|x| *x).collect::<Vec<_>>());
+
+        let mut tree = BTree::from_iter([1, 2, 2, 4]);
+        tree.apply_patch([
+            BTreeChange::Added(Rc::new(0)),
+            BTreeChange::Removed(Rc::new(2)),
+            BTreeChange::Added(Rc::new(3)),
+            BTreeChange::Removed(Rc::new(3)),
+            BTreeChange::Removed(Rc::new(5)),
+            BTreeChange::Added(Rc::new(6)),
+        ]);
+        assert_eq!(tree, vec![0, 1, 2, 4, 6]);
+
+        let mut set = BTree::with_mode(BTreeMode::Set);
+        set.extend([1, 3]);
+        set.apply_patch([
+            BTreeChange::Added(Rc::new(1)),
+            BTreeChange::Added(Rc::new(2)),
+        ]);
+        assert_eq!(set, vec![1, 2, 3]);
+
+        let mut tree = BTree::from_iter([1, 5]);
+        tree.apply_patch([
+            BTreeChange::Added(Rc::new(7)),
+            BTreeChange::Added(Rc::new(3)),
+            BTreeChange::Removed(Rc::new(5)),
+            BTreeChange::Added(Rc::new(3)),
+            BTreeChange::Added(Rc::new(0)),
+        ]);
+        assert_invariants(&tree);
+        assert_eq!(tree, vec![0, 1, 3, 3, 7]);
+
+        set.apply_patch([
+            BTreeChange::Added(Rc::new(4)),
+            BTreeChange::Added(Rc::new(2)),
+            BTreeChange::Removed(Rc::new(1)),
+        ]);
+        assert_eq!(set, vec![2, 3, 4]);
+    }
+
+    #[test]
+    fn get_many_test() {
+        let tree = BTree::from_iter((0..1000).rev().map(|x| x * 2));
+        let indices = [0, 0, 1, 2, 3, 250, 251, 700, 999, 1000, 5000];
+
+        assert_eq!(
+            tree.get_many(&indices)
+                .into_iter()
+                .map(|x| x.map(|x| *x))
+                .collect::<Vec<_>>(),
+            indices
+                .iter()
+                .map(|&index| tree.get(index).map(|x| *x))
+                .collect::<Vec<_>>()
+        );
+
+        assert!(tree.get_many(&[]).is_empty());
+        assert_eq!(BTree::<i32>::new().get_many(&[0, 1]), vec![None, None]);
+    }
+
+    #[test]
+    #[should_panic(expected = "indices should be sorted")]
+    fn get_many_unsorted_test() {
+        BTree::from_iter(0..10).get_many(&[3, 1]);
+    }
+
+    #[test]
+    fn top_k_test() {
+        let tree = BTree::from_iter((0..100).map(|x| x * 37 % 100));
+
+        for k in [0, 1, 2, 3, 50, 100, 150] {
+            let top = tree.top_k(k).into_iter().map(|x| *x).collect::<Vec<_>>();
+            let bottom = tree.bottom_k(k).into_iter().map(|x| *x).collect::<Vec<_>>();
+
+            assert_eq!(top, (0..100).rev().take(k).collect::<Vec<_>>());
+            assert_eq!(bottom, (0..100).take(k).collect::<Vec<_>>());
+        }
+
+        assert!(BTree::<i32>::new().top_k(3).is_empty());
+        assert!(BTree::<i32>::new().bottom_k(3).is_empty());
+    }
+
+    #[test]
+    fn counts_test() {
+        let tree = BTree::from_iter([3, 1, 3, 3, 7, 1, 9, 9, 3]);
+
+        assert!(tree.counts().map(|(value, count)| (*value, count)).eq([
+            (1, 2),
+            (3, 4),
+            (7, 1),
+            (9, 2)
+        ]));
+
+        assert_eq!(BTree::<i32>::new().counts().next(), None);
+    }
+
+    #[test]
+    fn page_test() {
+        let tree = BTree::from_iter((0..1000).rev());
+
+        for (offset, limit) in [(0, 20), (200, 20), (990, 20), (1000, 5), (5000, 5), (3, 0)] {
+            assert!(tree
+                .page(offset, limit)
+                .map(|x| *x as usize)
+                .eq((offset..(offset + limit).min(1000)).take(limit)));
+        }
+
+        assert_eq!(BTree::<i32>::new().page(0, 10).next(), None);
+    }
+
+    #[test]
+    fn cursor_token_test() {
+        let tree = BTree::from_iter((0..100).rev().map(|x| x * 3));
+
+        for direction in [BTreeDirection::Forward, BTreeDirection::Backward] {
+            let mut cursor = tree.cursor(direction);
+            let mut pages = vec![];
+
+            loop {
+                let page = cursor.by_ref().take(7).map(|x| *x).collect::<Vec<_>>();
+
+                if page.is_empty() {
+                    break;
+                }
+
+                pages.extend(page);
+
+                let token = cursor.token().unwrap();
+                cursor = tree.resume_from(&token);
+                assert_eq!(cursor.direction(), direction);
+            }
+
+            let mut expected = (0..100).map(|x| x * 3).collect::<Vec<_>>();
+
+            if direction == BTreeDirection::Backward {
+                expected.reverse();
+            }
+
+            assert_eq!(pages, expected);
+        }
+
+        let mut cursor = tree.cursor(BTreeDirection::Forward);
+        assert_eq!(cursor.token(), None);
+        cursor.next();
+        let token = cursor.token().unwrap();
+
+        let shifted = BTree::from_iter([-1, 1, 2, 5]);
+        assert!(shifted.resume_from(&token).map(|x| *x).eq([1, 2, 5]));
+        assert_eq!(BTree::new().resume_from(&token).next(), None);
+
+        let duplicates = BTree::from_iter([3, 2, 2, 1, 2]);
+
+        for (direction, expected) in [
+            (BTreeDirection::Forward, [1, 2, 2, 2, 3]),
+            (BTreeDirection::Backward, [3, 2, 2, 2, 1]),
+        ] {
+            let mut cursor = duplicates.cursor(direction);
+            let mut pages = vec![];
+
+            loop {
+                let page = cursor.by_ref().take(2).map(|x| *x).collect::<Vec<_>>();
+
+                if page.is_empty() {
+                    break;
+                }
+
+                pages.extend(page);
+                cursor = duplicates.resume_from(&cursor.token().unwrap());
+            }
+
+            assert_eq!(pages, expected);
+        }
+    }
+
+    #[test]
+    fn mid_keys_test() {
+        assert_eq!(BTree::<i32>::new().mid_keys().count(), 0);
+        assert_eq!(BTree::from_iter([1, 2]).mid_keys().count(), 0);
+
+        let mid_keys = BTree::from_iter(1..=5)
+            .mid_keys()
+            .map(|(depth, keys)| (depth, keys.iter().map(|x| **x).collect::<Vec<_>>()))
+            .collect::<Vec<_>>();
+
+        assert_eq!(mid_keys, vec![(0, vec![3]), (1, vec![2]), (1, vec![4])]);
+
+        let tree = BTree::from_iter(0..1000);
+        assert_invariants(&tree);
+
+        assert!(tree
+            .mid_keys()
+            .all(|(_, keys)| (1..=2).contains(&keys.len())));
+
+        assert!(tree
+            .mid_keys()
+            .all(|(_, keys)| keys.windows(2).all(|w| w[0] < w[1])));
+    }
+
+    #[test]
+    fn node_accessors_test() {
+        let tree = BTree::<i32>::from_iter(0..10);
+        let root = tree.root.as_ref().unwrap();
+
+        for value in 0..10 {
+            let leaf = BTreeNode::find(root, &value);
+            assert!(leaf
+                .borrow()
+                .unwrap_as_leaf()
+                .values
+                .contains(&Rc::new(value)));
+
+            let parent = leaf
+                .borrow_mut()
+                .get_parent_mut()
+                .unwrap()
+                .upgrade()
+                .unwrap();
+            assert!(parent
+                .borrow()
+                .unwrap_as_subtree()
+                .children
+                .iter()
+                .any(|child| Rc::ptr_eq(child, &leaf)));
+        }
+
+        let first = BTreeNode::find(root, &0);
+        let values = std::mem::take(&mut first.borrow_mut().unwrap_as_leaf_mut().values);
+        assert!(BTreeNode::find(root, &0)
+            .borrow()
+            .unwrap_as_leaf()
+            .values
+            .is_empty());
+        first.borrow_mut().unwrap_as_leaf_mut().values = values;
+
+        let values_number =
+            std::mem::take(&mut root.borrow_mut().unwrap_as_subtree_mut().values_number);
+        assert_eq!(tree.len(), 0);
+        root.borrow_mut().unwrap_as_subtree_mut().values_number = values_number;
+
+        assert!(root.borrow_mut().get_parent_mut().is_none());
+        assert_invariants(&tree);
+    }
+
+    #[test]
+    fn traverse_events_test() {
+        use BTreeTraverseEvent::*;
+
+        assert_eq!(BTree::<i32>::new().traverse_events().count(), 0);
+
+        let events = BTree::from_iter(1..=3)
+            .traverse_events()
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            events,
+            vec![
+                Enter(0, vec![Rc::new(2)]),
+                Leaf(1, vec![Rc::new(1)]),
+                Leaf(1, vec![Rc::new(2), Rc::new(3)]),
+                Exit(0),
+            ]
+        );
+
+        let tree = BTree::from_iter(0..1000);
+
+        let leaves = tree
+            .traverse_events()
+            .filter_map(|event| match event {
+                Leaf(depth, values) => Some((depth, values)),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+
+        assert!(leaves.windows(2).all(|w| w[0].0 == w[1].0));
+
+        assert!(leaves
+            .into_iter()
+            .flat_map(|(_, values)| values)
+            .map(|x| *x)
+            .eq(0..1000));
+
+        let (enters, exits) = tree
+            .traverse_events()
+            .fold((0, 0), |(enters, exits), event| match event {
+                Enter(..) => (enters + 1, exits),
+                Exit(..) => (enters, exits + 1),
+                Leaf(..) => (enters, exits),
+            });
+
+        assert_eq!(enters, exits);
+        assert_eq!(enters, tree.mid_keys().count());
     }
 }
 